@@ -8,12 +8,10 @@
 // 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
 
 
-// this file is more for reference, we don't use it directly
+// this file is more for reference, we don't use it directly.
+// it is only compiled for the tests, which check it against the size table in codec.rs.
 
 
-#![allow(unused_variables)]
-#![allow(dead_code)]
-
 use crate::xy::XY;                    // size 4
 
 #[repr(C, packed)]
@@ -133,7 +131,7 @@ pub struct TimeHandHeader {
     pub one: u8,
     pub e_type: u8,
     pub subid: u8,
-    pub unknown_xy: XY,
+    pub pivot: XY,                      // rotation pivot within the hand image
     pub offset: u32,
     pub width: u16,
     pub height: u16,
@@ -195,3 +193,33 @@ pub struct DashHeader {
     pub width: u16,
     pub height: u16,
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+    use crate::codec::Codec;
+
+    #[test]
+    fn sizes_match_the_codec() {
+        let codec = Codec::for_api_ver(4).unwrap();
+        assert_eq!(size_of::<FaceHeaderN>(), codec.header_size);
+        assert_eq!(size_of::<DigitsHeader>(), codec.digit_set_size);
+        let size = |e_type: u8| codec.element_sizes.iter().find(|s| s.e_type == e_type).unwrap();
+        assert_eq!(size_of::<ImageHeader>(), size(0).base);
+        assert_eq!(size_of::<TimeNumHeader>(), size(2).base);
+        assert_eq!(size_of::<DayNameHeader>(), size(4).base);
+        assert_eq!(size_of::<BatteryFillHeader>(), size(5).base);
+        assert_eq!(size_of::<HeartRateNumHeader>(), size(6).base);
+        assert_eq!(size_of::<StepsNumHeader>(), size(7).base);
+        assert_eq!(size_of::<KCalNumHeader>(), size(9).base);
+        assert_eq!(size_of::<TimeHandHeader>(), size(10).base);
+        assert_eq!(size_of::<DayNumHeader>(), size(13).base);
+        assert_eq!(size_of::<MonthNumHeader>(), size(15).base);
+        assert_eq!(size_of::<WeatherHeader>(), size(27).base + 9 * size(27).per_image);
+        assert_eq!(size_of::<Unknown1D01Header>(), size(29).base);
+        assert_eq!(size_of::<DashHeader>(), size(35).base);
+        assert_eq!(size_of::<OffsetWidthHeight>(), size(18).per_image);
+    }
+}
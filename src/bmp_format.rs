@@ -43,7 +43,7 @@ const CLASSIC_SIZE: usize = 54 + 12;
 impl BMPHeaderClassic {
     pub fn from_bytes(bytes: &[u8]) -> Self {
         assert_eq!(CLASSIC_SIZE, std::mem::size_of::<Self>(), "BMPHeaderClassic is of unexpected size!");
-        assert!(bytes.len() < CLASSIC_SIZE, "BMP file is too small.");
+        assert!(bytes.len() >= CLASSIC_SIZE, "BMP file is too small.");
         confirm_le_byte_order();
         // If:
         // - Architecture byte order is little-endian
@@ -85,7 +85,7 @@ pub struct BMPHeaderV4 {
 impl BMPHeaderV4 {
     pub fn from_bytes(bytes: &[u8]) -> Self {
         assert_eq!(V4SIZE, std::mem::size_of::<Self>(), "BMPHeaderV4 is of unexpected size!");
-        assert!(bytes.len() < V4SIZE, "BMP file is too small.");
+        assert!(bytes.len() >= V4SIZE, "BMP file is too small.");
         confirm_le_byte_order();
         // If:
         // - Architecture byte order is little-endian
@@ -177,8 +177,9 @@ impl BMPHeaderV5 {
         dest.file_size = dest.image_data_size + V5SIZE as u32;
         dest.hres = 2835;
         dest.vres = 2835;
-        return dest;
+        dest
     }
+    #[allow(clippy::wrong_self_convention)]     // by reference, as it is transmuted in place
     pub fn to_bytes(&self) -> Vec<u8> {
         assert_eq!(V5SIZE, std::mem::size_of::<BMPHeaderV5>(), "BMPHeaderV5 is not 138 bytes!");
         confirm_le_byte_order();
@@ -0,0 +1,131 @@
+//  codec.rs - the sizes in the binary layout that depend on api_ver
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// Each api_ver we understand has a Codec, which is a table of sizes: the header, a digit set, and each element type.
+// FaceN::from_bin and FaceN::to_bin pick one from the api_ver in the header, so a file from newer firmware is
+// rejected instead of being misparsed.
+// The fields inside each element are read and written the same way for every version, by Element::from_bin and
+// to_bin. A version that only changes sizes is added to CODECS, one that changes the fields needs those changed too.
+
+
+use crate::elements::Element;
+
+
+// size of an element in the binary file, including the 'one' and 'e_type' bytes
+#[derive(Clone, Copy, Debug)]
+pub struct ElementSize
+{
+    pub e_type: u8,
+    pub base: usize,
+    pub per_image: usize,           // for elements with a count of images
+    pub count_at: usize,            // where that count is in the element
+}
+
+const fn fixed(e_type: u8, base: usize) -> ElementSize {
+    ElementSize { e_type, base, per_image: 0, count_at: 0 }
+}
+
+#[derive(Debug)]
+pub struct Codec
+{
+    pub api_ver: u16,
+    pub header_size: usize,         // the file header, the digits section starts after it
+    pub digits_intro: u16,          // the first two bytes of the digits section
+    pub digit_set_size: usize,
+    pub element_sizes: &'static [ElementSize],
+}
+
+const ELEMENTS_V4: [ElementSize; 14] = [
+    fixed(0, 14),                   // image
+    fixed(2, 34),                   // time_num
+    fixed(4, 63),                   // day_name
+    fixed(5, 42),                   // battery_fill
+    fixed(6, 26),                   // heart_rate_num
+    fixed(7, 26),                   // steps_num
+    fixed(9, 19),                   // k_cal_num
+    fixed(10, 19),                  // time_hand
+    fixed(13, 12),                  // day_num
+    fixed(15, 12),                  // month_num
+    ElementSize { e_type: 18, base: 8, per_image: 8, count_at: 3 },     // bar_display
+    ElementSize { e_type: 27, base: 7, per_image: 8, count_at: 2 },     // weather
+    fixed(29, 3),                   // unknown29
+    fixed(35, 10),                  // dash
+];
+
+const CODECS: [Codec; 1] = [
+    Codec {
+        api_ver: 4,
+        header_size: 16,
+        digits_intro: 0x0101,
+        digit_set_size: 83,
+        element_sizes: &ELEMENTS_V4,
+    },
+];
+
+impl Codec
+{
+    pub fn for_api_ver(api_ver: u16) -> Result<&'static Codec, String> {
+        CODECS.iter().find(|c| c.api_ver == api_ver).ok_or_else(|| {
+            let known: Vec<String> = CODECS.iter().map(|c| c.api_ver.to_string()).collect();
+            format!("Unsupported api_ver {}, this tool understands api_ver {}", api_ver, known.join(", "))
+        })
+    }
+
+    pub fn element_size(&self, el: &Element) -> Result<usize, String> {
+        let e_type = el.e_type();
+        let size = self.element_sizes.iter().find(|s| s.e_type == e_type)
+            .ok_or_else(|| format!("Element type {} ({}) is not supported by api_ver {}", e_type, el.type_name(), self.api_ver))?;
+        let count = match el {
+            Element::BarDisplay(e) => e.count as usize,
+            Element::Weather(e) => e.count as usize,
+            _ => 0,
+        };
+        Ok(size.base + count * size.per_image)
+    }
+
+    // the size of the element at offset in a binary file, before reading it, so a damaged file can't be read past its end
+    pub fn element_size_at(&self, file_data: &[u8], offset: usize) -> Result<usize, String> {
+        let e_type = *file_data.get(offset + 1).ok_or("The elements run past the end of the file")?;
+        let size = self.element_sizes.iter().find(|s| s.e_type == e_type)
+            .ok_or_else(|| format!("Unknown element type {} at offset {}", e_type, offset))?;
+        let count = match size.per_image {
+            0 => 0,
+            _ => *file_data.get(offset + size.count_at).ok_or("The elements run past the end of the file")? as usize,
+        };
+        let total = size.base + count * size.per_image;
+        if offset + total > file_data.len() {
+            return Err(format!("Element type {} at offset {} runs past the end of the file", e_type, offset));
+        }
+        Ok(total)
+    }
+
+    pub fn digits_size(&self, digit_sets: usize) -> usize {
+        if digit_sets > 0 { 2 + digit_sets * self.digit_set_size } else { 0 }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::device::DEVICES;
+    use crate::face::FaceN;
+    use crate::face_builder::FaceBuilder;
+    use crate::img::Img;
+
+    #[test]
+    fn unsupported_api_ver_is_rejected() {
+        let mut face = FaceBuilder::new(&DEVICES[0]).preview(Img::new(4, 4)).build().unwrap();
+        let mut bin = face.to_bin().unwrap();
+        bin[0..2].copy_from_slice(&5u16.to_le_bytes());
+        assert_eq!(FaceN::from_bin(&bin).err().unwrap(), "Unsupported api_ver 5, this tool understands api_ver 4");
+        face.api_ver = 3;
+        assert_eq!(face.to_bin().err().unwrap(), "Unsupported api_ver 3, this tool understands api_ver 4");
+    }
+}
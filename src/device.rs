@@ -0,0 +1,34 @@
+//  device.rs - the watches that we know about
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Device
+{
+    pub tpls: u16,
+    pub w: u16,                     // screen width in pixels
+    pub h: u16,                     // screen height in pixels
+    pub name: &'static str,         // example model
+}
+
+// see the 'Supported watches' table in README.md
+pub const DEVICES: [Device; 1] = [
+    Device { tpls: 70, w: 240, h: 296, name: "GTS 3" },
+];
+
+impl Device
+{
+    pub fn from_tpls(tpls: u16) -> Option<&'static Device> {
+        DEVICES.iter().find(|d| d.tpls == tpls)
+    }
+
+    pub fn from_size(w: u16, h: u16) -> Option<&'static Device> {
+        DEVICES.iter().find(|d| d.w == w && d.h == h)
+    }
+}
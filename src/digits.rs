@@ -25,17 +25,20 @@ pub struct Digits
 
 impl Digits 
 {
-    pub fn from_bin(file_data: &[u8], offset: usize, expected_set: usize) -> Digits {
+    pub fn from_bin(file_data: &[u8], offset: usize, expected_set: usize) -> Result<Digits, String> {
         let set: u8 = file_data[offset];
-        assert_eq!(set as usize, expected_set);         // ensure that the digits array index matches the specified digit set number
+        // ensure that the digits array index matches the specified digit set number
+        if set as usize != expected_set {
+            return Err(format!("Digit set at offset {} is numbered {}, expected {}", offset, set, expected_set));
+        }
         let mut img_data: Vec<ImgData> = Vec::new();
         for i in 0..10 {            
-            img_data.push( ImgData::from_owh(file_data, offset + 1 + 8 * i) );
+            img_data.push( ImgData::from_owh(file_data, offset + 1 + 8 * i)? );
         }
         let unknown: u16 = get_u16(file_data, offset + 81);
-        Digits {
+        Ok(Digits {
             img_data,
             unknown,
-        }
+        })
     }
 }
@@ -9,7 +9,7 @@
 
 
 use serde::{Serialize, Deserialize};
-use crate::img_data::ImgData;
+use crate::img_data::{ImgData, BlobWriter};
 use crate::util::{*};
 use crate::xy::{*};
 
@@ -38,51 +38,31 @@ pub enum Element
 
 impl Element 
 {
-    pub fn from_bin(file_data: &[u8], base_offset: usize) -> Element {
+    pub fn from_bin(file_data: &[u8], base_offset: usize) -> Result<Element, String> {
         let _one = file_data[base_offset];
         let e_type = file_data[base_offset + 1];
         let offset = base_offset + 2;
         let e: Element = match e_type {
-            0 => Element::Image(Image::from_bin(file_data, offset)),
+            0 => Element::Image(Image::from_bin(file_data, offset)?),
             2 => Element::TimeNum(TimeNum::from_bin(file_data, offset)),
-            4 => Element::DayName(DayName::from_bin(file_data, offset)),
-            5 => Element::BatteryFill(BatteryFill::from_bin(file_data, offset)),
+            4 => Element::DayName(DayName::from_bin(file_data, offset)?),
+            5 => Element::BatteryFill(BatteryFill::from_bin(file_data, offset)?),
             6 => Element::HeartRateNum(HeartRateNum::from_bin(file_data, offset)),
             7 => Element::StepsNum(StepsNum::from_bin(file_data, offset)),
             9 => Element::KCalNum(KCalNum::from_bin(file_data, offset)),
-            10 => Element::TimeHand(TimeHand::from_bin(file_data, offset)),
+            10 => Element::TimeHand(TimeHand::from_bin(file_data, offset)?),
             13 => Element::DayNum(DayNum::from_bin(file_data, offset)),
             15 => Element::MonthNum(MonthNum::from_bin(file_data, offset)),
-            18 => Element::BarDisplay(BarDisplay::from_bin(file_data, offset)),
-            27 => Element::Weather(Weather::from_bin(file_data, offset)),
+            18 => Element::BarDisplay(BarDisplay::from_bin(file_data, offset)?),
+            27 => Element::Weather(Weather::from_bin(file_data, offset)?),
             29 => Element::Unknown29(Unknown29::from_bin(file_data, offset)),
-            35 => Element::Dash(Dash::from_bin(file_data, offset)),
+            35 => Element::Dash(Dash::from_bin(file_data, offset)?),
             _ => Element::Unknown,
         };
-        return e;
-    }
-    pub fn bin_size(&self) -> usize {
-        // returns the size of the binary file counterpart struct. includes the 'one' and 'e_type' bytes.
-        return match self {
-            Element::Image(_) => 14,
-            Element::TimeNum(_) => 34,
-            Element::DayName(_) => 63,
-            Element::BatteryFill(_) => 42,
-            Element::HeartRateNum(_) => 26,
-            Element::StepsNum(_) => 26,
-            Element::KCalNum(_) => 19,
-            Element::TimeHand(_) => 19,
-            Element::DayNum(_) => 12,
-            Element::MonthNum(_) => 12,
-            Element::BarDisplay(e) => e.bin_size(),
-            Element::Weather(e) => e.bin_size(),
-            Element::Unknown29(_) => 3,
-            Element::Dash(_) => 10,
-            _ => panic!("ERROR: Unknown type requested in Element::bin_size()!"),
-        };        
+        Ok(e)
     }
     pub fn e_type(&self) -> u8 {
-        return match self {
+        match self {
             Element::Image(_) => 0,
             Element::TimeNum(_) => 2,
             Element::DayName(_) => 4,
@@ -98,26 +78,59 @@ impl Element
             Element::Unknown29(_) => 29,
             Element::Dash(_) => 35,
             _ => panic!("ERROR: Unknown type requested in Element::e_type()!"),
-        };        
+        }
     }
-    pub fn to_bin(&self, blob_data: &mut Vec<u8>, blob_offset: &mut u32) -> Vec::<u8> {
+    pub fn type_name(&self) -> &'static str {
+        // the same name that is used for the e_type in the json file
+        match self {
+            Element::Image(_) => "image",
+            Element::TimeNum(_) => "time_num",
+            Element::DayName(_) => "day_name",
+            Element::BatteryFill(_) => "battery_fill",
+            Element::HeartRateNum(_) => "heart_rate_num",
+            Element::StepsNum(_) => "steps_num",
+            Element::KCalNum(_) => "k_cal_num",
+            Element::TimeHand(_) => "time_hand",
+            Element::DayNum(_) => "day_num",
+            Element::MonthNum(_) => "month_num",
+            Element::BarDisplay(_) => "bar_display",
+            Element::Weather(_) => "weather",
+            Element::Unknown29(_) => "unknown29",
+            Element::Dash(_) => "dash",
+            Element::Unknown => "unknown",
+        }
+    }
+    pub fn img_data_mut(&mut self) -> Vec<&mut ImgData> {
+        // the images of this element, in the order they are in the binary file
+        match self {
+            Element::Image(e) => vec![ &mut e.img_data ],
+            Element::DayName(e) => e.img_data.iter_mut().collect(),
+            Element::BatteryFill(e) => vec![ &mut e.img_data, &mut e.image_data1, &mut e.image_data2 ],
+            Element::TimeHand(e) => vec![ &mut e.img_data ],
+            Element::BarDisplay(e) => e.img_data.iter_mut().collect(),
+            Element::Weather(e) => e.img_data.iter_mut().collect(),
+            Element::Dash(e) => vec![ &mut e.img_data ],
+            _ => Vec::new(),        // no images
+        }
+    }
+    pub fn to_bin(&self, blobs: &mut BlobWriter) -> Vec::<u8> {
         // return the binary form of this element
         let mut h = Vec::<u8>::from([ 1, self.e_type() ]);
         h.extend( match self {
-            Element::Image(el) =>       el.to_bin(blob_data, blob_offset),
+            Element::Image(el) =>       el.to_bin(blobs),
             Element::TimeNum(el) =>     el.to_bin(),
-            Element::DayName(el) =>     el.to_bin(blob_data, blob_offset),
-            Element::BatteryFill(el) => el.to_bin(blob_data, blob_offset),
+            Element::DayName(el) =>     el.to_bin(blobs),
+            Element::BatteryFill(el) => el.to_bin(blobs),
             Element::HeartRateNum(el) => el.to_bin(),
             Element::StepsNum(el) =>    el.to_bin(),
             Element::KCalNum(el) =>     el.to_bin(),
-            Element::TimeHand(el) =>    el.to_bin(blob_data, blob_offset),
+            Element::TimeHand(el) =>    el.to_bin(blobs),
             Element::DayNum(el) =>      el.to_bin(),
             Element::MonthNum(el) =>    el.to_bin(),
-            Element::BarDisplay(el) =>  el.to_bin(blob_data, blob_offset),
-            Element::Weather(el) =>     el.to_bin(blob_data, blob_offset),
+            Element::BarDisplay(el) =>  el.to_bin(blobs),
+            Element::Weather(el) =>     el.to_bin(blobs),
             Element::Unknown29(el) =>   el.to_bin(),
-            Element::Dash(el) =>        el.to_bin(blob_data, blob_offset),
+            Element::Dash(el) =>        el.to_bin(blobs),
             _ => panic!("ERROR: Unknown type requested in Element::e_type()!"),
         });
         h
@@ -136,25 +149,22 @@ pub struct Image
 
 impl Image 
 {
-    pub fn from_bin(file_data: &[u8], offset: usize) -> Image {
-        Image {
+    pub fn from_bin(file_data: &[u8], offset: usize) -> Result<Image, String> {
+        Ok(Image {
             x: get_u16(file_data, offset),
             y: get_u16(file_data, offset+2),
-           img_data: ImgData::from_owh(file_data, offset+4),
-        }
+            img_data: ImgData::from_owh(file_data, offset+4)?,
+        })
     }
-    // takes mutable references to blob_data and blob_offset for storing the image data
+    // takes a BlobWriter for storing the image data
     // returns a Vec<u8> full of the header data
-    pub fn to_bin(&self, blob_data: &mut Vec<u8>, blob_offset: &mut u32) -> Vec<u8> {
+    pub fn to_bin(&self, blobs: &mut BlobWriter) -> Vec<u8> {
         let mut h = Vec::<u8>::new();
         h.extend(self.x.to_le_bytes());
         h.extend(self.y.to_le_bytes());
-        h.extend(blob_offset.to_le_bytes());
+        h.extend(blobs.push(&self.img_data).to_le_bytes());
         h.extend(self.img_data.w.to_le_bytes());
         h.extend(self.img_data.h.to_le_bytes());
-        let bd = self.img_data.to_bin();
-        *blob_offset += bd.len() as u32;
-        blob_data.extend(bd);
         h
     }
 }
@@ -169,6 +179,7 @@ pub struct TimeNum
 
 impl TimeNum 
 {
+    #[allow(clippy::identity_op)]       // the 1 * keeps the xys lined up
     pub fn from_bin(file_data: &[u8], offset: usize) -> Self {
         let r = &file_data[offset..(offset+32)];
         Self {
@@ -203,7 +214,7 @@ pub struct DayName
 
 impl DayName 
 {
-    pub fn from_bin(file_data: &[u8], offset: usize) -> Self {
+    pub fn from_bin(file_data: &[u8], offset: usize) -> Result<Self, String> {
         let r = &file_data[offset..(offset+61)];
         let mut e = Self {
             n_type: r[0],
@@ -213,25 +224,22 @@ impl DayName
         };
         let mut owh_offset = offset + 5;
         for _ in 0..7 {
-            e.img_data.push(ImgData::from_owh(file_data, owh_offset));
+            e.img_data.push(ImgData::from_owh(file_data, owh_offset)?);
             owh_offset += 8;
         }
-        e
+        Ok(e)
     }
-    // takes mutable references to blob_data and blob_offset for storing the image data
+    // takes a BlobWriter for storing the image data
     // returns a Vec<u8> full of the header data
-    pub fn to_bin(&self, blob_data: &mut Vec<u8>, blob_offset: &mut u32) -> Vec<u8> {
+    pub fn to_bin(&self, blobs: &mut BlobWriter) -> Vec<u8> {
         let mut h = Vec::<u8>::new();
         h.push(self.n_type);
         h.extend(self.x.to_le_bytes());
         h.extend(self.y.to_le_bytes());
         for id in self.img_data.iter() {      // size 7
-            h.extend(blob_offset.to_le_bytes());
+            h.extend(blobs.push(id).to_le_bytes());
             h.extend(id.w.to_le_bytes());
             h.extend(id.h.to_le_bytes());
-            let bd = id.to_bin();
-            *blob_offset += bd.len() as u32;
-            blob_data.extend(bd);
         }
         h
     }
@@ -255,49 +263,40 @@ pub struct BatteryFill
 
 impl BatteryFill 
 {
-    pub fn from_bin(file_data: &[u8], offset: usize) -> Self {
+    pub fn from_bin(file_data: &[u8], offset: usize) -> Result<Self, String> {
         let r = &file_data[offset..(offset+40)];
-        Self {
+        Ok(Self {
             x: get_u16(r, 0),
             y: get_u16(r, 2),
-            img_data: ImgData::from_owh(file_data, offset + 4),
+            img_data: ImgData::from_owh(file_data, offset + 4)?,
             x1: r[12],
             y1: r[13],
             x2: r[14],
             y2: r[15],
             unknown0: get_u32(r, 16),
             unknown1: get_u32(r, 20),
-            image_data1: ImgData::from_owh(file_data, offset + 24),
-            image_data2: ImgData::from_owh(file_data, offset + 32),
-        }
+            image_data1: ImgData::from_owh(file_data, offset + 24)?,
+            image_data2: ImgData::from_owh(file_data, offset + 32)?,
+        })
     }
-    pub fn to_bin(&self, blob_data: &mut Vec<u8>, blob_offset: &mut u32) -> Vec<u8> {
+    pub fn to_bin(&self, blobs: &mut BlobWriter) -> Vec<u8> {
         let mut h = Vec::<u8>::new();
         h.extend(self.x.to_le_bytes());
         h.extend(self.y.to_le_bytes());
-        h.extend(blob_offset.to_le_bytes());
+        h.extend(blobs.push(&self.img_data).to_le_bytes());
         h.extend(self.img_data.w.to_le_bytes());
         h.extend(self.img_data.h.to_le_bytes());
-        let bd = self.img_data.to_bin();
-        *blob_offset += bd.len() as u32;
-        blob_data.extend(bd);
         h.extend([ self.x1, self.y1, self.x2, self.y2 ]);
         h.extend(self.unknown0.to_le_bytes());
         h.extend(self.unknown1.to_le_bytes());
         
-        h.extend(blob_offset.to_le_bytes());
+        h.extend(blobs.push(&self.image_data1).to_le_bytes());
         h.extend(self.image_data1.w.to_le_bytes());
         h.extend(self.image_data1.h.to_le_bytes());
-        let bd = self.image_data1.to_bin();
-        *blob_offset += bd.len() as u32;
-        blob_data.extend(bd);
 
-        h.extend(blob_offset.to_le_bytes());
+        h.extend(blobs.push(&self.image_data2).to_le_bytes());
         h.extend(self.image_data2.w.to_le_bytes());
         h.extend(self.image_data2.h.to_le_bytes());
-        let bd = self.image_data2.to_bin();
-        *blob_offset += bd.len() as u32;
-        blob_data.extend(bd);
 
         h
     }   
@@ -406,41 +405,60 @@ impl KCalNum
     }    
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HandType
+{
+    Hour = 0,
+    Minute = 1,
+    Second = 2,
+}
+
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct TimeHand 
 {       // e_type 10
     pub h_type: u8,             // 0 = hour, 1 = minutes, 2 = seconds
-    pub unknown_x: u16,
-    pub unknown_y: u16,
-    pub img_data: ImgData,
-    pub x: u16,
+    #[serde(alias = "unknown_x")]
+    pub pivot_x: u16,           // rotation pivot, in pixels from the top left of the hand image
+    #[serde(alias = "unknown_y")]
+    pub pivot_y: u16,
+    pub img_data: ImgData,      // the hand pointing to 12 o'clock
+    pub x: u16,                 // the point on the screen that the pivot sits on (the dial centre)
     pub y: u16,
 }
 
+impl HandType
+{
+    pub fn from_h_type(h_type: u8) -> Option<HandType> {
+        match h_type {
+            0 => Some(HandType::Hour),
+            1 => Some(HandType::Minute),
+            2 => Some(HandType::Second),
+            _ => None,
+        }
+    }
+}
+
 impl TimeHand 
 {
-    pub fn from_bin(file_data: &[u8], offset: usize) -> Self {
+    pub fn from_bin(file_data: &[u8], offset: usize) -> Result<Self, String> {
         let r = &file_data[offset..(offset+17)];
-        Self {
+        Ok(Self {
             h_type: r[0],
-            unknown_x: get_u16(r, 1),
-            unknown_y: get_u16(r, 3),
-            img_data: ImgData::from_owh(file_data, offset+5),
+            pivot_x: get_u16(r, 1),
+            pivot_y: get_u16(r, 3),
+            img_data: ImgData::from_owh(file_data, offset+5)?,
             x: get_u16(r, 13),
             y: get_u16(r, 15),
-        }
+        })
     }
-    pub fn to_bin(&self, blob_data: &mut Vec<u8>, blob_offset: &mut u32) -> Vec<u8> {
+    pub fn to_bin(&self, blobs: &mut BlobWriter) -> Vec<u8> {
         let mut h = Vec::<u8>::new();
         h.push(self.h_type);
-        h.extend(self.unknown_x.to_le_bytes());
-        h.extend(self.unknown_y.to_le_bytes());
-        h.extend(blob_offset.to_le_bytes());
+        h.extend(self.pivot_x.to_le_bytes());
+        h.extend(self.pivot_y.to_le_bytes());
+        h.extend(blobs.push(&self.img_data).to_le_bytes());
         h.extend(self.img_data.w.to_le_bytes());
         h.extend(self.img_data.h.to_le_bytes());
-        let bd = self.img_data.to_bin();
-        *blob_offset += bd.len() as u32;
-        blob_data.extend(bd);
         h.extend(self.x.to_le_bytes());
         h.extend(self.y.to_le_bytes());
         h
@@ -520,7 +538,7 @@ pub struct BarDisplay
 
 impl BarDisplay 
 {
-    pub fn from_bin(file_data: &[u8], offset: usize) -> Self {
+    pub fn from_bin(file_data: &[u8], offset: usize) -> Result<Self, String> {
         let r = &file_data[offset..];
         let b_type = r[0];
         let count = r[1];
@@ -529,32 +547,26 @@ impl BarDisplay
         let y = get_u16(r, 4);
         let mut img_data = Vec::<ImgData>::new();
         for i in 0..c {
-            img_data.push(ImgData::from_owh(file_data, offset + 6 + i * 8));
+            img_data.push(ImgData::from_owh(file_data, offset + 6 + i * 8)?);
         }
-        Self {
+        Ok(Self {
             b_type,
             count,
             x,
             y,
             img_data,
-        }
+        })
     }
-    pub fn bin_size(&self) -> usize {
-        return 8 + (self.count as usize) * 8;
-    }
-    pub fn to_bin(&self, blob_data: &mut Vec<u8>, blob_offset: &mut u32) -> Vec<u8> {
+    pub fn to_bin(&self, blobs: &mut BlobWriter) -> Vec<u8> {
         let mut h = Vec::<u8>::new();
         h.push(self.b_type);
         h.push(self.count);
         h.extend(self.x.to_le_bytes());
         h.extend(self.y.to_le_bytes());
         for id in self.img_data.iter() {      // size is 'count'
-            h.extend(blob_offset.to_le_bytes());
+            h.extend(blobs.push(id).to_le_bytes());
             h.extend(id.w.to_le_bytes());
             h.extend(id.h.to_le_bytes());
-            let bd = id.to_bin();
-            *blob_offset += bd.len() as u32;
-            blob_data.extend(bd);
         }
         h
     }    
@@ -571,35 +583,29 @@ pub struct Weather
 
 impl Weather 
 {
-    pub fn from_bin(file_data: &[u8], offset: usize) -> Self {
+    pub fn from_bin(file_data: &[u8], offset: usize) -> Result<Self, String> {
         let r = &file_data[offset..];
         let c = r[0] as usize;
         let mut img_data = Vec::<ImgData>::new();
         for i in 0..c {
-            img_data.push(ImgData::from_owh(file_data, offset + 5 + i * 8));
+            img_data.push(ImgData::from_owh(file_data, offset + 5 + i * 8)?);
         }
-        Self {
+        Ok(Self {
             count: r[0],
             x: get_u16(r, 1),
             y: get_u16(r, 3),
             img_data,
-        }
+        })
     }
-    pub fn bin_size(&self) -> usize {
-        return 7 + (self.count as usize) * 8;
-    }
-    pub fn to_bin(&self, blob_data: &mut Vec<u8>, blob_offset: &mut u32) -> Vec<u8> {
+    pub fn to_bin(&self, blobs: &mut BlobWriter) -> Vec<u8> {
         let mut h = Vec::<u8>::new();
         h.push(self.count);
         h.extend(self.x.to_le_bytes());
         h.extend(self.y.to_le_bytes());
         for id in self.img_data.iter() {      // size 'count'
-            h.extend(blob_offset.to_le_bytes());
+            h.extend(blobs.push(id).to_le_bytes());
             h.extend(id.w.to_le_bytes());
             h.extend(id.h.to_le_bytes());
-            let bd = id.to_bin();
-            *blob_offset += bd.len() as u32;
-            blob_data.extend(bd);
         }
         h
     }    
@@ -631,19 +637,16 @@ pub struct Dash
 
 impl Dash 
 {
-    pub fn from_bin(file_data: &[u8], offset: usize) -> Self {
-        Self {
-            img_data: ImgData::from_owh(file_data, offset),
-        }
+    pub fn from_bin(file_data: &[u8], offset: usize) -> Result<Self, String> {
+        Ok(Self {
+            img_data: ImgData::from_owh(file_data, offset)?,
+        })
     }
-    pub fn to_bin(&self, blob_data: &mut Vec<u8>, blob_offset: &mut u32) -> Vec<u8> {
+    pub fn to_bin(&self, blobs: &mut BlobWriter) -> Vec<u8> {
         let mut h = Vec::<u8>::new();
-        h.extend(blob_offset.to_le_bytes());
+        h.extend(blobs.push(&self.img_data).to_le_bytes());
         h.extend(self.img_data.w.to_le_bytes());
         h.extend(self.img_data.h.to_le_bytes());
-        let bd = self.img_data.to_bin();
-        *blob_offset += bd.len() as u32;
-        blob_data.extend(bd);
         h
     }    
 }
@@ -16,13 +16,61 @@
 //#![allow(dead_code)]
 
 
+use std::fmt;
 use serde::{Serialize, Deserialize};
 use crate::util::{*};
-use crate::img_data::{ImgData, DumpFormat};
+use crate::img_data::{ImgData, DumpFormat, BlobWriter};
 use crate::elements::{*};
 use crate::digits::Digits;
+use crate::codec::Codec;
 
 
+// a part of a binary watch face file, for inspecting it
+#[derive(Clone, PartialEq, Debug)]
+pub struct Region
+{
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+}
+
+// the parts of the original binary file that aren't images or headers, so a repack can be identical
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct OriginalLayout
+{
+    pub file_size: u32,
+    pub gaps: Vec<Gap>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Gap
+{
+    pub offset: u32,
+    pub bytes: String,          // hex
+}
+
+// an image that isn't the size watchface.json says it is
+#[derive(Clone, PartialEq, Debug)]
+pub struct SizeChange
+{
+    pub index: usize,                   // in all_img_data_mut()
+    pub what: &'static str,             // what it belongs to
+    pub file_name: Option<String>,      // None if it is embedded
+    pub declared: (u16, u16),
+    pub actual: (u16, u16),
+}
+
+impl fmt::Display for SizeChange
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file_name {
+            Some(n) => write!(f, "Image '{}'", n)?,
+            None => write!(f, "Image {} ({}, embedded)", self.index, self.what)?,
+        }
+        write!(f, " is {}x{}, but watchface.json says {}x{}", self.actual.0, self.actual.1, self.declared.0, self.declared.1)
+    }
+}
+
 // FACEN STARTS HERE
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -36,20 +84,33 @@ pub struct FaceN
     pub preview_img_data: ImgData,
     pub digits: Vec<Digits>,
     pub elements: Vec<Element>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_layout: Option<OriginalLayout>,
 }
 
 impl FaceN 
 {
-    pub fn from_bin(file_data: &[u8]) -> FaceN {
+    pub fn from_bin(file_data: &[u8]) -> Result<FaceN, String> {
+        Ok(Self::from_bin_with_layout(file_data)?.0)
+    }
+
+    // as from_bin, but also returns where each part of the header section was found
+    pub fn from_bin_with_layout(file_data: &[u8]) -> Result<(FaceN, Vec<Region>), String> {
+        if file_data.len() < 16 {
+            return Err("The file is too small to be a watch face".to_string());
+        }
+        let codec = Codec::for_api_ver(get_u16(file_data, 0))?;
+        let mut layout: Vec<Region> = vec![ Region { name: "header".to_string(), offset: 0, size: codec.header_size } ];
         let mut f = FaceN {
             type_str: "extrathunder watchface".to_string(),
             rev: 0,
             tpls: 0,
             api_ver:   get_u16(file_data, 0),
             unknown:   get_u16(file_data, 2),
-            preview_img_data: ImgData::from_owh(file_data, 4),
+            preview_img_data: ImgData::from_owh(file_data, 4)?,
             digits: Vec::new(),
             elements: Vec::new(),
+            original_layout: None,
         };
         let d_offset = get_u16(file_data, 12);
         let e_offset = get_u16(file_data, 14);
@@ -58,17 +119,25 @@ impl FaceN
         if d_offset != 0 {
             // we start at d_offset
             let mut offset: usize = d_offset.into();
-            let digits_size: usize = std::mem::size_of::<crate::binary_face_n::DigitsHeader>();
+            let digits_size: usize = codec.digit_set_size;
                 
-            // Read the introduction to the digit section 0x0101
+            if offset + 2 > file_data.len() {
+                return Err("The digits run past the end of the file".to_string());
+            }
+            // Read the introduction to the digit section, 0x0101 so far
             let dss = get_u16(file_data, offset);
-            if dss != 0x0101 {
-                println!("WARNING: Unknown start to digits section: 0x{:04X}", dss);
+            if dss != codec.digits_intro {
+                eprintln!("WARNING: Unknown start to digits section: 0x{:04X}", dss);
             }
+            layout.push(Region { name: "digits intro".to_string(), offset, size: 2 });
             offset += 2;
             let mut digits_count = 0;
             while offset < e_offset.into() {
-                let digits = Digits::from_bin(file_data, offset, digits_count);
+                if offset + digits_size > file_data.len() {
+                    return Err(format!("Digit set {} runs past the end of the file", digits_count));
+                }
+                let digits = Digits::from_bin(file_data, offset, digits_count)?;
+                layout.push(Region { name: format!("digit set {}", digits_count), offset, size: digits_size });
                 f.digits.push(digits);
                 digits_count += 1;
                 offset += digits_size;
@@ -78,74 +147,111 @@ impl FaceN
         // read elements
         let mut offset = e_offset as usize;
         loop {
+            if offset + 2 > file_data.len() {
+                return Err("The elements run past the end of the file".to_string());
+            }
         	let one = file_data[offset];
-        	let e_type = file_data[offset+1];
             if one == 0 {
                 // End of header section        
+                layout.push(Region { name: "end of elements".to_string(), offset, size: 2 });
                 break;
             }
             // print!("Loading e_type {} ... ", e_type);
-            let e = Element::from_bin(file_data, offset);            
-            if e == Element::Unknown {
-                panic!("ERROR: Unknown Element {}", e_type);
-            }
-            offset += e.bin_size();
+            let size = codec.element_size_at(file_data, offset)?;
+            let e = Element::from_bin(file_data, offset)?;
+            layout.push(Region { name: format!("element {} ({})", f.elements.len(), e.type_name()), offset, size });
+            offset += size;
             f.elements.push(e);
             // println!("done.");
         }
-        f // return the FaceN struct
+        f.original_layout = Some(f.find_gaps(file_data, offset + 2));
+        Ok((f, layout)) // return the FaceN struct
+    }
+
+    // everything after the header section that isn't part of an image
+    fn find_gaps(&mut self, file_data: &[u8], header_end: usize) -> OriginalLayout {
+        let mut used: Vec<(usize, usize)> = self.all_img_data_mut().iter()
+            .filter_map(|(_, id)| id.offset.map(|o| (o as usize, o as usize + id.header.len() + id.data.len())))
+            .collect();
+        used.sort();
+        let mut gaps: Vec<Gap> = Vec::new();
+        let mut pos = header_end;
+        for (start, end) in used.iter().chain([ (file_data.len(), file_data.len()) ].iter()) {
+            if *start > pos {
+                gaps.push(Gap { offset: pos as u32, bytes: to_hex(&file_data[pos..*start]) });
+            }
+            pos = pos.max(*end);
+        }
+        OriginalLayout { file_size: file_data.len() as u32, gaps }
+    }
+
+    // the binary file. see to_bin_with_layout for when the images are put back where they were.
+    pub fn to_bin(&self) -> Result<Vec<u8>, String> {
+        Ok(self.to_bin_with_layout()?.0)
+    }
+
+    // the binary file, with the images where they were in the original file if they still fit there.
+    // if they don't, the file is laid out anew, and the second value says why.
+    pub fn to_bin_with_layout(&self) -> Result<(Vec<u8>, Option<String>), String> {
+        if self.original_layout.is_none() {
+            return Ok((self.build_bin(false)?, None));
+        }
+        match self.build_bin(true) {
+            Ok(data) => Ok((data, None)),
+            Err(e) => Ok((self.build_bin(false)?, Some(e))),
+        }
     }
 
-    pub fn to_bin(&self) -> Vec<u8> {
-        let digits_header_size = if self.digits.len() > 0 {
-            2 + self.digits.len() * 83
-        } else { 0 };
+    fn build_bin(&self, keep_offsets: bool) -> Result<Vec<u8>, String> {
+        let codec = Codec::for_api_ver(self.api_ver)?;
+        let digits_header_size = codec.digits_size(self.digits.len());
         let mut data: Vec<u8> = Vec::new();
         data.extend(self.api_ver.to_le_bytes());
         data.extend(self.unknown.to_le_bytes());
         data.extend([0, 0, 0, 0]);                          // we will fill this in later once we know the offset
         data.extend(self.preview_img_data.w.to_le_bytes());
         data.extend(self.preview_img_data.h.to_le_bytes());
-        let dh_offset: u16 = if self.digits.len() > 0 { 16 } else { 0 };
+        let dh_offset: u16 = if !self.digits.is_empty() { codec.header_size as u16 } else { 0 };
         data.extend(dh_offset.to_le_bytes());
-        let bh_offset = 16 + digits_header_size as u16;
+        let bh_offset = (codec.header_size + digits_header_size) as u16;
         data.extend(bh_offset.to_le_bytes());
+        data.resize(codec.header_size, 0);
 
         // we cant calculate offsets, until we know the size of all the headers
-        let mut total_header_size = 16;                     // size of the main header
+        let element_sizes = self.elements.iter().map(|el| codec.element_size(el)).collect::<Result<Vec<usize>, String>>()?;
+        let mut total_header_size = codec.header_size;      // size of the main header
         total_header_size += digits_header_size;            // size of the digits section
-        total_header_size += self.elements.iter().map(|el| el.bin_size()).sum::<usize>();   // size of all the elements
+        total_header_size += element_sizes.iter().sum::<usize>();   // size of all the elements
         total_header_size += 2;                             // there are two zero bytes that mark the end of the elements section
                 
-        let mut blob_data: Vec<u8> = Vec::new();
         let header_align = get_align_diff(total_header_size as u32);
-        let mut blob_offset: u32 = total_header_size as u32 + header_align as u32;        // align it to 32-bit
+        let blob_offset: u32 = total_header_size as u32 + header_align;        // align it to 32-bit
+        let mut blobs = BlobWriter::new(blob_offset, keep_offsets);
 
         // just save zeros for the digits headers for now...
         data.extend(vec![0; digits_header_size]);
 
         // create each of the elements binary headers and push their image data
-        for el in self.elements.iter() {
-            let expected_size = el.bin_size();
-            let el_data = el.to_bin(&mut blob_data, &mut blob_offset);
+        for (el, expected_size) in self.elements.iter().zip(element_sizes) {
+            let el_data = el.to_bin(&mut blobs);
             if expected_size != el_data.len() {
-                panic!("Size of binary element generated does not match expected size!");
+                return Err(format!("Element {} is {} bytes, but api_ver {} expects {}", el.type_name(), el_data.len(), self.api_ver, expected_size));
             } 
             data.extend( el_data );
-            pad_it(&mut blob_data);
-            align_it(&mut blob_offset);
+            pad_it(&mut blobs.data);
+            align_it(&mut blobs.offset);
         }
         data.extend([0, 0]);       // this ends the elements header section
 
         // confirm data size matches expected
         if data.len() != total_header_size {
-            panic!("header sizes mismatch!");
+            return Err(format!("The header is {} bytes, but should be {}", data.len(), total_header_size));
         }
 
         // go back and fill in the digits section
-        if self.digits.len() > 0 {
+        if !self.digits.is_empty() {
             let mut dh_offset: usize = dh_offset as usize;
-            put_u16(&mut data, dh_offset, 0x0101);
+            put_u16(&mut data, dh_offset, codec.digits_intro);
             dh_offset += 2;     //data.extend([1, 1]);       // this is an introductory sequence to the digits section!
             
         
@@ -153,54 +259,207 @@ impl FaceN
                 let mut dh: Vec<u8> = Vec::new();
                 dh.push(n as u8);
                 for id in d.img_data.iter() {
-                    let mut bd = id.to_bin();
-                    pad_it(&mut bd);
-                    dh.extend(blob_offset.to_le_bytes());       // offset of blob u32
+                    dh.extend(blobs.push(id).to_le_bytes());    // offset of blob u32
                     dh.extend(id.w.to_le_bytes());              // width of blob u16
                     dh.extend(id.h.to_le_bytes());              // height of blob u16
-                    blob_offset += bd.len() as u32;
-                    blob_data.extend(bd);                
                 }
                 dh.extend(d.unknown.to_le_bytes());
-                if dh.len() != 83 {
-                    panic!("Digits header size is not 83 bytes! Too few digits?");
+                if dh.len() != codec.digit_set_size {
+                    return Err(format!("Digit set {} header is not {} bytes, it needs 10 images", n, codec.digit_set_size));
                 }
                 
                 // copy the digits header into the main header
                 for (i,b) in dh.iter().enumerate() {
                     data[dh_offset+i] = *b;
                 }
-                dh_offset += 83;
+                dh_offset += codec.digit_set_size;
             }
         }
 
         // save the preview image data, and store the offset in the file header
-        blob_data.extend(self.preview_img_data.to_bin());
-        pad_it(&mut blob_data);
-        put_u32(&mut data, 4, blob_offset);
+        let preview_offset = blobs.push(&self.preview_img_data);
+        pad_it(&mut blobs.data);
+        put_u32(&mut data, 4, preview_offset);
+
+        if keep_offsets {
+            return self.place_blobs(data, blobs);
+        }
 
         // return allllll the binary data of the file
         data.extend(vec![0; header_align as usize]);   // align the start of the blob
-        data.extend(blob_data);
-        data
+        data.extend(blobs.data);
+        Ok(data)
     }
 
-    fn gen_name(prefix: &str, numbers: &[usize], format: &DumpFormat) -> String {
-        let ext = match format {
+    // put the header, the images and the gaps between them where they were in the original file
+    fn place_blobs(&self, header: Vec<u8>, blobs: BlobWriter) -> Result<Vec<u8>, String> {
+        let layout = self.original_layout.as_ref().ok_or("No original layout")?;
+        // an image without an offset was added or re-encoded since the file was dumped
+        if blobs.unplaced > 0 {
+            return Err(format!("{} images have no place in the original file, they are new or were re-encoded", blobs.unplaced));
+        }
+        let mut out: Vec<u8> = vec![0; layout.file_size as usize];
+        let mut written: Vec<bool> = vec![false; out.len()];
+        let mut parts: Vec<(u32, Vec<u8>)> = vec![ (0, header) ];
+        for g in layout.gaps.iter() {
+            parts.push((g.offset, from_hex(&g.bytes)?));
+        }
+        parts.extend(blobs.placed);
+        for (offset, bytes) in parts {
+            let start = offset as usize;
+            if start + bytes.len() > out.len() {
+                return Err(format!("Data at 0x{:X} no longer fits in the original {} byte file", offset, out.len()));
+            }
+            for (i, b) in bytes.iter().enumerate() {
+                // images can share data, but nothing else can overlap
+                if written[start + i] && out[start + i] != *b {
+                    return Err(format!("Data at 0x{:X} overlaps other data, an image may have changed size", offset));
+                }
+                out[start + i] = *b;
+                written[start + i] = true;
+            }
+        }
+        Ok(out)
+    }
+
+    // check the face for problems that would stop it working on the watch. returns a list of problems found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+
+        let codec = match Codec::for_api_ver(self.api_ver) {
+            Ok(c) => Some(c),
+            Err(e) => { problems.push(e); None },
+        };
+        if self.digits.len() > 255 {
+            problems.push(format!("Too many digit sets: {}", self.digits.len()));
+        }
+        for (n, d) in self.digits.iter().enumerate() {
+            if d.img_data.len() != 10 {
+                problems.push(format!("Digit set {} has {} images, expected 10", n, d.img_data.len()));
+                continue;
+            }
+            let (w, h) = (d.img_data[0].w, d.img_data[0].h);
+            if d.img_data.iter().any(|id| id.w != w || id.h != h) {
+                problems.push(format!("Digit set {} images are not all the same size", n));
+            }
+        }
+
+        // check any digit set references are valid
+        let check_set = |problems: &mut Vec<String>, n: usize, set: u8| {
+            if set as usize >= self.digits.len() {
+                problems.push(format!("Element {} uses digit set {}, which doesn't exist", n, set));
+            }
+        };
+        for (n, el) in self.elements.iter().enumerate() {
+            match el {
+                Element::TimeNum(e) => e.digit_sets.iter().for_each(|s| check_set(&mut problems, n, *s)),
+                Element::HeartRateNum(e) => check_set(&mut problems, n, e.digit_set),
+                Element::StepsNum(e) => check_set(&mut problems, n, e.digit_set),
+                Element::KCalNum(e) => check_set(&mut problems, n, e.digit_set),
+                Element::DayNum(e) => check_set(&mut problems, n, e.digit_set),
+                Element::MonthNum(e) => check_set(&mut problems, n, e.digit_set),
+                Element::DayName(e) if e.img_data.len() != 7 => {
+                    problems.push(format!("Element {} (day_name) has {} images, expected 7", n, e.img_data.len()));
+                },
+                Element::BarDisplay(e) if e.img_data.len() != e.count as usize => {
+                    problems.push(format!("Element {} (bar_display) has {} images, but count is {}", n, e.img_data.len(), e.count));
+                },
+                Element::Weather(e) if e.img_data.len() != e.count as usize => {
+                    problems.push(format!("Element {} (weather) has {} images, but count is {}", n, e.img_data.len(), e.count));
+                },
+                Element::TimeHand(e) if e.h_type > 2 => {
+                    problems.push(format!("Element {} (time_hand) has unknown h_type {}", n, e.h_type));
+                },
+                Element::Unknown => problems.push(format!("Element {} is of unknown type", n)),
+                _ => {},
+            }
+            if let Some(c) = codec {
+                if *el != Element::Unknown {
+                    if let Err(e) = c.element_size(el) {
+                        problems.push(format!("Element {}: {}", n, e));
+                    }
+                }
+            }
+        }
+        problems
+    }
+
+    // every image in the face, with the name of what it belongs to: "preview", "digits", or the element type name
+    pub fn all_img_data_mut(&mut self) -> Vec<(&'static str, &mut ImgData)> {
+        let mut v: Vec<(&'static str, &mut ImgData)> = Vec::new();
+        v.push(("preview", &mut self.preview_img_data));
+        for d in self.digits.iter_mut() {
+            v.extend(d.img_data.iter_mut().map(|id| ("digits", id)));
+        }
+        for el in self.elements.iter_mut() {
+            let name = el.type_name();
+            v.extend(el.img_data_mut().into_iter().map(|id| (name, id)));
+        }
+        v
+    }
+
+    // the w and h of every image, in the same order as all_img_data_mut()
+    pub fn img_sizes(&mut self) -> Vec<(u16, u16)> {
+        self.all_img_data_mut().iter().map(|(_, id)| (id.w, id.h)).collect()
+    }
+
+    // the images that are a different size to before they were read in
+    pub fn size_changes(&mut self, declared: &[(u16, u16)]) -> Vec<SizeChange> {
+        let mut v: Vec<SizeChange> = Vec::new();
+        for (n, ((what, id), d)) in self.all_img_data_mut().into_iter().zip(declared).enumerate() {
+            if (id.w, id.h) != *d {
+                let file_name = if id.embedded.is_none() { id.file_name.clone() } else { None };
+                v.push(SizeChange { index: n, what, file_name, declared: *d, actual: (id.w, id.h) });
+            }
+        }
+        v
+    }
+
+    fn extension(format: &DumpFormat) -> &'static str {
+        match format {
             DumpFormat::BIN => ".bin",
-            DumpFormat::RAW => ".raw",
+            DumpFormat::RAW(_) => ".raw",
             DumpFormat::BMP => ".bmp",
-        };
-        let mut file_name: String = format!("{}", prefix);
+            DumpFormat::PPM => ".ppm",
+            DumpFormat::PAM => ".pam",
+            DumpFormat::TGA { .. } => ".tga",
+        }
+    }
+
+    fn gen_name(prefix: &str, numbers: &[usize], format: &DumpFormat) -> String {
+        let ext = Self::extension(format);
+        let mut file_name: String = prefix.to_string();
         for n in numbers {
             file_name += "_";
             file_name += &n.to_string();
         }
         file_name += ext;
-        return file_name;
+        file_name
+    }
+
+    // preview.bmp, digits/set0/3.bmp and elements/2_day_name/4.bmp, or all in one folder if flat
+    pub fn generate_file_names(&mut self, format: &DumpFormat, flat: bool) {
+        if flat {
+            self.generate_flat_file_names(format);
+            return;
+        }
+        let ext = Self::extension(format);
+        self.preview_img_data.set_file_name(&format!("preview{}", ext), true);
+        for (n, ds) in self.digits.iter_mut().enumerate() {
+            for (i, id) in ds.img_data.iter_mut().enumerate() {
+                id.set_file_name(&format!("digits/set{}/{}{}", n, i, ext), true);
+            }
+        }
+        for (n, el) in self.elements.iter_mut().enumerate() {
+            let folder = format!("elements/{}_{}", n, el.type_name());
+            for (i, id) in el.img_data_mut().into_iter().enumerate() {
+                id.set_file_name(&format!("{}/{}{}", folder, i, ext), true);
+            }
+        }
     }
 
-    pub fn generate_file_names(&mut self, format: &DumpFormat) {
+    // the names used before subfolders, e.g. day_name_0_4.bmp
+    fn generate_flat_file_names(&mut self, format: &DumpFormat) {
         let overwrite: bool = true;
 
         // write the preview filename
@@ -230,144 +489,259 @@ impl FaceN
                     day_name_counter += 1;
                 },
                 Element::BatteryFill(e) => {    // three images
-                    e.img_data.set_file_name(  &Self::gen_name("battery_fill", &[battery_fill_counter, 0], &format), overwrite );
-                    e.image_data1.set_file_name( &Self::gen_name("battery_fill", &[battery_fill_counter, 1], &format), overwrite );
-                    e.image_data2.set_file_name( &Self::gen_name("battery_fill", &[battery_fill_counter, 2], &format), overwrite );
+                    e.img_data.set_file_name(  &Self::gen_name("battery_fill", &[battery_fill_counter, 0], format), overwrite );
+                    e.image_data1.set_file_name( &Self::gen_name("battery_fill", &[battery_fill_counter, 1], format), overwrite );
+                    e.image_data2.set_file_name( &Self::gen_name("battery_fill", &[battery_fill_counter, 2], format), overwrite );
                     battery_fill_counter += 1;
                 }, 
                 Element::HeartRateNum(_) => {}, // no images
                 Element::StepsNum(_) => {},     // no images
                 Element::KCalNum(_) => {},      // no images
                 Element::TimeHand(e) => {       // one image, h_type in filename                    
-                    e.img_data.set_file_name( &Self::gen_name("time_hand", &[e.h_type as usize], &format), overwrite );
+                    e.img_data.set_file_name( &Self::gen_name("time_hand", &[e.h_type as usize], format), overwrite );
                 }, 
                 Element::DayNum(_) => {},       // no images
                 Element::MonthNum(_) => {},     // no images
                 Element::BarDisplay(e) => {     // variable images
                     for i in 0..e.img_data.len() {                        
-                        e.img_data[i].set_file_name( &Self::gen_name("bar_display", &[e.b_type as usize, i], &format), overwrite );
+                        e.img_data[i].set_file_name( &Self::gen_name("bar_display", &[e.b_type as usize, i], format), overwrite );
                     }
                 }, 
                 Element::Weather(e) => {        // variable images
                     for i in 0..e.img_data.len() {                        
-                        e.img_data[i].set_file_name( &Self::gen_name("weather", &[i], &format), overwrite );
+                        e.img_data[i].set_file_name( &Self::gen_name("weather", &[i], format), overwrite );
                     }
                 }, 
                 Element::Unknown29(_) => {},    // no images
                 Element::Dash(e) => {      // one image
-                    e.img_data.set_file_name( &Self::gen_name("dash", &[], &format), overwrite );
+                    e.img_data.set_file_name( &Self::gen_name("dash", &[], format), overwrite );
                 },
                 _ => panic!("ERROR: Unknown type requested in FaceN::generate_file_names()!"),
             };
         }
     }    
 
-    pub fn read_imgs(&mut self, folder_name: &str) {    // read in the image files
+    pub fn read_imgs(&mut self, folder_name: &str) -> Result<(), String> {    // read in the image files
         // read in the preview image
-        self.preview_img_data.read_img(folder_name);
+        self.preview_img_data.read_img(folder_name)?;
         
         // read in the digit image files
         for d in self.digits.iter_mut() {
             for i in 0..10 {
-                d.img_data[i].read_img(folder_name);
+                d.img_data[i].read_img(folder_name)?;
             }
         }
         // read in the element image files
         for el in self.elements.iter_mut() {
             match el {
                 Element::Image(e) => {          // one image, can be multiple Images
-                    e.img_data.read_img(folder_name);
+                    e.img_data.read_img(folder_name)?;
                 },
                 Element::TimeNum(_) => {},      // no images
                 Element::DayName(e) => {        // seven images
                     for id in e.img_data.iter_mut() {                        
-                        id.read_img(folder_name);
+                        id.read_img(folder_name)?;
                     }
                 },
                 Element::BatteryFill(e) => {    // three images
-                    e.img_data.read_img(folder_name);
-                    e.image_data1.read_img(folder_name);
-                    e.image_data2.read_img(folder_name);
+                    e.img_data.read_img(folder_name)?;
+                    e.image_data1.read_img(folder_name)?;
+                    e.image_data2.read_img(folder_name)?;
                 }, 
                 Element::HeartRateNum(_) => {}, // no images
                 Element::StepsNum(_) => {},     // no images
                 Element::KCalNum(_) => {},      // no images
                 Element::TimeHand(e) => {       // one image, h_type in filename                    
-                    e.img_data.read_img(folder_name);
+                    e.img_data.read_img(folder_name)?;
                 }, 
                 Element::DayNum(_) => {},       // no images
                 Element::MonthNum(_) => {},     // no images
                 Element::BarDisplay(e) => {     // variable images
                     for id in e.img_data.iter_mut() {                        
-                        id.read_img(folder_name);
+                        id.read_img(folder_name)?;
                     }
                 }, 
                 Element::Weather(e) => {        // variable images
                     for id in e.img_data.iter_mut() {                        
-                        id.read_img(folder_name);
+                        id.read_img(folder_name)?;
                     }
                 }, 
                 Element::Unknown29(_) => {},    // no images
                 Element::Dash(e) => {      // one image
-                    e.img_data.read_img(folder_name);
+                    e.img_data.read_img(folder_name)?;
                 },
-                _ => panic!("ERROR: Unknown type found in FaceN::read_imgs()!"),
+                _ => return Err("Unknown type found in FaceN::read_imgs()!".to_string()),
             };
         }
+        Ok(())
     }
 
-    pub fn write_imgs(&self, folder_name: &str, format: &DumpFormat) {
+    pub fn write_imgs(&self, folder_name: &str, format: &DumpFormat) -> Result<(), String> {
         // write the preview image
-        self.preview_img_data.write_img(folder_name, format);
+        self.preview_img_data.write_img(folder_name, format)?;
 
         // write the digits
         for d in self.digits.iter() {
             for i in 0..10 {
-                d.img_data[i].write_img(folder_name, format);
+                d.img_data[i].write_img(folder_name, format)?;
             }
         }        
         // write the elements
         for el in self.elements.iter() {
             match el {
                 Element::Image(e) => {          // one image, can be multiple Images
-                    e.img_data.write_img(folder_name, format);
+                    e.img_data.write_img(folder_name, format)?;
                 },
                 Element::TimeNum(_) => {},      // no images
                 Element::DayName(e) => {        // seven images
                     for id in e.img_data.iter() {                        
-                        id.write_img(folder_name, format);
+                        id.write_img(folder_name, format)?;
                     }
                 },
                 Element::BatteryFill(e) => {    // three images
-                    e.img_data.write_img(folder_name, format);
-                    e.image_data1.write_img(folder_name, format);
-                    e.image_data2.write_img(folder_name, format);
+                    e.img_data.write_img(folder_name, format)?;
+                    e.image_data1.write_img(folder_name, format)?;
+                    e.image_data2.write_img(folder_name, format)?;
                 }, 
                 Element::HeartRateNum(_) => {}, // no images
                 Element::StepsNum(_) => {},     // no images
                 Element::KCalNum(_) => {},      // no images
                 Element::TimeHand(e) => {       // one image, h_type in filename                    
-                    e.img_data.write_img(folder_name, format);
+                    e.img_data.write_img(folder_name, format)?;
                 }, 
                 Element::DayNum(_) => {},       // no images
                 Element::MonthNum(_) => {},     // no images
                 Element::BarDisplay(e) => {     // variable images
                     for id in e.img_data.iter() {                        
-                        id.write_img(folder_name, format);
+                        id.write_img(folder_name, format)?;
                     }
                 }, 
                 Element::Weather(e) => {        // variable images
                     for id in e.img_data.iter() {                        
-                        id.write_img(folder_name, format);
+                        id.write_img(folder_name, format)?;
                     }
                 }, 
                 Element::Unknown29(_) => {},    // no images
                 Element::Dash(e) => {      // one image
-                    e.img_data.write_img(folder_name, format);
+                    e.img_data.write_img(folder_name, format)?;
                 },
-                _ => panic!("ERROR: Unknown type found in FaceN::write_imgs()!"),
+                _ => return Err("Unknown type found in FaceN::write_imgs()!".to_string()),
             };
         }
+        Ok(())
     }    
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+    use crate::face_builder::FaceBuilder;
+    use crate::img::Img;
+    use crate::img_data::DumpFormat;
+    use crate::xy::XY;
+    use std::fs;
+
+    fn imgs(n: usize) -> Vec<Img> {
+        (0..n).map(|_| Img::new(6, 8)).collect()
+    }
+
+    // a bar display first, then digits and the rest, so every part of the header can be damaged
+    fn face_bin() -> Vec<u8> {
+        FaceBuilder::new(&DEVICES[0]).preview(Img::new(4, 4))
+            .bar_display(0, 10, 10, imgs(4))
+            .digit_set(imgs(10))
+            .time_num(0, [ XY { x: 0, y: 0 }, XY { x: 10, y: 0 }, XY { x: 20, y: 0 }, XY { x: 30, y: 0 } ])
+            .weather(50, 50, imgs(3))
+            .build().unwrap()
+            .to_bin().unwrap()
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let bin = face_bin();
+        assert!(FaceN::from_bin(&bin).is_ok());
+        // the last image is padded to 4 bytes, anything shorter than that cuts into it
+        for n in 0..bin.len() - 3 {
+            assert!(FaceN::from_bin(&bin[..n]).is_err(), "{} of {} bytes", n, bin.len());
+        }
+    }
+
+    #[test]
+    fn zero_height_image_is_an_error() {
+        let mut bin = face_bin();
+        bin[10..12].copy_from_slice(&0u16.to_le_bytes());      // the preview height
+        assert_eq!(FaceN::from_bin(&bin).err().unwrap(), format!("Image at offset {} has a height of 0", get_u32(&bin, 4)));
+    }
+
+    #[test]
+    fn damaged_tables_are_errors() {
+        let bin = face_bin();
+        let e_offset = get_u16(&bin, 14) as usize;
+
+        // more bar display images than the file has room for
+        let mut b = bin.clone();
+        b[e_offset + 3] = 255;
+        assert!(FaceN::from_bin(&b).err().unwrap().contains("runs past the end of the file"));
+
+        // an image offset past the end
+        let mut b = bin.clone();
+        b[4..8].copy_from_slice(&(bin.len() as u32).to_le_bytes());
+        assert!(FaceN::from_bin(&b).err().unwrap().contains("runs past the end of the file"));
+
+        // the elements start past the end
+        let mut b = bin.clone();
+        b[14..16].copy_from_slice(&(bin.len() as u16).to_le_bytes());
+        assert!(FaceN::from_bin(&b).is_err());
+
+        // a digit set with the wrong number
+        let mut b = bin.clone();
+        let d_offset = get_u16(&bin, 12) as usize;
+        b[d_offset + 2] = 7;
+        assert_eq!(FaceN::from_bin(&b).err().unwrap(), format!("Digit set at offset {} is numbered 7, expected 0", d_offset + 2));
+    }
+
+    // a face with some bytes after the last image, which only the original layout keeps
+    fn face_with_gap() -> (Vec<u8>, FaceN) {
+        let mut bin = face_bin();
+        bin.extend([ 0xAB; 8 ]);
+        let face = FaceN::from_bin(&bin).unwrap();
+        (bin, face)
+    }
+
+    #[test]
+    fn dump_and_pack_gives_the_same_file() {
+        let (bin, mut face) = face_with_gap();
+        let folder = std::env::temp_dir().join(format!("extrathundertool-face-{}", std::process::id()));
+        let folder_name = folder.to_str().unwrap();
+        fs::create_dir_all(&folder).unwrap();
+        face.generate_file_names(&DumpFormat::BMP, false);
+        face.write_imgs(folder_name, &DumpFormat::BMP).unwrap();
+        let mut packed: FaceN = serde_json::from_str(&serde_json::to_string(&face).unwrap()).unwrap();
+        packed.read_imgs(folder_name).unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(packed.to_bin_with_layout().unwrap(), (bin, None));
+    }
+
+    #[test]
+    fn changed_image_is_relocated() {
+        let (bin, mut face) = face_with_gap();
+        // a new image has no offset
+        face.preview_img_data = ImgData::from_img(&Img::new(5, 5)).unwrap();
+        let (new_bin, problem) = face.to_bin_with_layout().unwrap();
+        assert_eq!(problem.unwrap(), "1 images have no place in the original file, they are new or were re-encoded");
+        assert!(!new_bin.ends_with(&bin[bin.len() - 8..]));     // laid out anew, without the gap
+        assert_eq!(FaceN::from_bin(&new_bin).unwrap().preview_img_data.h, 5);
+
+        // a bigger image where a smaller one was
+        let (_, mut face) = face_with_gap();
+        let offset = face.preview_img_data.offset;
+        face.preview_img_data = ImgData::from_img(&Img::new(40, 40)).unwrap();
+        face.preview_img_data.offset = offset;
+        let (new_bin, problem) = face.to_bin_with_layout().unwrap();
+        assert!(problem.unwrap().contains("no longer fits"));
+        assert_eq!(FaceN::from_bin(&new_bin).unwrap().preview_img_data.w, 40);
+    }
+}
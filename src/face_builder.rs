@@ -0,0 +1,381 @@
+//  face_builder.rs - build a watch face in code
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// FaceBuilder takes decoded images (Img) and produces a FaceN that is ready for to_bin().
+// It is for code that makes faces, such as the tests. The commands all start from a file, so they don't use it.
+
+
+use crate::device::Device;
+use crate::digits::Digits;
+use crate::elements::{*};
+use crate::face::FaceN;
+use crate::img::Img;
+use crate::img_data::ImgData;
+use crate::xy::XY;
+
+
+// the api_ver written to new faces, unless FaceBuilder::api_ver() is used
+pub const DEFAULT_API_VER: u16 = 4;
+
+/// Builds a face from decoded images, encoding them and checking the result.
+///
+/// ```ignore
+/// let face = FaceBuilder::new(&DEVICES[0])
+///     .preview(preview_img)
+///     .background(background_img)
+///     .digit_set(digit_imgs)
+///     .time_num(0, [ XY { x: 20, y: 100 }, XY { x: 60, y: 100 }, XY { x: 130, y: 100 }, XY { x: 170, y: 100 } ])
+///     .hand(HandType::Minute, minute_img, XY { x: 5, y: 110 })
+///     .build()?;
+/// fs::write("watchface.bin", face.to_bin()?)?;
+/// ```
+pub struct FaceBuilder
+{
+    device: Device,
+    api_ver: u16,
+    unknown: u16,
+    preview: Option<ImgData>,
+    digits: Vec<Digits>,
+    elements: Vec<Element>,
+    errors: Vec<String>,
+}
+
+impl FaceBuilder
+{
+    pub fn new(device: &Device) -> Self {
+        Self {
+            device: device.clone(),
+            api_ver: DEFAULT_API_VER,
+            unknown: 0,
+            preview: None,
+            digits: Vec::new(),
+            elements: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    // encode an image, keeping the error for build()
+    fn encode(&mut self, img: &Img) -> ImgData {
+        ImgData::from_img(img).unwrap_or_else(|e| {
+            self.errors.push(e);
+            ImgData::default()
+        })
+    }
+
+    fn encode_all(&mut self, imgs: &[Img]) -> Vec<ImgData> {
+        imgs.iter().map(|img| self.encode(img)).collect()
+    }
+
+    pub fn api_ver(mut self, api_ver: u16) -> Self {
+        self.api_ver = api_ver;
+        self
+    }
+
+    pub fn preview(mut self, img: Img) -> Self {
+        self.preview = Some(self.encode(&img));
+        self
+    }
+
+    // a full screen image at 0,0
+    pub fn background(self, img: Img) -> Self {
+        self.image(0, 0, img)
+    }
+
+    pub fn image(mut self, x: u16, y: u16, img: Img) -> Self {
+        let img_data = self.encode(&img);
+        self.elements.push(Element::Image(Image { x, y, img_data }));
+        self
+    }
+
+    // add a set of ten digit images, 0 to 9. the sets are numbered in the order they are added.
+    pub fn digit_set(mut self, imgs: Vec<Img>) -> Self {
+        if imgs.len() != 10 {
+            self.errors.push(format!("digit_set {} needs 10 images, got {}", self.digits.len(), imgs.len()));
+            return self;
+        }
+        if imgs.iter().any(|i| i.w != imgs[0].w || i.h != imgs[0].h) {
+            self.errors.push(format!("digit_set {} images must all be the same size", self.digits.len()));
+        }
+        let img_data = self.encode_all(&imgs);
+        self.digits.push(Digits {
+            img_data,
+            unknown: 0,
+        });
+        self
+    }
+
+    // four digits HHMM, all from the same digit set
+    pub fn time_num(mut self, set: u8, xys: [XY; 4]) -> Self {
+        self.elements.push(Element::TimeNum(TimeNum { digit_sets: [set; 4], xys, unknown: [0; 12] }));
+        self
+    }
+
+    pub fn day_num(mut self, set: u8, align: u8, xys: [XY; 2]) -> Self {
+        self.elements.push(Element::DayNum(DayNum { digit_set: set, align, xys }));
+        self
+    }
+
+    pub fn month_num(mut self, set: u8, align: u8, xys: [XY; 2]) -> Self {
+        self.elements.push(Element::MonthNum(MonthNum { digit_set: set, align, xys }));
+        self
+    }
+
+    pub fn heart_rate_num(mut self, set: u8, align: u8, x: u16, y: u16) -> Self {
+        self.elements.push(Element::HeartRateNum(HeartRateNum { digit_set: set, align, x, y, unknown: [0; 18] }));
+        self
+    }
+
+    pub fn steps_num(mut self, set: u8, align: u8, x: u16, y: u16) -> Self {
+        self.elements.push(Element::StepsNum(StepsNum { digit_set: set, align, x, y, unknown: [0; 18] }));
+        self
+    }
+
+    pub fn kcal_num(mut self, set: u8, align: u8, x: u16, y: u16) -> Self {
+        self.elements.push(Element::KCalNum(KCalNum { digit_set: set, align, x, y, unknown: [0; 11] }));
+        self
+    }
+
+    // seven images, sunday first
+    pub fn day_name(mut self, n_type: u8, x: u16, y: u16, imgs: Vec<Img>) -> Self {
+        if imgs.len() != 7 {
+            self.errors.push(format!("day_name needs 7 images, got {}", imgs.len()));
+            return self;
+        }
+        let img_data = self.encode_all(&imgs);
+        self.elements.push(Element::DayName(DayName { n_type, x, y, img_data }));
+        self
+    }
+
+    // a hand that rotates around the centre of the screen. pivot is the point in the hand image that sits on the centre.
+    pub fn hand(self, h_type: HandType, img: Img, pivot: XY) -> Self {
+        let centre = XY { x: self.device.w / 2, y: self.device.h / 2 };
+        self.hand_at(h_type, img, pivot, centre)
+    }
+
+    pub fn hand_at(mut self, h_type: HandType, img: Img, pivot: XY, centre: XY) -> Self {
+        if pivot.x as u32 >= img.w || pivot.y as u32 >= img.h {
+            self.errors.push(format!("{:?} hand pivot {},{} is outside the {}x{} image", h_type, pivot.x, pivot.y, img.w, img.h));
+        }
+        let img_data = self.encode(&img);
+        self.elements.push(Element::TimeHand(TimeHand {
+            h_type: h_type as u8,
+            pivot_x: pivot.x,
+            pivot_y: pivot.y,
+            img_data,
+            x: centre.x,
+            y: centre.y,
+        }));
+        self
+    }
+
+    pub fn bar_display(mut self, b_type: u8, x: u16, y: u16, imgs: Vec<Img>) -> Self {
+        if imgs.is_empty() || imgs.len() > 255 {
+            self.errors.push(format!("bar_display needs 1 to 255 images, got {}", imgs.len()));
+            return self;
+        }
+        let img_data = self.encode_all(&imgs);
+        self.elements.push(Element::BarDisplay(BarDisplay {
+            b_type,
+            count: imgs.len() as u8,
+            x,
+            y,
+            img_data,
+        }));
+        self
+    }
+
+    pub fn weather(mut self, x: u16, y: u16, imgs: Vec<Img>) -> Self {
+        if imgs.is_empty() || imgs.len() > 255 {
+            self.errors.push(format!("weather needs 1 to 255 images, got {}", imgs.len()));
+            return self;
+        }
+        let img_data = self.encode_all(&imgs);
+        self.elements.push(Element::Weather(Weather {
+            count: imgs.len() as u8,
+            x,
+            y,
+            img_data,
+        }));
+        self
+    }
+
+    // fill is the x1, y1, x2, y2 rectangle inside the background image that the watch fills in
+    pub fn battery_fill(mut self, x: u16, y: u16, img: Img, fill: [u8; 4], img1: Img, img2: Img) -> Self {
+        let (img_data, image_data1, image_data2) = (self.encode(&img), self.encode(&img1), self.encode(&img2));
+        self.elements.push(Element::BatteryFill(BatteryFill {
+            x,
+            y,
+            img_data,
+            x1: fill[0],
+            y1: fill[1],
+            x2: fill[2],
+            y2: fill[3],
+            unknown0: 0,
+            unknown1: 0,
+            image_data1,
+            image_data2,
+        }));
+        self
+    }
+
+    pub fn dash(mut self, img: Img) -> Self {
+        let img_data = self.encode(&img);
+        self.elements.push(Element::Dash(Dash { img_data }));
+        self
+    }
+
+    pub fn build(self) -> Result<FaceN, String> {
+        let mut errors = self.errors;
+        let preview_img_data = match self.preview {
+            Some(p) => p,
+            None => { errors.push("no preview image".to_string()); ImgData::default() },
+        };
+        let face = FaceN {
+            type_str: "extrathunder watchface".to_string(),
+            rev: 0,
+            tpls: self.device.tpls,
+            api_ver: self.api_ver,
+            unknown: self.unknown,
+            preview_img_data,
+            digits: self.digits,
+            elements: self.elements,
+            original_layout: None,
+        };
+        errors.extend(face.validate());
+        if !errors.is_empty() {
+            return Err(format!("Unable to build face: {}", errors.join("; ")));
+        }
+        Ok(face)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+
+    // a pattern with some transparency, so the RLE encoder has runs and changes to deal with
+    fn pattern(w: u32, h: u32, seed: u8) -> Img {
+        let mut img = Img::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let a = if (x + y) % 5 == 0 { 0 } else { 255 };
+                img.put_pixel(x, y, [a, (x * 7) as u8 ^ seed, (y * 11) as u8, ((x ^ y) * 3) as u8]);
+            }
+        }
+        img
+    }
+
+    fn patterns(n: usize, w: u32, h: u32) -> Vec<Img> {
+        (0..n).map(|i| pattern(w, h, i as u8 * 17)).collect()
+    }
+
+    fn base() -> FaceBuilder {
+        FaceBuilder::new(&DEVICES[0]).preview(pattern(120, 148, 1)).background(pattern(240, 296, 2))
+    }
+
+    fn all_img_data(face: &FaceN) -> Vec<ImgData> {
+        let mut face = face.clone();
+        face.all_img_data_mut().into_iter().map(|(_, id)| id.clone()).collect()
+    }
+
+    // to_bin then from_bin must give back the same face, and the same bytes again
+    fn round_trip(face: FaceN) {
+        let bin = face.to_bin().unwrap();
+        let mut back = FaceN::from_bin(&bin).unwrap();
+        back.original_layout = None;
+        back.tpls = face.tpls;                  // not in the binary file
+        for (_, id) in back.all_img_data_mut() {
+            id.offset = None;
+        }
+        assert_eq!(serde_json::to_value(&face).unwrap(), serde_json::to_value(&back).unwrap());
+        assert!(all_img_data(&face) == all_img_data(&back), "images changed");
+        assert_eq!(back.to_bin().unwrap(), bin);
+    }
+
+    #[test]
+    fn image() {
+        round_trip(base().image(10, 20, pattern(30, 40, 3)).build().unwrap());
+    }
+
+    #[test]
+    fn time_num() {
+        let xys = [ XY { x: 20, y: 100 }, XY { x: 60, y: 100 }, XY { x: 130, y: 100 }, XY { x: 170, y: 100 } ];
+        round_trip(base().digit_set(patterns(10, 36, 50)).time_num(0, xys).build().unwrap());
+    }
+
+    #[test]
+    fn day_name() {
+        round_trip(base().day_name(1, 100, 30, patterns(7, 40, 16)).build().unwrap());
+    }
+
+    #[test]
+    fn battery_fill() {
+        round_trip(base().battery_fill(180, 10, pattern(40, 20, 4), [2, 3, 36, 17], pattern(34, 14, 5), pattern(34, 14, 6)).build().unwrap());
+    }
+
+    #[test]
+    fn heart_rate_num() {
+        round_trip(base().digit_set(patterns(10, 12, 18)).heart_rate_num(0, 1, 50, 200).build().unwrap());
+    }
+
+    #[test]
+    fn steps_num() {
+        round_trip(base().digit_set(patterns(10, 12, 18)).steps_num(0, 2, 50, 220).build().unwrap());
+    }
+
+    #[test]
+    fn kcal_num() {
+        round_trip(base().digit_set(patterns(10, 12, 18)).kcal_num(0, 0, 50, 240).build().unwrap());
+    }
+
+    #[test]
+    fn time_hand() {
+        let face = base()
+            .hand(HandType::Hour, pattern(10, 70, 7), XY { x: 5, y: 65 })
+            .hand_at(HandType::Minute, pattern(8, 100, 8), XY { x: 4, y: 95 }, XY { x: 100, y: 150 })
+            .build().unwrap();
+        round_trip(face);
+    }
+
+    #[test]
+    fn day_num() {
+        round_trip(base().digit_set(patterns(10, 12, 18)).day_num(0, 0, [ XY { x: 10, y: 10 }, XY { x: 22, y: 10 } ]).build().unwrap());
+    }
+
+    #[test]
+    fn month_num() {
+        round_trip(base().digit_set(patterns(10, 12, 18)).month_num(0, 0, [ XY { x: 40, y: 10 }, XY { x: 52, y: 10 } ]).build().unwrap());
+    }
+
+    #[test]
+    fn bar_display() {
+        round_trip(base().bar_display(1, 20, 260, patterns(5, 60, 8)).build().unwrap());
+    }
+
+    #[test]
+    fn weather() {
+        round_trip(base().weather(150, 30, patterns(3, 24, 24)).build().unwrap());
+    }
+
+    #[test]
+    fn dash() {
+        round_trip(base().dash(pattern(20, 4, 9)).build().unwrap());
+    }
+
+    #[test]
+    fn build_reports_problems() {
+        let err = base().api_ver(3).digit_set(patterns(9, 12, 18)).build().err().unwrap();
+        assert!(err.contains("Unsupported api_ver 3"), "{}", err);
+        assert!(err.contains("needs 10 images"), "{}", err);
+        assert!(FaceBuilder::new(&DEVICES[0]).api_ver(DEFAULT_API_VER).build().is_err(), "no preview");
+    }
+}
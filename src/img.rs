@@ -20,19 +20,21 @@ use crate::img_data::{*};
 //  RGB FORMAT CONVERSION
 //----------------------------------------------------------------------------
 
-fn rgb565_to_888(a: u8, b: u8) -> [u8; 3] {
+// returns [R, G, B]
+pub fn rgb565_to_888(a: u8, b: u8) -> [u8; 3] {
     let pixel = (b as u16) | ((a as u16) << 8);
-    let mut output = [0 as u8, 0 as u8, 0 as u8];
-    output[0] = ((pixel & 0x001F) << 3) as u8;
-    output[0] |= ((pixel & 0x001C) >> 3) as u8;
+    let mut output = [0_u8, 0_u8, 0_u8];
+    output[2] = ((pixel & 0x001F) << 3) as u8;
+    output[2] |= ((pixel & 0x001C) >> 3) as u8;
     output[1] = ((pixel & 0x07E0) >> 3) as u8;
     output[1] |= ((pixel & 0x0600) >> 9) as u8;
-    output[2] = ((pixel & 0xF800) >> 8) as u8;
-    output[2] |= ((pixel & 0xE000) >> 13) as u8;
+    output[0] = ((pixel & 0xF800) >> 8) as u8;
+    output[0] |= ((pixel & 0xE000) >> 13) as u8;
     output
 }
 
-fn rgb888_to_565(buf: &[u8]) -> [u8; 2] {
+// takes [R, G, B]
+pub fn rgb888_to_565(buf: &[u8]) -> [u8; 2] {
     let mut output: u16 = 0;
     output |= (buf[2] as u16 & 0xF8) >> 3;
     output |= (buf[1] as u16 & 0xFC) << 3;
@@ -47,7 +49,7 @@ fn rgb888_to_565(buf: &[u8]) -> [u8; 2] {
 // Different formats supported by Img
 #[derive(PartialEq, Copy, Clone)]
 pub enum ImgFormat {
- 	Argb8888 = 0,				// ARGB8888   4 bytes per pixel
+ 	Argb8888 = 0,				// ARGB8888   4 bytes per pixel, in the byte order A, R, G, B
  	Argb8565 = 1,				// ARGB8565   3 bytes per pixel
  	RleNew = 2,				    // Compressed ARGB8565
  }
@@ -73,7 +75,10 @@ impl Img {
         }
     }
 
-    pub fn from_bmp(bytes: &Vec<u8>) -> Result<Img, String> {  
+    pub fn from_bmp(bytes: &[u8]) -> Result<Img, String> {  
+        if bytes.len() < mem::size_of::<BMPHeaderClassic>() {
+            return Err("BMP file is too small.".to_string());
+        }
         let h = BMPHeaderClassic::from_bytes(bytes);
         
         let mut height = h.height;
@@ -108,7 +113,7 @@ impl Img {
         }
     
         let mut image_data_size = h.image_data_size as usize;
-        let mut row_size = image_data_size as usize / height as usize;
+        let mut row_size = image_data_size / height as usize;
         let bytes_per_pixel = h.bpp as usize / 8;
         if row_size < (h.width as usize * bytes_per_pixel) {
             image_data_size = bytes.len() - h.offset as usize;        // calculate it ourselves, the header could be wrong
@@ -144,21 +149,23 @@ impl Img {
             }
             for y in 0..img.h as usize {
                 let row = if top_down { y } else { img.h as usize - y - 1 };
-                let bmp_offset = h.offset as usize + row as usize * row_size as usize;
+                let bmp_offset = h.offset as usize + row * row_size;
                 for x in 0..img.w as usize {
                     let a = bytes[bmp_offset + 2 * x ];
                     let b = bytes[bmp_offset + 2 * x + 1];
-                    let dest_data = [0xFF, a, b];
+                    let dest_data = [0xFF, b, a];           // the BMP is little-endian, we store the high byte first
                     let dest_offset = (y * img.w as usize + x) * 3;
-                    img.data[dest_offset as usize..dest_offset as usize + 3].copy_from_slice(&dest_data);
+                    img.data[dest_offset..dest_offset + 3].copy_from_slice(&dest_data);
                 }
             }
         } else if h.bpp == 32 && h.dib_header_size > 40 {
+            if bytes.len() < mem::size_of::<BMPHeaderV4>() {
+                return Err("BMP file is too small.".to_string());
+            }
             let h4 = BMPHeaderV4::from_bytes(bytes);
-            if h.compression_type == 3 {
-                if h4.rgba_masks[0] != 0x00FF0000 || h4.rgba_masks[1] != 0x0000FF00 || h4.rgba_masks[2] != 0x000000FF || h4.rgba_masks[3] != 0xFF000000 {
-                    return Err("32bpp BMP bitfields not BGRA8888.".to_string());
-                }
+            if h.compression_type == 3
+                && (h4.rgba_masks[0] != 0x00FF0000 || h4.rgba_masks[1] != 0x0000FF00 || h4.rgba_masks[2] != 0x000000FF || h4.rgba_masks[3] != 0xFF000000) {
+                return Err("32bpp BMP bitfields not BGRA8888.".to_string());
             }
             for y in 0..img.h as usize {
                 let row = if top_down { y } else { img.h as usize - y - 1 };
@@ -178,24 +185,18 @@ impl Img {
             }
         } else if h.bpp == 24 {
             // This pathway is untested... 
-            if h.compression_type == 3 {
-                if h.bmi_colors[0] != 0xFF0000 || h.bmi_colors[1] != 0x00FF00 || h.bmi_colors[2] != 0x0000FF {
-                    return Err("24bpp BMP bitfields not RGB888.".to_string());
-                }
+            if h.compression_type == 3
+                && (h.bmi_colors[0] != 0xFF0000 || h.bmi_colors[1] != 0x00FF00 || h.bmi_colors[2] != 0x0000FF) {
+                return Err("24bpp BMP bitfields not RGB888.".to_string());
             }
             for y in 0..img.h as usize {
                 let row = if top_down { y } else { img.h as usize - y - 1 };
                 let bmp_offset = h.offset as usize + row * row_size;
                 for x in 0..img.w as usize {
-                    let mut pixel: u32 = 0xFF;
-                    pixel <<= 8;
-                    pixel |= bytes[bmp_offset + x as usize * 3] as u32;
-                    pixel <<= 8;
-                    pixel |= bytes[bmp_offset + x as usize * 3 + 1] as u32;
-                    pixel <<= 8;
-                    pixel |= bytes[bmp_offset + x as usize * 3 + 2] as u32;
+                    // the BMP file stores BGR, we want ARGB
+                    let pixel = &bytes[bmp_offset + x * 3..bmp_offset + (x + 1) * 3];
                     let dest_offset = (y * img.w as usize + x) * 4;
-                    img.data[dest_offset as usize..dest_offset as usize + 4].copy_from_slice(&pixel.to_le_bytes());
+                    img.data[dest_offset..dest_offset + 4].copy_from_slice(&[0xFF, pixel[2], pixel[1], pixel[0]]);
                 }
             }
         } else {
@@ -225,11 +226,11 @@ impl Img {
                 let output = &mut new_img.data[offset_d..offset_d+4];
                 // Read in 3 bytes, convert to 4 bytes
                 // Alpha byte is the same, RGB parts need converting from 565 to 888
-                output[3] = p[0];
+                output[0] = p[0];
                 let rgb = rgb565_to_888(p[1], p[2]);
-                output[0] = rgb[0];
-                output[1] = rgb[1];
-                output[2] = rgb[2];
+                output[1] = rgb[0];
+                output[2] = rgb[1];
+                output[3] = rgb[2];
             }
         }
         *self = new_img;
@@ -384,7 +385,7 @@ impl Img {
             let mut row_dest_data = Vec::<u8>::new();
             let mut offset = 0;
 
-            while offset <= (row_width - 9) {
+            while offset + 9 <= row_width {
                 let mut pixel_a: Vec<u8> = row_src_data[offset..offset+3].to_vec();
                 let mut pixel_b: Vec<u8> = row_src_data[offset+3..offset+6].to_vec();
                 let mut pixel_c: Vec<u8> = row_src_data[offset+6..offset+9].to_vec();
@@ -496,7 +497,7 @@ impl Img {
             rle_header: None,
         };
 
-        // decompress the data
+        // decompress the data. a damaged image stops at the end of the data or the image, the rest is left transparent.
         let mut bytes_out = 0;
         let mut bytes_in = 0;
         while bytes_in < self.data.len() {
@@ -505,23 +506,25 @@ impl Img {
             if (cmd & 0x80) != 0 {
                 // Repeat the pixel
                 let count = (cmd & 0x7F) as usize;
-                let data = [
-                    self.data[bytes_in],
-                    self.data[bytes_in + 1],
-                    self.data[bytes_in + 2],
-                ];
+                let data = match self.data.get(bytes_in..bytes_in + 3) {
+                    Some(d) => [ d[0], d[1], d[2] ],
+                    None => break,
+                };
                 bytes_in += 3;
                 for _ in 0..count {
-                    new_img.data[bytes_out] = data[0];
-                    new_img.data[bytes_out + 1] = data[1];
-                    new_img.data[bytes_out + 2] = data[2];
+                    match new_img.data.get_mut(bytes_out..bytes_out + 3) {
+                        Some(p) => p.copy_from_slice(&data),
+                        None => break,
+                    }
                     bytes_out += 3;
                 }
             } else {
                 // Normal pixel data
                 let count = cmd as usize * 3;
-                new_img.data[bytes_out..bytes_out + count]
-                    .copy_from_slice(&self.data[bytes_in..bytes_in + count]);
+                match (new_img.data.get_mut(bytes_out..bytes_out + count), self.data.get(bytes_in..bytes_in + count)) {
+                    (Some(to), Some(from)) => to.copy_from_slice(from),
+                    _ => break,
+                }
                 bytes_out += count;
                 bytes_in += count;
             }
@@ -571,11 +574,526 @@ impl Img {
         let mut d_offset = header_size;
         for y in 0..img.h as usize {
             let src_row_data = &img.data[(y * img.w as usize * 4)..((y + 1) * img.w as usize * 4)];
-            let src_row_len = img.w as usize * 4;
-            (0..src_row_len).for_each(|i| b[d_offset + i] = src_row_data[i]);
+            // we store ARGB, the BMP file wants BGRA
+            for x in 0..img.w as usize {
+                let p = &src_row_data[x * 4..x * 4 + 4];
+                b[d_offset + x * 4..d_offset + x * 4 + 4].copy_from_slice(&[p[3], p[2], p[1], p[0]]);
+            }
             d_offset += dest_row_size;
         }
         b
     }
+
+    pub fn to_png(&self) -> Result<Vec<u8>, String> {
+        let mut img = self.clone();
+        img.convert_format(ImgFormat::Argb8888);
+        // we store ARGB, the PNG file wants RGBA
+        let rgba: Vec<u8> = img.data.chunks_exact(4).flat_map(|p| [p[1], p[2], p[3], p[0]]).collect();
+        let mut b: Vec<u8> = Vec::new();
+        let mut encoder = png::Encoder::new(&mut b, img.w, img.h);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+        writer.write_image_data(&rgba).map_err(|e| e.to_string())?;
+        writer.finish().map_err(|e| e.to_string())?;
+        Ok(b)
+    }
+
+    // reads any PNG, the result is Argb8888
+    pub fn from_png(bytes: &[u8]) -> Result<Img, String> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).map_err(|e| e.to_string())?;
+        let mut img = Img::new(info.width, info.height);
+        let channels = info.color_type.samples();
+        for (i, p) in buf[..info.buffer_size()].chunks_exact(channels).enumerate() {
+            let argb = match info.color_type {
+                png::ColorType::Grayscale => [255, p[0], p[0], p[0]],
+                png::ColorType::GrayscaleAlpha => [p[1], p[0], p[0], p[0]],
+                png::ColorType::Rgb => [255, p[0], p[1], p[2]],
+                png::ColorType::Rgba => [p[3], p[0], p[1], p[2]],
+                png::ColorType::Indexed => return Err("PNG palette was not expanded".to_string()),
+            };
+            img.data[i * 4..i * 4 + 4].copy_from_slice(&argb);
+        }
+        Ok(img)
+    }
 }
     
+
+//----------------------------------------------------------------------------
+//  DRAWING - ALPHA COMPOSITING AND ROTATION (Argb8888 only)
+//----------------------------------------------------------------------------
+
+impl Img {
+    // a new, fully transparent, Argb8888 image
+    pub fn new(w: u32, h: u32) -> Self {
+        Self {
+            w,
+            h,
+            format: ImgFormat::Argb8888,
+            data: vec![0; w as usize * h as usize * 4],
+            rle_header: None,
+        }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let o = (y as usize * self.w as usize + x as usize) * 4;
+        [ self.data[o], self.data[o+1], self.data[o+2], self.data[o+3] ]
+    }
+
+    pub fn put_pixel(&mut self, x: u32, y: u32, p: [u8; 4]) {
+        let o = (y as usize * self.w as usize + x as usize) * 4;
+        self.data[o..o+4].copy_from_slice(&p);
+    }
+
+    // alpha composite an ARGB pixel over the pixel at x, y. coordinates outside the image are ignored.
+    pub fn blend_pixel(&mut self, x: i32, y: i32, src: [u8; 4]) {
+        if x < 0 || y < 0 || x >= self.w as i32 || y >= self.h as i32 || src[0] == 0 {
+            return;
+        }
+        let dst = self.get_pixel(x as u32, y as u32);
+        let sa = src[0] as u32;
+        let da = dst[0] as u32 * (255 - sa) / 255;      // how much of the destination shows through
+        let oa = sa + da;
+        let mut out = [oa as u8, 0, 0, 0];
+        for c in 1..4 {
+            out[c] = ((src[c] as u32 * sa + dst[c] as u32 * da + oa / 2) / oa) as u8;
+        }
+        self.put_pixel(x as u32, y as u32, out);
+    }
+
+    // alpha composite all of src over this image, with the top left of src at x, y
+    pub fn draw(&mut self, src: &Img, x: i32, y: i32) {
+        let mut src = src.clone();
+        src.convert_format(ImgFormat::Argb8888);
+        for sy in 0..src.h {
+            for sx in 0..src.w {
+                self.blend_pixel(x + sx as i32, y + sy as i32, src.get_pixel(sx, sy));
+            }
+        }
+    }
+
+    // sample the image at a fractional position, with pixel centres at whole numbers.
+    // the colours are weighted by their alpha, so transparent pixels don't bleed their colour into the edges.
+    pub fn sample_bilinear(&self, x: f32, y: f32) -> [u8; 4] {
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let mut acc = [0.0f32; 4];
+        for (dx, dy, wt) in [ (0, 0, (1.0 - fx) * (1.0 - fy)), (1, 0, fx * (1.0 - fy)), (0, 1, (1.0 - fx) * fy), (1, 1, fx * fy) ] {
+            let px = x0 as i32 + dx;
+            let py = y0 as i32 + dy;
+            if wt == 0.0 || px < 0 || py < 0 || px >= self.w as i32 || py >= self.h as i32 {
+                continue;           // outside the image is transparent
+            }
+            let p = self.get_pixel(px as u32, py as u32);
+            let a = p[0] as f32 * wt;
+            acc[0] += a;
+            acc[1] += p[1] as f32 * a;
+            acc[2] += p[2] as f32 * a;
+            acc[3] += p[3] as f32 * a;
+        }
+        if acc[0] < 0.5 {
+            return [0, 0, 0, 0];
+        }
+        [ acc[0].round() as u8,
+          (acc[1] / acc[0]).round().min(255.0) as u8,
+          (acc[2] / acc[0]).round().min(255.0) as u8,
+          (acc[3] / acc[0]).round().min(255.0) as u8 ]
+    }
+
+    // alpha composite src over this image, rotated clockwise by angle degrees around the point pivot in src.
+    // the pivot lands on the point centre in this image.
+    pub fn draw_rotated(&mut self, src: &Img, pivot: (f32, f32), centre: (f32, f32), angle: f32) {
+        let mut src = src.clone();
+        src.convert_format(ImgFormat::Argb8888);
+        let (sin, cos) = angle.to_radians().sin_cos();
+
+        // only visit the destination pixels that src could possibly reach
+        let corners = [ (0.0, 0.0), (src.w as f32, 0.0), (0.0, src.h as f32), (src.w as f32, src.h as f32) ];
+        let radius = corners.iter().map(|(x, y)| ((x - pivot.0).powi(2) + (y - pivot.1).powi(2)).sqrt()).fold(0.0, f32::max) + 1.0;
+        let x_start = (centre.0 - radius).floor().max(0.0) as i32;
+        let y_start = (centre.1 - radius).floor().max(0.0) as i32;
+        let x_end = (centre.0 + radius).ceil().min(self.w as f32) as i32;
+        let y_end = (centre.1 + radius).ceil().min(self.h as f32) as i32;
+
+        for y in y_start..y_end {
+            for x in x_start..x_end {
+                // map the destination pixel back into src, by rotating it the other way
+                let vx = x as f32 - centre.0;
+                let vy = y as f32 - centre.1;
+                let sx = pivot.0 + vx * cos + vy * sin;
+                let sy = pivot.1 - vx * sin + vy * cos;
+                let p = src.sample_bilinear(sx, sy);
+                self.blend_pixel(x, y, p);
+            }
+        }
+    }
+}
+
+//----------------------------------------------------------------------------
+//  DEVICE SIMULATION - WHAT THE WATCH WILL ACTUALLY SHOW
+//----------------------------------------------------------------------------
+
+pub struct ColourError {
+    pub max: u8,                // largest difference in any channel, of any pixel
+    pub mean: f32,              // average of each pixel's largest channel difference
+    pub changed: usize,         // number of pixels that are not identical
+}
+
+impl Img {
+    // put the image through the same conversions the watch face goes through: ARGB8565, RLE compression, and back
+    pub fn simulate_device(&self) -> Img {
+        let mut img = self.clone();
+        img.convert_format(ImgFormat::RleNew);
+        img.convert_format(ImgFormat::Argb8888);
+        img
+    }
+
+    // compare two images of the same size. returns a heatmap of the per-pixel error, and a summary of it.
+    // the heatmap goes black (no error), blue, green, yellow, red (an error of 16 or more in a channel).
+    pub fn error_heatmap(&self, other: &Img) -> (Img, ColourError) {
+        assert!(self.w == other.w && self.h == other.h, "Can't compare images of different sizes.");
+        let mut a = self.clone();
+        a.convert_format(ImgFormat::Argb8888);
+        let mut b = other.clone();
+        b.convert_format(ImgFormat::Argb8888);
+        let ramp: [[u8; 3]; 5] = [ [0, 0, 0], [0, 0, 255], [0, 255, 0], [255, 255, 0], [255, 0, 0] ];
+        let mut heatmap = Img::new(self.w, self.h);
+        let mut stats = ColourError { max: 0, mean: 0.0, changed: 0 };
+        let mut total: u64 = 0;
+        for y in 0..self.h {
+            for x in 0..self.w {
+                let pa = a.get_pixel(x, y);
+                let pb = b.get_pixel(x, y);
+                let err = (0..4).map(|c| pa[c].abs_diff(pb[c])).max().unwrap();
+                if err > 0 {
+                    stats.changed += 1;
+                }
+                stats.max = stats.max.max(err);
+                total += err as u64;
+                // position along the ramp, 0.0 to 4.0
+                let t = (err as f32 / 4.0).min(4.0);
+                let i = (t.floor() as usize).min(3);
+                let f = t - i as f32;
+                let mut p = [255, 0, 0, 0];
+                for c in 0..3 {
+                    p[c + 1] = (ramp[i][c] as f32 * (1.0 - f) + ramp[i + 1][c] as f32 * f).round() as u8;
+                }
+                heatmap.put_pixel(x, y, p);
+            }
+        }
+        stats.mean = total as f32 / (self.w as f32 * self.h as f32).max(1.0);
+        (heatmap, stats)
+    }
+}
+
+//----------------------------------------------------------------------------
+//  CROPPING (Argb8888 only)
+//----------------------------------------------------------------------------
+
+impl Img {
+    // the smallest box holding every pixel that isn't fully transparent, as x0, y0, x1, y1 (x1 and y1 exclusive).
+    // None if the whole image is transparent.
+    pub fn opaque_bounds(&self) -> Option<(u32, u32, u32, u32)> {
+        let mut b: Option<(u32, u32, u32, u32)> = None;
+        for y in 0..self.h {
+            for x in 0..self.w {
+                if self.get_pixel(x, y)[0] == 0 {
+                    continue;
+                }
+                b = Some(match b {
+                    Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x + 1), y1.max(y + 1)),
+                    None => (x, y, x + 1, y + 1),
+                });
+            }
+        }
+        b
+    }
+
+    // a copy of part of the image
+    pub fn crop(&self, x: u32, y: u32, w: u32, h: u32) -> Img {
+        let mut dest = Img::new(w, h);
+        for dy in 0..h.min(self.h.saturating_sub(y)) {
+            for dx in 0..w.min(self.w.saturating_sub(x)) {
+                dest.put_pixel(dx, dy, self.get_pixel(x + dx, y + dy));
+            }
+        }
+        dest
+    }
+}
+
+//----------------------------------------------------------------------------
+//  RESIZING
+//----------------------------------------------------------------------------
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ResizeFilter {
+    Nearest,
+    Bilinear,
+    Bicubic,
+    Lanczos,
+}
+
+impl ResizeFilter {
+    pub fn from_name(name: &str) -> Result<ResizeFilter, String> {
+        match name.to_lowercase().as_str() {
+            "nearest" => Ok(ResizeFilter::Nearest),
+            "bilinear" => Ok(ResizeFilter::Bilinear),
+            "bicubic" => Ok(ResizeFilter::Bicubic),
+            "lanczos" => Ok(ResizeFilter::Lanczos),
+            _ => Err(format!("Unknown resize filter '{}', expected nearest, bilinear, bicubic or lanczos", name)),
+        }
+    }
+
+    // how far the kernel reaches either side, in source pixels when not shrinking
+    fn radius(&self) -> f32 {
+        match self {
+            ResizeFilter::Nearest => 0.5,
+            ResizeFilter::Bilinear => 1.0,
+            ResizeFilter::Bicubic => 2.0,
+            ResizeFilter::Lanczos => 3.0,
+        }
+    }
+
+    // the weight of a source pixel at distance x. resize() samples for nearest and bilinear instead,
+    // which gives the same result when growing.
+    fn kernel(&self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            // a box. halfway between two pixels both count, and are averaged.
+            ResizeFilter::Nearest => if x <= 0.5 { 1.0 } else { 0.0 },
+            // a triangle
+            ResizeFilter::Bilinear => (1.0 - x).max(0.0),
+            // catmull-rom, which keeps edges sharp without much ringing
+            ResizeFilter::Bicubic => {
+                if x < 1.0 {
+                    1.5 * x * x * x - 2.5 * x * x + 1.0
+                } else if x < 2.0 {
+                    -0.5 * x * x * x + 2.5 * x * x - 4.0 * x + 2.0
+                } else {
+                    0.0
+                }
+            },
+            ResizeFilter::Lanczos => {
+                if x < 1e-6 {
+                    1.0
+                } else if x < 3.0 {
+                    let px = std::f32::consts::PI * x;
+                    3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+                } else {
+                    0.0
+                }
+            },
+        }
+    }
+
+    // for each destination pixel, the first source pixel and the weights from there on.
+    // when shrinking the kernel is stretched, so every source pixel counts.
+    fn weights(&self, src_len: u32, dest_len: u32) -> Vec<(usize, Vec<f32>)> {
+        let scale = src_len as f32 / dest_len as f32;
+        let stretch = scale.max(1.0);
+        let support = self.radius() * stretch;
+        (0..dest_len).map(|i| {
+            let centre = (i as f32 + 0.5) * scale - 0.5;
+            let first = (centre - support).ceil().max(0.0) as usize;
+            let last = ((centre + support).floor() as usize).min(src_len as usize - 1);
+            let mut w: Vec<f32> = (first..=last).map(|j| self.kernel((j as f32 - centre) / stretch)).collect();
+            let total: f32 = w.iter().sum();
+            if total.abs() > 1e-6 {
+                w.iter_mut().for_each(|v| *v /= total);
+            }
+            (first, w)
+        }).collect()
+    }
+}
+
+impl Img {
+    // returns a resized Argb8888 copy of the image
+    pub fn resize(&self, w: u32, h: u32, filter: ResizeFilter) -> Img {
+        let mut src = self.clone();
+        src.convert_format(ImgFormat::Argb8888);
+        let mut dest = Img::new(w, h);
+        if src.w == 0 || src.h == 0 {
+            return dest;
+        }
+        if w == 0 || h == 0 {
+            return dest;
+        }
+        if filter == ResizeFilter::Bicubic || filter == ResizeFilter::Lanczos {
+            return src.resize_convolve(w, h, filter);
+        }
+        let scale_x = src.w as f32 / w as f32;
+        let scale_y = src.h as f32 / h as f32;
+        for y in 0..h {
+            for x in 0..w {
+                // the centre of the destination pixel, in source pixel coordinates
+                let sx = ((x as f32 + 0.5) * scale_x - 0.5).clamp(0.0, (src.w - 1) as f32);
+                let sy = ((y as f32 + 0.5) * scale_y - 0.5).clamp(0.0, (src.h - 1) as f32);
+                let p = match filter {
+                    ResizeFilter::Bilinear => src.sample_bilinear(sx, sy),
+                    _ => src.get_pixel(sx.round() as u32, sy.round() as u32),
+                };
+                dest.put_pixel(x, y, p);
+            }
+        }
+        dest
+    }
+
+    // resize an Argb8888 image with a kernel filter, first across then down.
+    // the colours are premultiplied by alpha, so transparent pixels don't bleed their colour into the edges.
+    fn resize_convolve(&self, w: u32, h: u32, filter: ResizeFilter) -> Img {
+        let mut pixels: Vec<[f32; 4]> = Vec::with_capacity((self.w * self.h) as usize);
+        for y in 0..self.h {
+            for x in 0..self.w {
+                let p = self.get_pixel(x, y);
+                let a = p[0] as f32 / 255.0;
+                pixels.push([ p[0] as f32, p[1] as f32 * a, p[2] as f32 * a, p[3] as f32 * a ]);
+            }
+        }
+
+        let across = filter.weights(self.w, w);
+        let mut wide: Vec<[f32; 4]> = vec![[0.0; 4]; (w * self.h) as usize];
+        for y in 0..self.h as usize {
+            for (x, (first, weights)) in across.iter().enumerate() {
+                let mut acc = [0.0f32; 4];
+                for (i, wt) in weights.iter().enumerate() {
+                    let p = &pixels[y * self.w as usize + first + i];
+                    for c in 0..4 {
+                        acc[c] += p[c] * wt;
+                    }
+                }
+                wide[y * w as usize + x] = acc;
+            }
+        }
+
+        let down = filter.weights(self.h, h);
+        let mut dest = Img::new(w, h);
+        for (y, (first, weights)) in down.iter().enumerate() {
+            for x in 0..w as usize {
+                let mut acc = [0.0f32; 4];
+                for (i, wt) in weights.iter().enumerate() {
+                    let p = &wide[(first + i) * w as usize + x];
+                    for c in 0..4 {
+                        acc[c] += p[c] * wt;
+                    }
+                }
+                // the negative lobes can overshoot, so clamp before undoing the premultiply
+                let a = acc[0].round().clamp(0.0, 255.0);
+                if a < 1.0 {
+                    continue;       // Img::new is transparent
+                }
+                let unmul = |v: f32| (v * 255.0 / a).round().clamp(0.0, 255.0) as u8;
+                dest.put_pixel(x as u32, y as u32, [ a as u8, unmul(acc[1]), unmul(acc[2]), unmul(acc[3]) ]);
+            }
+        }
+        dest
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a one pixel high image with the values in red, fully opaque
+    fn row(values: &[u8]) -> Img {
+        let mut img = Img::new(values.len() as u32, 1);
+        for (x, v) in values.iter().enumerate() {
+            img.put_pixel(x as u32, 0, [ 255, *v, 0, 0 ]);
+        }
+        img
+    }
+
+    fn red(img: &Img) -> Vec<u8> {
+        (0..img.w).map(|x| img.get_pixel(x, 0)[1]).collect()
+    }
+
+    // the expected values were worked out separately from the kernel formulas. doubling puts the destination
+    // pixels 0.25 and 0.75 source pixels from their neighbours, which catmull-rom weights -0.0234375, 0.2265625,
+    // 0.8671875 and -0.0703125. at the edges the weights that fall outside the image are dropped and the rest scaled up.
+    #[test]
+    fn bicubic_doubles_a_step_and_an_impulse() {
+        assert_eq!(red(&row(&[ 0, 0, 255, 255 ]).resize(8, 1, ResizeFilter::Bicubic)), [ 0, 0, 0, 52, 203, 255, 255, 255 ]);
+        assert_eq!(red(&row(&[ 0, 0, 255, 0, 0 ]).resize(10, 1, ResizeFilter::Bicubic)), [ 0, 0, 0, 58, 221, 221, 58, 0, 0, 0 ]);
+    }
+
+    #[test]
+    fn lanczos_doubles_a_step_and_an_impulse() {
+        assert_eq!(red(&row(&[ 0, 0, 255, 255 ]).resize(8, 1, ResizeFilter::Lanczos)), [ 10, 0, 0, 54, 201, 255, 255, 245 ]);
+        assert_eq!(red(&row(&[ 0, 0, 255, 0, 0 ]).resize(10, 1, ResizeFilter::Lanczos)), [ 10, 0, 0, 71, 229, 229, 71, 0, 0, 10 ]);
+    }
+
+    // nearest takes the closest pixel, rounding halfway up. bilinear mixes the two closest, by how close each is.
+    #[test]
+    fn nearest_and_bilinear_worked_values() {
+        assert_eq!(red(&row(&[ 0, 200 ]).resize(4, 1, ResizeFilter::Nearest)), [ 0, 0, 200, 200 ]);
+        assert_eq!(red(&row(&[ 0, 200 ]).resize(4, 1, ResizeFilter::Bilinear)), [ 0, 50, 150, 200 ]);
+        assert_eq!(red(&row(&[ 0, 100, 200, 250 ]).resize(2, 1, ResizeFilter::Nearest)), [ 100, 250 ]);
+        assert_eq!(red(&row(&[ 0, 100, 200, 250 ]).resize(2, 1, ResizeFilter::Bilinear)), [ 50, 225 ]);
+    }
+
+    // their kernels give the same weights as the sampling, and never panic
+    #[test]
+    fn nearest_and_bilinear_kernels() {
+        assert_eq!([ 0.0, 0.4, 0.5, 0.6, 2.0 ].map(|x| ResizeFilter::Nearest.kernel(x)), [ 1.0, 1.0, 1.0, 0.0, 0.0 ]);
+        assert_eq!([ 0.0, -0.25, 0.75, 1.0, 2.0 ].map(|x| ResizeFilter::Bilinear.kernel(x)), [ 1.0, 0.75, 0.25, 0.0, 0.0 ]);
+        assert_eq!(ResizeFilter::Bilinear.weights(2, 4), vec![ (0, vec![ 1.0 ]), (0, vec![ 0.75, 0.25 ]), (0, vec![ 0.25, 0.75 ]), (1, vec![ 1.0 ]) ]);
+        assert_eq!(ResizeFilter::Nearest.weights(2, 4), vec![ (0, vec![ 1.0 ]), (0, vec![ 1.0 ]), (1, vec![ 1.0 ]), (1, vec![ 1.0 ]) ]);
+    }
+
+    // the weights always add up to 1, so a solid colour comes out exactly the same, growing or shrinking
+    #[test]
+    fn solid_colour_stays_exact() {
+        for argb in [ [ 255, 200, 100, 50 ], [ 128, 17, 240, 3 ] ] {
+            let mut img = Img::new(9, 7);
+            for y in 0..7 {
+                for x in 0..9 {
+                    img.put_pixel(x, y, argb);
+                }
+            }
+            for filter in [ ResizeFilter::Bicubic, ResizeFilter::Lanczos ] {
+                for (w, h) in [ (20, 15), (4, 3), (9, 7) ] {
+                    let out = img.resize(w, h, filter);
+                    assert!(out.data.chunks_exact(4).all(|p| p == argb), "{:?} {}x{} {:?}", filter, w, h, argb);
+                }
+            }
+        }
+    }
+
+    // colours the watch can show exactly come back the same. others lose the low bits of red and green.
+    #[test]
+    fn simulate_device_colours() {
+        let mut img = row(&[ 255, 0, 100 ]);
+        img.put_pixel(1, 0, [ 255, 0, 255, 0 ]);
+        img.put_pixel(2, 0, [ 255, 100, 100, 0 ]);
+        let device = img.simulate_device();
+        assert!(device.format == ImgFormat::Argb8888);
+        assert_eq!((device.w, device.h), (3, 1));
+        assert_eq!(device.get_pixel(0, 0), [ 255, 255, 0, 0 ]);
+        assert_eq!(device.get_pixel(1, 0), [ 255, 0, 255, 0 ]);
+        assert_eq!(device.get_pixel(2, 0), [ 255, 99, 101, 0 ]);
+        let (_, err) = img.error_heatmap(&device);
+        assert_eq!((err.max, err.changed), (1, 1));
+    }
+
+    // errors of 0, 2, 8 and 40 are black, half way to blue, green and red
+    #[test]
+    fn heatmap_ramp() {
+        let (heatmap, err) = row(&[ 10, 10, 10, 10 ]).error_heatmap(&row(&[ 10, 12, 2, 50 ]));
+        assert_eq!((0..4).map(|x| heatmap.get_pixel(x, 0)).collect::<Vec<_>>(),
+            [ [ 255, 0, 0, 0 ], [ 255, 0, 0, 128 ], [ 255, 0, 255, 0 ], [ 255, 255, 0, 0 ] ]);
+        assert_eq!((err.max, err.changed, err.mean), (40, 3, 12.5));
+
+        // alpha counts as a channel too
+        let mut clear = row(&[ 10 ]);
+        clear.put_pixel(0, 0, [ 251, 10, 0, 0 ]);
+        let (heatmap, err) = row(&[ 10 ]).error_heatmap(&clear);
+        assert_eq!((heatmap.get_pixel(0, 0), err.max), ([ 255, 0, 0, 255 ], 4));
+    }
+
+}
@@ -15,19 +15,112 @@
 use serde::{Serialize, Deserialize};
 use crate::util::{*};
 use crate::sane_file_name::{*};
-use std::path::PathBuf;
 use std::fs;
 use std::fmt;
 use crate::img::{*};
+use crate::raw::{*};
 
 
 // IMAGE DUMP FORMAT
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[allow(clippy::upper_case_acronyms)]         // named like the file extensions
 pub enum DumpFormat {
-        BIN = 0,
-        RAW = 1,
-        BMP = 2,
+        BIN,
+        RAW(RawLayout),
+        BMP,
+        PPM,
+        PAM,
+        TGA { rle: bool },
+}
+
+impl DumpFormat {
+    pub fn from_name(name: &str) -> Result<DumpFormat, String> {
+        match name.to_lowercase().as_str() {
+            "bin" => Ok(DumpFormat::BIN),
+            "raw" => Ok(DumpFormat::RAW(RawLayout::Argb8565)),
+            "bmp" => Ok(DumpFormat::BMP),
+            "ppm" => Ok(DumpFormat::PPM),
+            "pam" => Ok(DumpFormat::PAM),
+            "tga" => Ok(DumpFormat::TGA { rle: false }),
+            _ => Err(format!("Unknown image format '{}', expected bmp, raw, bin, ppm, pam or tga", name)),
+        }
+    }
+
+    // the format of an image file, from its extension
+    pub fn from_file_name(file_name: &str) -> Option<DumpFormat> {
+        let (_, extn) = file_name.rsplit_once('.')?;
+        Self::from_name(extn).ok()
+    }
+}
+
+// IMAGES EMBEDDED IN THE JSON
+
+// Instead of a file, an image can be kept in watchface.json as base64, so the JSON alone is the whole face:
+//  "embedded": { "format": "png", "data": "iVBORw0KGgo..." }
+// png and bmp are image files, rle is the compressed image exactly as it is in the binary file, header first.
+
+#[derive(Serialize, Deserialize, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbedFormat {
+    Png,
+    Bmp,
+    Rle,
+}
+
+impl EmbedFormat {
+    pub fn from_name(name: &str) -> Result<EmbedFormat, String> {
+        match name.to_lowercase().as_str() {
+            "png" => Ok(EmbedFormat::Png),
+            "bmp" => Ok(EmbedFormat::Bmp),
+            "rle" => Ok(EmbedFormat::Rle),
+            _ => Err(format!("Unknown embedded image format '{}', expected png, bmp or rle", name)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Embedded {
+    pub format: EmbedFormat,
+    pub data: String,               // base64
+}
+
+// BLOB WRITER
+
+// Collects the image blobs while the binary headers are written.
+// Normally each blob goes straight after the previous one. With keep_offsets, images that know their
+// original offset are put back there instead, and FaceN::to_bin places them.
+
+pub struct BlobWriter {
+    pub data: Vec<u8>,                  // the blobs that were added in order, padded
+    pub offset: u32,                    // the file offset of the next blob added in order
+    pub keep_offsets: bool,
+    pub placed: Vec<(u32, Vec<u8>)>,    // file offset and unpadded blob, for the images put back in place
+    pub unplaced: usize,                // images that had to be added in order, when keeping offsets
+}
+
+impl BlobWriter {
+    pub fn new(offset: u32, keep_offsets: bool) -> Self {
+        Self { data: Vec::new(), offset, keep_offsets, placed: Vec::new(), unplaced: 0 }
+    }
+
+    // store the image, and return the file offset it is stored at
+    pub fn push(&mut self, id: &ImgData) -> u32 {
+        if self.keep_offsets {
+            if let Some(o) = id.offset {
+                let mut bd = id.header.clone();
+                bd.extend(id.data.iter());
+                self.placed.push((o, bd));
+                return o;
+            }
+            self.unplaced += 1;
+        }
+        let o = self.offset;
+        let bd = id.to_bin();
+        self.offset += bd.len() as u32;
+        self.data.extend(bd);
+        o
+    }
 }
 
 // IMAGEDATA STARTS HERE
@@ -42,45 +135,81 @@ pub struct ImgData {
     pub w: u16,
     pub h: u16,
     pub file_name: Option<String>,
+    // where the image was in the original binary file, so it can be put back in the same place
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u32>,
+    // the image itself, instead of a file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedded: Option<Box<Embedded>>,
 }
 
 impl ImgData {
     // get the size of the rle compressed image data
-    fn get_data_size(file_data: &[u8], base_offset: usize, height: usize) -> usize {
-        let header_size = (height as usize) * 4;
+    fn get_data_size(file_data: &[u8], base_offset: usize, height: usize) -> Result<usize, String> {
+        let header_size = height * 4;
+        if height == 0 {
+            return Err(format!("Image at offset {} has a height of 0", base_offset));
+        }
         let last_header_entry = base_offset + header_size - 4;
-        let mut last_offset = get_u16(&file_data, last_header_entry) as usize;       // this offset is from start of image data
-        let mut last_size   = get_u16(&file_data, last_header_entry + 2) as usize;
+        if last_header_entry + 4 > file_data.len() {
+            return Err(format!("Image at offset {} runs past the end of the file", base_offset));
+        }
+        let mut last_offset = get_u16(file_data, last_header_entry) as usize;       // this offset is from start of image data
+        let mut last_size   = get_u16(file_data, last_header_entry + 2) as usize;
         last_offset        += (last_size & 0x1F) << 16;        // The lowest 5 bits are the hi part of the offset.
-        last_size           = last_size / 32;
+        last_size          /= 32;
         let image_size      = last_offset + last_size;
         // we will return the size of the image blob, excluding the headers (which aren't important to us)
-        return image_size - header_size;
+        image_size.checked_sub(header_size).ok_or(format!("Image at offset {} has a bad row table", base_offset))
     }
 
     // read in binary data from a file. pass in the offset of the OWH structure. the function will load the image data from the file data.
-    pub fn from_owh(file_data: &[u8], owh_offset: usize) -> ImgData {
+    pub fn from_owh(file_data: &[u8], owh_offset: usize) -> Result<ImgData, String> {
+        if owh_offset + 8 > file_data.len() {
+            return Err(format!("Image header at offset {} runs past the end of the file", owh_offset));
+        }
         // read in an owh structure
-        let bin_offset =  get_u32(&file_data, owh_offset) as usize;      // offset is from start of file
-        let width =       get_u16(&file_data, owh_offset+4) as usize;
-        let height =      get_u16(&file_data, owh_offset+6) as usize;
+        let bin_offset =  get_u32(file_data, owh_offset) as usize;      // offset is from start of file
+        let width =       get_u16(file_data, owh_offset+4) as usize;
+        let height =      get_u16(file_data, owh_offset+6) as usize;
         
         ImgData::from_bin(file_data, bin_offset, width, height)
     }
 
     // load binary image data.
-    pub fn from_bin(bin_data: &[u8], bin_offset: usize, width: usize, height: usize) -> ImgData {        
-        let header_size = height as usize * 4;
-        let blob_size = ImgData::get_data_size(&bin_data, bin_offset, height);    // determine the size of the compressed image data blob
+    pub fn from_bin(bin_data: &[u8], bin_offset: usize, width: usize, height: usize) -> Result<ImgData, String> {
+        let header_size = height * 4;
+        let blob_size = ImgData::get_data_size(bin_data, bin_offset, height)?;    // determine the size of the compressed image data blob
+        let end = bin_offset + header_size + blob_size;
+        if end > bin_data.len() {
+            return Err(format!("Image at offset {} runs past the end of the file", bin_offset));
+        }
         let header: Vec<u8> = bin_data[bin_offset..(bin_offset+header_size)].into();
-        let data: Vec<u8> = bin_data[(bin_offset + header_size)..(bin_offset + header_size + blob_size)].into();
-        ImgData {
+        let data: Vec<u8> = bin_data[(bin_offset + header_size)..end].into();
+        Ok(ImgData {
             header,
             data,
             w: width as u16,
             h: height as u16,
             file_name: None,
-        }
+            offset: Some(bin_offset as u32),
+            embedded: None,
+        })
+    }
+
+    // compress a decoded image into a new ImgData, with no file name
+    pub fn from_img(img: &Img) -> Result<ImgData, String> {
+        let mut img = img.clone();
+        img.convert_format(ImgFormat::RleNew);
+        Ok(ImgData {
+            header: img.rle_header.ok_or("Unable to RLE encode the image")?,
+            data: img.data,
+            w: img.w as u16,
+            h: img.h as u16,
+            file_name: None,
+            offset: None,
+            embedded: None,
+        })
     }
 
     pub fn to_bin(&self) -> Vec<u8> {
@@ -93,50 +222,55 @@ impl ImgData {
         bin_data
     }
 
-    pub fn read_img(&mut self, folder_name: &str) {
-        // check we have a filename
-        if self.file_name.is_none() || !sane_file_name(&self.file_name.as_ref().unwrap()) {
-            println!("WARNING: Not reading image file, as file_name is non-existant or non-sensible.");
-            return;
+    pub fn read_img(&mut self, folder_name: &str) -> Result<(), String> {
+        if self.embedded.is_some() {
+            return self.read_embedded();
         }
-        let file_name = self.file_name.as_ref().unwrap();
+        // check we have a filename
+        let file_name = match self.file_name.as_deref().map(normalise_file_name) {
+            Some(Ok(f)) => f,
+            Some(Err(e)) => return Err(format!("Not reading image file: {}", e)),
+            None => return Err("An image has no file_name, and isn't embedded".to_string()),
+        };
 
         // check file format
-        let mut format = DumpFormat::BMP;                
-        if file_name.len() > 4 {
-            let extn = file_name[file_name.len()-4..file_name.len()].to_lowercase();
-            format = match extn.as_str() {
-                ".bin" => DumpFormat::BIN,
-                ".raw" => DumpFormat::RAW,
-                ".bmp" => DumpFormat::BMP,
-                _ => { println!("WARNING: Unrecognised file extension '{}', assuming BMP.", extn); DumpFormat::BMP }
-            };
-        } else {
-            println!("WARNING: File name '{}' is very short, it should be a BMP file.", file_name);
-        }
+        let format = match DumpFormat::from_file_name(&file_name) {
+            Some(f) => f,
+            None => { eprintln!("WARNING: Unrecognised file extension on '{}', assuming BMP.", file_name); DumpFormat::BMP },
+        };
         
         // read in the file
-        let path: PathBuf = [folder_name, &file_name].iter().collect();
-        let file_data: Vec<u8> = match fs::read(path) {
+        let path = file_path(folder_name, &file_name)?;
+        let file_data: Vec<u8> = match fs::read(&path) {
             Ok(fd) => fd,
-            Err(e) => panic!("Unable to read file '{}': {}", &file_name, e),
+            Err(e) => return Err(format!("Unable to read file '{}': {}", &file_name, e)),
         };
 
-        if format == DumpFormat::BMP || format == DumpFormat::RAW {
+        if let DumpFormat::BMP | DumpFormat::RAW(_) | DumpFormat::PPM | DumpFormat::PAM | DumpFormat::TGA { .. } = format {
             // read it into an Img
             let mut img: Img = match format {
                 DumpFormat::BMP => match Img::from_bmp(&file_data) {
                                     Ok(i) => i,
-                                    Err(e) => panic!("Unable to understand BMP file '{}': {}", &file_name, e),
+                                    Err(e) => return Err(format!("Unable to understand BMP file '{}': {}", &file_name, e)),
                 },
-                DumpFormat::RAW => Img {
-                                    w: self.w as u32,
-                                    h: self.h as u32,
-                                    format: ImgFormat::Argb8565,
-                                    data: file_data,
-                                    rle_header: None,
+                DumpFormat::PPM | DumpFormat::PAM => Img::from_netpbm(&file_data).map_err(|e| format!("Unable to understand Netpbm file '{}': {}", &file_name, e))?,
+                DumpFormat::TGA { .. } => Img::from_tga(&file_data).map_err(|e| format!("Unable to understand TGA file '{}': {}", &file_name, e))?,
+                DumpFormat::RAW(_) => {
+                    // the sidecar says what the file is. without one it is an old dump, the size of the json.
+                    let info = match read_sidecar(&path)? {
+                        Some(info) => info,         // a different size to the json is reported by the caller
+                        None => match detect(file_data.len(), self.w, self.h) {
+                            Some(RawLayout::Argb8565) => RawInfo { w: self.w, h: self.h, format: RawLayout::Argb8565 },
+                            Some(format) => {
+                                eprintln!("WARNING: RAW file '{}' has no '{}.json', guessing it is {:?} from its size.", &file_name, &file_name, format);
+                                RawInfo { w: self.w, h: self.h, format }
+                            },
+                            None => return Err(format!("RAW file '{}' is the wrong size for {}x{}", &file_name, self.w, self.h)),
+                        },
+                    };
+                    info.format.decode(&file_data, info.w, info.h).map_err(|e| format!("RAW file '{}': {}", &file_name, e))?
                 },
-                _ => panic!("unexpected ImgFormat"),
+                _ => return Err("unexpected ImgFormat".to_string()),
             };
 
             // convert it to bin format
@@ -146,40 +280,95 @@ impl ImgData {
             self.w = img.w as u16;
             self.h = img.h as u16;
             self.data = img.data;
-            self.header = img.rle_header.unwrap();
+            self.header = img.rle_header.ok_or("Unable to RLE encode the image")?;
         } else if format == DumpFormat::BIN {
             // read it in
-            *self = ImgData::from_bin(&file_data, 0, self.w as usize, self.h as usize);
+            let offset = self.offset;
+            *self = ImgData::from_bin(&file_data, 0, self.w as usize, self.h as usize).map_err(|e| format!("BIN file '{}': {}", &file_name, e))?;
+            self.file_name = Some(file_name);
+            self.offset = offset;
         } else {
-            panic!("Unknown ImgFormat!");
+            return Err("Unknown ImgFormat!".to_string());
         }
+        Ok(())
     }
 
-    pub fn write_img(&self, folder_name: &str, format: &DumpFormat) {        
+    pub fn write_img(&self, folder_name: &str, format: &DumpFormat) -> Result<(), String> {
         if self.file_name.is_none() {
-            println!("ERROR: No file name for ImgData::write_img()!");
-            return;
+            return Err("No file name for ImgData::write_img()!".to_string());
         }        
         let file_name = self.file_name.as_ref().unwrap();
-        let path: PathBuf = [folder_name, &file_name].iter().collect();
-        let mut img = Img::from_img_data(&self);
+        let path = file_path(folder_name, file_name)?;
+        // images can be in subfolders
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Unable to create folder '{}': {}", parent.display(), e))?;
+        }
+        let img = Img::from_img_data(self);
         let b: Vec<u8> = match *format {
             DumpFormat::BMP => img.to_bmp(),
-            DumpFormat::RAW => {
-                img.convert_format(ImgFormat::Argb8565);        // decompress the image data
-                img.data
+            DumpFormat::PPM => img.to_ppm(),
+            DumpFormat::PAM => img.to_pam(),
+            DumpFormat::TGA { rle } => img.to_tga(rle),
+            DumpFormat::RAW(layout) => {
+                write_sidecar(&path, &RawInfo { w: self.w, h: self.h, format: layout })?;
+                layout.encode(&img)
             },
             DumpFormat::BIN => {
-                let mut tmp = img.rle_header.unwrap();
+                let mut tmp = img.rle_header.ok_or("Unable to RLE encode the image")?;
                 tmp.extend(img.data.iter());
                 tmp
             }
         };
 
-        match fs::write(path, b) {
-            Ok(_) => {},
-            Err(e) => { println!("ERROR: Unable to save '{}': {}", &file_name, e); return; },
+        fs::write(path, b).map_err(|e| format!("Unable to save '{}': {}", &file_name, e))
+    }
+
+    // put the image in the json instead of a file
+    pub fn embed(&mut self, format: EmbedFormat) -> Result<(), String> {
+        let img = Img::from_img_data(self);
+        let b: Vec<u8> = match format {
+            EmbedFormat::Png => img.to_png()?,
+            EmbedFormat::Bmp => img.to_bmp(),
+            EmbedFormat::Rle => {
+                let mut tmp = self.header.clone();
+                tmp.extend(self.data.iter());
+                tmp
+            },
+        };
+        self.embedded = Some(Box::new(Embedded { format, data: to_base64(&b) }));
+        self.file_name = None;
+        Ok(())
+    }
+
+    fn read_embedded(&mut self) -> Result<(), String> {
+        let embedded = *self.embedded.clone().unwrap();
+        let b = from_base64(&embedded.data).map_err(|e| format!("Embedded image is not valid base64: {}", e))?;
+        let img: Img = match embedded.format {
+            EmbedFormat::Png => Img::from_png(&b).map_err(|e| format!("Unable to understand embedded PNG: {}", e))?,
+            EmbedFormat::Bmp => Img::from_bmp(&b).map_err(|e| format!("Unable to understand embedded BMP: {}", e))?,
+            EmbedFormat::Rle => {
+                // the size comes from the json, so check the blob fits it before reading
+                let header_size = self.h as usize * 4;
+                if ImgData::get_data_size(&b, 0, self.h as usize).ok().map(|s| s + header_size) != Some(b.len()) {
+                    return Err(format!("Embedded RLE image is the wrong size for {}x{}", self.w, self.h));
+                }
+                let (offset, file_name) = (self.offset, self.file_name.take());
+                *self = ImgData::from_bin(&b, 0, self.w as usize, self.h as usize)?;
+                self.offset = offset;
+                self.file_name = file_name;
+                self.embedded = Some(Box::new(embedded));
+                return Ok(());
+            },
         };
+        if img.w > u16::MAX as u32 || img.h > u16::MAX as u32 {
+            return Err("Embedded image is too large".to_string());
+        }
+        let id = ImgData::from_img(&img)?;
+        self.w = id.w;
+        self.h = id.h;
+        self.header = id.header;
+        self.data = id.data;
+        Ok(())
     }
 
     pub fn set_file_name(&mut self, file_name: &str, overwrite: bool) {
@@ -197,6 +386,38 @@ impl fmt::Debug for ImgData
          .field("data (size)", &self.data.len())
          .field("w", &self.w)
          .field("h", &self.h)
+         .field("offset", &self.offset)
          .finish()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_image_is_an_error() {
+        // a 2x2 image needs an 8 byte row table before its data
+        assert!(ImgData::from_bin(&[ 0; 6 ], 0, 2, 2).is_err());
+        assert!(ImgData::from_owh(&[ 0; 6 ], 0).is_err());
+        let mut id = ImgData::from_img(&Img::new(2, 2)).unwrap();
+        let blob = id.to_bin();
+        assert!(ImgData::from_bin(&blob[..blob.len() - 4], 0, 2, 2).is_err());
+        assert_eq!(ImgData::from_bin(&blob, 0, 2, 2).unwrap().data, id.data);
+
+        // damaged pixel data decodes to something, without panicking
+        id.data = vec![ 0x85, 1, 2 ];
+        let mut img = Img::from_img_data(&id);
+        img.convert_format(ImgFormat::Argb8888);
+        assert_eq!(img.data.len(), 16);
+    }
+
+    #[test]
+    fn unsafe_file_name_is_an_error() {
+        let mut id = ImgData::from_img(&Img::new(2, 2)).unwrap();
+        id.file_name = Some("../x.bmp".to_string());
+        assert_eq!(id.read_img("dump").err().unwrap(), "Not reading image file: '../x.bmp' uses .., it must stay inside the folder");
+        id.file_name = None;
+        assert!(id.read_img("dump").is_err());
+    }
+}
@@ -9,10 +9,16 @@
 
 
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
 
 mod util;
 mod bmp_format;
+#[cfg(test)]
+#[allow(dead_code)]         // layout reference, the structs are only measured
 mod binary_face_n;
 mod img;
 mod digits;
@@ -21,184 +27,887 @@ mod xy;
 mod img_data;
 mod elements;
 mod sane_file_name;
+mod stats;
+mod device;
+#[allow(dead_code)]         // an api for code that makes faces, the commands don't use it
+mod face_builder;
+mod render;
+mod retarget;
+mod recolor;
+mod watch;
+mod serve;
+mod tui;
+mod codec;
+mod upload;
+mod ora;
+mod svg;
+mod optimize;
+mod raw;
+mod netpbm;
+mod tga;
+mod pack_cache;
+mod preview;
 
-use crate::img_data::DumpFormat;
+use crate::face::{FaceN, SizeChange};
+use crate::img_data::{DumpFormat, EmbedFormat};
+use crate::raw::RawLayout;
+use crate::pack_cache::PackCache;
 
 
-fn main() {
-    let mut file_name = "";
-    let mut folder_name = "dump";
-    let mut format = DumpFormat::BMP;
-    let mut dump = false;
-    let mut pack = false;
-    let mut show_help = false;
-    let mut debug: u8 = 1;
-
-    // find executable name
-    let basename = "extrathundertool";
-    let argv: Vec<String> = std::env::args().collect();
-
-    // read command-line parameters
-    for i in 1..argv.len() {
-        if argv[i] == "--raw" {
-            format = DumpFormat::RAW;
-        } else if argv[i] == "--bin" {
-            format = DumpFormat::BIN;
-        } else if argv[i] == "--bmp" {
-            format = DumpFormat::BMP;
-        } else if argv[i].starts_with("--dump") {
-            dump = true;
-            if argv[i].len() >= 8 && argv[i].as_bytes()[6] == b'=' {
-                folder_name = &argv[i][7..];
-            }
-        } else if argv[i].starts_with("--pack") {
-            pack = true;
-            if dump && pack {
-                panic!("ERROR: Can't dump and pack at the same time!");
-            }
-            if argv[i].len() >= 8 && argv[i].as_bytes()[6] == b'=' {
-                folder_name = &argv[i][7..];
-            }
-        } else if argv[i].starts_with("--debug") {
-            debug = 3;
-            if argv[i].len() >= 9 && argv[i].as_bytes()[7] == b'=' {
-                debug = argv[i][8..].parse().unwrap();
-            }
-        } else if argv[i].starts_with("--help") {
-            show_help = true;
-        } else if argv[i].starts_with("--") {
-            eprintln!("ERROR: Unknown option: {}", argv[i]);
-            show_help = true;
+// exit codes
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+
+#[derive(Parser)]
+#[command(name = "extrathundertool", version, about = "ExtraThunder watch face tool: For 'new' Mo Young / Da Fit binary watch face files.")]
+struct Cli {
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text, help = "Print results and errors as text, or as JSON on stdout.")]
+    format: OutputFormat,
+
+    #[arg(long, global = true, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=3), help = "Print more debug info. Range 0 to 3.")]
+    debug: u8,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(ValueEnum, Copy, Clone, PartialEq, Debug)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Copy, Clone, PartialEq, Debug)]
+enum UploadDevice {
+    Mock,
+}
+
+#[derive(ValueEnum, Copy, Clone, PartialEq, Debug)]
+enum LayoutPicture {
+    None,
+    Preview,
+    Background,
+    Render,
+}
+
+// what pack does when an image is not the size watchface.json says
+#[derive(ValueEnum, Copy, Clone, PartialEq, Debug)]
+enum SizeCheck {
+    Warn,
+    Strict,
+    Update,
+}
+
+#[derive(Args)]
+struct RecolorOptions {
+    #[arg(long, default_value_t = 8, help = "When recolouring with map, how close a colour must be.")]
+    tolerance: u8,
+
+    #[arg(long, value_delimiter = ',', help = "Only recolour these, e.g. digits,preview,image,time_hand. Default all.")]
+    only: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Dump a binary watch face file to a folder of images and watchface.json.")]
+    Dump {
+        #[arg(help = "Binary watch face file.")]
+        file: String,
+        #[arg(default_value = "dump", help = "Folder to dump to.")]
+        folder: String,
+        #[arg(long, default_value = "bmp", value_parser = DumpFormat::from_name, help = "Image format: bmp (windows bitmap), raw (decompressed), bin (RLE compressed), ppm (no alpha), pam or tga.")]
+        images: DumpFormat,
+        #[arg(long, default_value = "argb8565", value_parser = RawLayout::from_name, help = "Pixel layout of raw images: argb8565, rgb565le, rgb565be, argb8888 or rgba8888.")]
+        raw_layout: RawLayout,
+        #[arg(long, help = "Compress tga images with RLE.")]
+        tga_rle: bool,
+        #[arg(long, value_name = "FORMAT", value_parser = EmbedFormat::from_name, help = "Put the images in watchface.json as base64 instead of files: png, bmp or rle (as in the binary file).")]
+        embed: Option<EmbedFormat>,
+        #[arg(long, help = "Put all the images in the folder itself, instead of in digits/ and elements/ subfolders.")]
+        flat: bool,
+    },
+
+    #[command(about = "Pack a folder of images and watchface.json into a binary watch face file.")]
+    Pack {
+        #[arg(help = "Folder containing watchface.json, or a JSON file with embedded images.")]
+        folder: String,
+        #[arg(help = "Binary watch face file to write.")]
+        out: String,
+        #[arg(long, help = "Keep watching the folder and pack again when anything changes.")]
+        watch: bool,
+        #[arg(long, help = "Encode every image, without using or updating the cache of encoded images.")]
+        no_cache: bool,
+        #[arg(long, value_enum, default_value_t = SizeCheck::Warn, help = "When an image is not the size watchface.json says: warn, fail (strict), or save the new sizes to watchface.json (update).")]
+        sizes: SizeCheck,
+        #[arg(long, help = "Replace the preview with the face rendered at 10:08 with sample sensor values, scaled to the preview size.")]
+        make_preview: bool,
+        #[arg(long, conflicts_with = "make_preview", help = "Warn if the preview looks too different from the face rendered at 10:08.")]
+        check_preview: bool,
+        #[arg(long = "recolor", value_name = "OP", help = "Recolour the images. OP is hue:DEGREES, sat:FACTOR, bright:FACTOR or map:RRGGBB=RRGGBB,... Can be repeated.")]
+        recolor: Vec<String>,
+        #[command(flatten)]
+        recolor_options: RecolorOptions,
+    },
+
+    #[command(about = "Show the header, digit sets and elements of a binary watch face file.")]
+    Info {
+        #[arg(help = "Binary watch face file.")]
+        file: String,
+        #[arg(long, help = "Show every field of the digit sets and elements.")]
+        verbose: bool,
+    },
+
+    #[command(about = "Check a binary watch face file for problems. Fails if any are found.")]
+    Verify {
+        #[arg(help = "Binary watch face file.")]
+        file: String,
+    },
+
+    #[command(about = "Render the watch face to a BMP file.")]
+    Render {
+        #[arg(help = "Binary watch face file.")]
+        file: String,
+        #[arg(help = "File to write.")]
+        out: String,
+        #[arg(long, value_parser = render::RenderState::from_time_str, help = "The time to show, HH:MM:SS. Default 10:08:36.")]
+        time: Option<render::RenderState>,
+        #[arg(long, help = "Mark the screen centre (magenta) and hand centres (cyan).")]
+        markers: bool,
+    },
+
+    #[command(about = "Draw the position of each element to an SVG file.")]
+    Layout {
+        #[arg(help = "Binary watch face file.")]
+        file: String,
+        #[arg(help = "SVG file to write.")]
+        out: String,
+        #[arg(long, value_enum, default_value_t = LayoutPicture::None, help = "Picture to draw underneath: the preview image, the background image, or the rendered face.")]
+        picture: LayoutPicture,
+    },
+
+    #[command(about = "Scale the watch face to a new screen size.")]
+    Retarget {
+        #[arg(help = "Binary watch face file.")]
+        file: String,
+        #[arg(help = "File to write.")]
+        out: String,
+        #[arg(long, value_parser = parse_size, help = "The new screen size, WxH.")]
+        size: (u16, u16),
+        #[arg(long, default_value = "bilinear", value_parser = img::ResizeFilter::from_name, help = "The resize filter: nearest, bilinear, bicubic (sharper) or lanczos (sharpest).")]
+        filter: img::ResizeFilter,
+        #[arg(long, default_value = "fit", value_parser = retarget::FitPolicy::from_name, help = "fit (show everything) or fill (cover the screen).")]
+        policy: retarget::FitPolicy,
+        #[arg(long, help = "The tpls of the new watch. By default, the one with that screen size.")]
+        tpls: Option<u16>,
+    },
+
+    #[command(about = "Make a watch face file smaller.")]
+    Optimize {
+        #[arg(help = "Binary watch face file.")]
+        file: String,
+        #[arg(help = "File to write.")]
+        out: String,
+        #[arg(long, help = "Crop transparent borders off the images, and move the elements to match.")]
+        trim: bool,
+        #[arg(long, value_name = "TOLERANCE", help = "Change pixels by up to TOLERANCE (0 to 255) so the images compress better.")]
+        lossy: Option<u8>,
+        #[arg(long, default_value_t = 8, requires = "lossy", help = "With --lossy, alpha this close to 0 or 255 becomes 0 or 255.")]
+        alpha_snap: u8,
+    },
+
+    #[command(about = "Recolour the images of a watch face.")]
+    Recolor {
+        #[arg(help = "Binary watch face file.")]
+        file: String,
+        #[arg(help = "File to write.")]
+        out: String,
+        #[arg(required = true, help = "hue:DEGREES, sat:FACTOR, bright:FACTOR or map:RRGGBB=RRGGBB,...")]
+        ops: Vec<String>,
+        #[command(flatten)]
+        recolor_options: RecolorOptions,
+    },
+
+    #[command(about = "Show how a BMP image will look on the watch. Saves IMAGE_device.bmp and an error heatmap IMAGE_error.bmp.")]
+    Simulate {
+        #[arg(help = "BMP image file.")]
+        image: String,
+    },
+
+    #[command(about = "Analyse unknown fields across a folder of watch faces.")]
+    Stats {
+        #[arg(help = "Folder of binary watch face files.")]
+        folder: String,
+    },
+
+    #[command(about = "Edit the layout of a dumped watch face in the web browser.")]
+    Serve {
+        #[arg(default_value = "dump", help = "Folder containing watchface.json.")]
+        folder: String,
+        #[arg(long, default_value_t = 8080, help = "The port to serve on, on localhost.")]
+        port: u16,
+    },
+
+    #[command(about = "Browse a binary watch face file in the terminal.")]
+    Tui {
+        #[arg(help = "Binary watch face file.")]
+        file: String,
+    },
+
+    #[command(about = "Export a dumped watch face as an OpenRaster (.ora) layered image, for GIMP or Krita.")]
+    ExportOra {
+        #[arg(help = "Folder containing watchface.json.")]
+        folder: String,
+        #[arg(help = "OpenRaster file to write.")]
+        out: String,
+    },
+
+    #[command(about = "Update the images and element positions of a dumped watch face from an OpenRaster (.ora) file.")]
+    ImportOra {
+        #[arg(help = "OpenRaster file to read.")]
+        file: String,
+        #[arg(help = "Folder containing watchface.json.")]
+        folder: String,
+    },
+
+    #[command(about = "Try out the upload protocol, by sending a binary watch face file to a mock watch.")]
+    Upload {
+        #[arg(help = "Binary watch face file.")]
+        file: String,
+        #[arg(long, help = "Where to send it. Only mock, an in-memory watch, is available so far. The transfer commands are placeholders until checked against a real watch.")]
+        device: UploadDevice,
+        #[arg(long, default_value_t = 244, help = "Largest packet size.")]
+        mtu: usize,
+        #[arg(long, default_value_t = 0, help = "Have the mock watch reject every Nth chunk once, to try the resending.")]
+        mock_faults: usize,
+    },
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Dump { .. } => "dump",
+            Command::Pack { .. } => "pack",
+            Command::Info { .. } => "info",
+            Command::Verify { .. } => "verify",
+            Command::Render { .. } => "render",
+            Command::Layout { .. } => "layout",
+            Command::Retarget { .. } => "retarget",
+            Command::Optimize { .. } => "optimize",
+            Command::Recolor { .. } => "recolor",
+            Command::Simulate { .. } => "simulate",
+            Command::Stats { .. } => "stats",
+            Command::Serve { .. } => "serve",
+            Command::Tui { .. } => "tui",
+            Command::Upload { .. } => "upload",
+            Command::ExportOra { .. } => "export-ora",
+            Command::ImportOra { .. } => "import-ora",
+        }
+    }
+}
+
+fn parse_size(s: &str) -> Result<(u16, u16), String> {
+    let size: Vec<u16> = s.split('x').filter_map(|v| v.parse().ok()).collect();
+    if size.len() != 2 || size[0] == 0 || size[1] == 0 {
+        return Err(format!("Size '{}' should be WIDTHxHEIGHT", s));
+    }
+    Ok((size[0], size[1]))
+}
+
+fn parse_recolor(specs: &[String], tolerance: u8) -> Result<Vec<recolor::RecolorOp>, String> {
+    specs.iter().map(|spec| recolor::RecolorOp::from_spec(spec, tolerance)).collect()
+}
+
+// progress and warnings go to the terminal in text mode. in json mode they are kept for the final result.
+struct Output {
+    json: bool,
+    debug: u8,
+    warnings: Vec<String>,
+}
+
+impl Output {
+    fn progress(&self, msg: &str) {
+        if !self.json && self.debug >= 1 {
+            print!("{} ... ", msg);
+            let _ = std::io::stdout().flush();
+        }
+    }
+
+    fn done(&self) {
+        if !self.json && self.debug >= 1 { println!("done."); }
+    }
+
+    // results that are only printed in text mode
+    fn text(&self, msg: &str) {
+        if !self.json { println!("{}", msg); }
+    }
+
+    fn warn(&mut self, msg: String) {
+        if !self.json { eprintln!("WARNING: {}", msg); }
+        self.warnings.push(msg);
+    }
+}
+
+// a last resort, for a bug that panics: report it as an error, so json mode still prints a result and the exit code is right
+fn catch<T>(what: &str, f: impl FnOnce() -> T + std::panic::UnwindSafe) -> Result<T, String> {
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(f);
+    std::panic::set_hook(hook);
+    result.map_err(|p| {
+        let msg = match p.downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => p.downcast_ref::<String>().cloned().unwrap_or_else(|| "unknown error".to_string()),
+        };
+        format!("{}: {}", what, msg.trim_start_matches("ERROR: "))
+    })
+}
+
+fn load_face(out: &Output, file_name: &str) -> Result<(Vec<u8>, FaceN), String> {
+    out.progress(&format!("Reading '{}'", file_name));
+    let fdata: Vec<u8> = fs::read(file_name).map_err(|e| format!("Unable to read '{}': {}", file_name, e))?;
+    let f = FaceN::from_bin(&fdata).map_err(|e| format!("Unable to understand '{}': {}", file_name, e))?;
+    out.done();
+    Ok((fdata, f))
+}
+
+fn save_face(out: &mut Output, f: &FaceN, file_name: &str) -> Result<usize, String> {
+    let (bin_data, layout_problem) = f.to_bin_with_layout().map_err(|e| format!("Unable to build the binary file: {}", e))?;
+    if let Some(p) = layout_problem {
+        out.warn(format!("Not keeping the original file layout: {}", p));
+    }
+    out.progress(&format!("Saving '{}'", file_name));
+    fs::write(file_name, &bin_data).map_err(|e| format!("Unable to save '{}': {}", file_name, e))?;
+    out.done();
+    Ok(bin_data.len())
+}
+
+// a face read from a folder
+struct Loaded {
+    face: FaceN,
+    json_path: PathBuf,
+    cache: Option<PackCache>,           // with use_cache
+    size_changes: Vec<SizeChange>,      // images that aren't the size watchface.json says, these are warned about
+}
+
+// read watchface.json and its images from a folder. a JSON file can be given instead, image files are next to it.
+// with use_cache, images that haven't changed since the last pack come from the cache file in the folder.
+// a JSON file can be anywhere, even the current folder, so it doesn't get a cache file.
+fn load_folder(out: &mut Output, folder_name: &str, use_cache: bool) -> Result<Loaded, String> {
+    let (json_path, folder_name, use_cache) = if folder_name.to_lowercase().ends_with(".json") && PathBuf::from(folder_name).is_file() {
+        let parent = Path::new(folder_name).parent().and_then(|p| p.to_str()).filter(|p| !p.is_empty()).unwrap_or(".");
+        (PathBuf::from(folder_name), parent, false)
+    } else if PathBuf::from(folder_name).is_dir() {
+        ([ folder_name, "watchface.json" ].iter().collect(), folder_name, use_cache)
+    } else {
+        return Err(format!("'{}' is not a folder", folder_name));
+    };
+    out.progress(&format!("Reading '{}'", json_path.display()));
+    let file_data = fs::read(&json_path).map_err(|e| format!("Unable to read file: {}", e))?;
+    let mut face: FaceN = serde_json::from_slice(&file_data).map_err(|e| format!("Unable to understand JSON file: {}", e))?;
+    out.done();
+
+    out.progress("Reading in bitmaps");
+    let declared = face.img_sizes();
+    let mut cache: Option<PackCache> = None;
+    if use_cache {
+        let mut c = PackCache::load(folder_name);
+        face.read_imgs_cached(folder_name, &mut c)?;
+        cache = Some(c);
+    } else {
+        face.read_imgs(folder_name)?;
+    }
+    out.done();
+    if let Some(c) = &mut cache {
+        // not being able to save the cache only makes the next pack slower
+        if let Err(e) = c.save(folder_name) {
+            out.warn(e);
+        }
+    }
+
+    let size_changes = face.size_changes(&declared);
+    for c in size_changes.iter() {
+        out.warn(c.to_string());
+    }
+    Ok(Loaded { face, json_path, cache, size_changes })
+}
+
+fn recolor_face(out: &Output, f: &mut FaceN, ops: &[recolor::RecolorOp], only: &[String]) -> Result<usize, String> {
+    // every image is encoded again, so the file is laid out anew
+    f.original_layout = None;
+    out.progress("Recolouring");
+    let count = f.recolor(ops, only)?;
+    if !out.json && out.debug >= 1 { println!("{} images done.", count); }
+    Ok(count)
+}
+
+fn dump(out: &mut Output, file: &str, folder_name: &str, format: DumpFormat, embed: Option<EmbedFormat>, flat: bool) -> Result<Value, String> {
+    let (_, mut f) = load_face(out, file)?;
+
+    // create folder if it doesn't exist
+    let path = PathBuf::from(folder_name);
+    if !path.is_dir() {
+        fs::create_dir_all(&path).map_err(|e| format!("Unable to create folder '{}': {}", folder_name, e))?;
+    }
+
+    if let Some(embed) = embed {
+        // the images go in the json
+        out.progress("Embedding images");
+        for (_, id) in f.all_img_data_mut() {
+            id.embed(embed)?;
+        }
+        out.done();
+    } else {
+        // generate image filenames
+        f.generate_file_names(&format, flat);
+
+        // save the images
+        out.progress("Saving images");
+        f.write_imgs(folder_name, &format)?;
+        out.done();
+    }
+
+    // save the json data
+    let json_data = serde_json::to_string_pretty(&f).map_err(|e| format!("Unable to serialize watchface: {}", e))?;
+    out.progress("Saving 'watchface.json'");
+    let path: PathBuf = [ folder_name, "watchface.json" ].iter().collect();
+    fs::write(path, json_data).map_err(|e| format!("Unable to save 'watchface.json': {}", e))?;
+    out.done();
+
+    Ok(json!({ "folder": folder_name, "images": f.all_img_data_mut().len() }))
+}
+
+// everything pack can do besides reading the folder and writing the file
+struct PackOptions<'a> {
+    watch: bool,
+    use_cache: bool,
+    sizes: SizeCheck,
+    make_preview: bool,
+    check_preview: bool,
+    recolor_ops: &'a [recolor::RecolorOp],
+    only: &'a [String],
+}
+
+fn pack(out: &mut Output, folder_name: &str, out_file: &str, o: &PackOptions) -> Result<Value, String> {
+    if o.watch {
+        if !PathBuf::from(folder_name).is_dir() {
+            return Err(format!("'{}' is not a folder", folder_name));
+        }
+        watch::Watcher::new(folder_name, out_file, o, out.json).run();
+        return Ok(Value::Null);
+    }
+    pack_once(out, folder_name, out_file, o)
+}
+
+// one pack, for pack and for each change in pack --watch
+fn pack_once(out: &mut Output, folder_name: &str, out_file: &str, o: &PackOptions) -> Result<Value, String> {
+    let (recolor_ops, only) = (o.recolor_ops, o.only);
+    let Loaded { mut face, json_path, cache, size_changes } = load_folder(out, folder_name, o.use_cache)?;
+    if let Some(c) = &cache {
+        out.text(&format!("{} images from the cache, {} encoded.", c.hits, c.encoded));
+    }
+    if !size_changes.is_empty() {
+        match o.sizes {
+            SizeCheck::Warn => {},
+            SizeCheck::Strict => return Err(format!("{} images are not the size watchface.json says. Use --sizes update to accept the new sizes.", size_changes.len())),
+            SizeCheck::Update => {
+                // save the json before recolouring, so only the sizes change
+                let json_data = serde_json::to_string_pretty(&face).map_err(|e| format!("Unable to serialize watchface: {}", e))?;
+                out.progress(&format!("Updating sizes in '{}'", json_path.display()));
+                fs::write(&json_path, json_data).map_err(|e| format!("Unable to save '{}': {}", json_path.display(), e))?;
+                out.done();
+            },
+        }
+    }
+    if !recolor_ops.is_empty() {
+        recolor_face(out, &mut face, recolor_ops, only)?;
+    }
+
+    // the preview is made last, so it shows any recolouring
+    let mut preview: Option<Value> = None;
+    if o.make_preview {
+        out.progress("Rendering the preview");
+        face.make_preview()?;
+        out.done();
+        preview = Some(json!({ "made": true }));
+    } else if o.check_preview {
+        let err = face.check_preview();
+        if err.mean > preview::PREVIEW_TOLERANCE {
+            out.warn(format!("The preview looks different to the face (mean error {:.1}, more than {:.1}). Use --make-preview to replace it.", err.mean, preview::PREVIEW_TOLERANCE));
         } else {
-            // must be file_name
-            if file_name == "" {
-                file_name = &argv[i];
-            } else {
-                eprintln!("WARNING: Ignored unknown parameter: {}", argv[i]);
-            }
+            out.text(&format!("The preview matches the face (mean error {:.1}).", err.mean));
         }
+        preview = Some(json!({ "mean_error": err.mean, "changed": err.changed, "stale": err.mean > preview::PREVIEW_TOLERANCE }));
     }
 
-    // display basic program header
-    if debug >= 1 { eprintln!("\nextrathunder watchface tool: For 'new' Mo Young / Da Fit binary watch face files.\n"); }
-
-    // display help
-    if argv.len() < 2 || show_help {
-        eprintln!("Usage:   {} [OPTIONS] FILENAME\n", basename);
-        eprintln!("  OPTIONS");
-        eprintln!("    --dump=FOLDERNAME    Dump data to folder. Folder name defaults to 'dump'.");
-        eprintln!("    --pack=FOLDERNAME    Pack data from folder. Folder name defaults to 'dump'.");
-        eprintln!("    --bmp                When dumping, dump BMP (windows bitmap) files. Default.");
-        eprintln!("    --raw                When dumping, dump raw (decompressed raw bitmap) files.");
-        eprintln!("    --bin                When dumping, dump binary (RLE compressed) files.");
-        eprintln!("    --debug=LEVEL        Print more debug info. Range 0 to 3.");
-        eprintln!("  FILENAME               Binary watch face file for input/output.");
-        eprintln!("\n");
-        std::process::exit(0);
-    }
-
-    if !pack {
-        if debug >= 1 { print!("Reading '{}' ... ", file_name); }
-
-        // Open the binary input file
-        let fdata: Vec<u8> = fs::read(file_name).expect("ERROR: Failed to read file into memory.");
-
-        // Load the binary watch face file
-        let mut f = face::FaceN::from_bin(&fdata);
-
-        if debug >= 1 { println!("done."); }
-
-        if debug >= 3 {
-            // Print debug info
-            eprintln!("api_ver          {}", f.api_ver);
-            eprintln!("unknown          0x{:04X}", f.unknown);
-            eprintln!("digits.len       {}", f.digits.len());
-            eprintln!("elements.len     {}", f.elements.len());
-
-            for i in 0..f.digits.len() {
-                eprintln!("\nDIGIT SET {}\n", i);
-                eprintln!("{:#?}", f.digits[i]);
-            }
-            for i in 0..f.elements.len() {
-                eprintln!("\nELEMENT {}: E_TYPE {}\n", i, f.elements[i].e_type());
-                eprintln!("{:#?}", f.elements[i]);
-            }
+    // check for problems
+    for p in face.validate() {
+        out.warn(p);
+    }
+
+    let size = save_face(out, &face, out_file)?;
+    let mut result = json!({ "out": out_file, "size": size });
+    if let Some(c) = cache {
+        result["cache"] = json!({ "hits": c.hits, "encoded": c.encoded });
+    }
+    if let Some(p) = preview {
+        result["preview"] = p;
+    }
+    if !size_changes.is_empty() {
+        result["size_changes"] = size_changes.iter().map(|c| json!({ "image": c.index, "file_name": c.file_name, "declared": [ c.declared.0, c.declared.1 ], "actual": [ c.actual.0, c.actual.1 ] })).collect();
+        result["sizes_updated"] = json!(o.sizes == SizeCheck::Update);
+    }
+    Ok(result)
+}
+
+fn info(out: &mut Output, file: &str, verbose: bool) -> Result<Value, String> {
+    let (fdata, f) = load_face(out, file)?;
+    let (w, h) = f.screen_size();
+    let d = device::Device::from_size(w, h);
+
+    out.text(&format!("api_ver          {}", f.api_ver));
+    out.text(&format!("unknown          0x{:04X}", f.unknown));
+    out.text(&format!("preview          {}x{}", f.preview_img_data.w, f.preview_img_data.h));
+    out.text(&format!("digits.len       {}", f.digits.len()));
+    out.text(&format!("elements.len     {}", f.elements.len()));
+    match d {
+        Some(d) => out.text(&format!("screen           {}x{} ({}, tpls {})", w, h, d.name, d.tpls)),
+        None => out.text(&format!("screen           {}x{}", w, h)),
+    }
+    if verbose {
+        for i in 0..f.digits.len() {
+            out.text(&format!("\nDIGIT SET {}\n", i));
+            out.text(&format!("{:#?}", f.digits[i]));
+        }
+        for i in 0..f.elements.len() {
+            out.text(&format!("\nELEMENT {}: E_TYPE {}\n", i, f.elements[i].e_type()));
+            out.text(&format!("{:#?}", f.elements[i]));
+        }
+    } else {
+        for (i, el) in f.elements.iter().enumerate() {
+            out.text(&format!("element {:<7}  {}", i, el.type_name()));
+        }
+    }
+
+    let mut result = json!({
+        "file_size": fdata.len(),
+        "api_ver": f.api_ver,
+        "unknown": f.unknown,
+        "preview": { "w": f.preview_img_data.w, "h": f.preview_img_data.h },
+        "screen": { "w": w, "h": h },
+        "device": d.map(|d| json!({ "name": d.name, "tpls": d.tpls })),
+        "digit_sets": f.digits.len(),
+        "elements": f.elements.iter().map(|el| el.type_name()).collect::<Vec<&str>>(),
+    });
+    if verbose {
+        // the same as in watchface.json
+        result["face"] = serde_json::to_value(&f).map_err(|e| e.to_string())?;
+    }
+    Ok(result)
+}
+
+fn verify(out: &mut Output, file: &str) -> Result<Value, String> {
+    let (fdata, f) = load_face(out, file)?;
+    for p in f.check_hands() {
+        out.warn(p);
+    }
+    let identical = f.to_bin().map_err(|e| format!("Unable to build the binary file: {}", e))? == fdata;
+    if !identical {
+        out.warn("Packing this face again will not give an identical file.".to_string());
+    }
+    let problems = f.validate();
+    if !problems.is_empty() {
+        for p in problems.iter() {
+            out.warn(p.clone());
+        }
+        return Err(format!("{} problems found in '{}'", problems.len(), file));
+    }
+    out.text("No problems found.");
+    Ok(json!({ "problems": 0, "repacks_identically": identical }))
+}
+
+fn render_cmd(out: &mut Output, file: &str, out_file: &str, state: &render::RenderState, markers: bool) -> Result<Value, String> {
+    let (_, f) = load_face(out, file)?;
+    for p in f.check_hands() {
+        out.warn(p);
+    }
+    out.progress(&format!("Rendering '{}'", out_file));
+    let img = f.render(state, markers);
+    fs::write(out_file, img.to_bmp()).map_err(|e| format!("Unable to save '{}': {}", out_file, e))?;
+    out.done();
+    Ok(json!({ "out": out_file, "w": img.w, "h": img.h }))
+}
+
+fn optimize_cmd(out: &mut Output, file: &str, out_file: &str, trim: bool, lossy: Option<u8>, alpha_snap: u8) -> Result<Value, String> {
+    if !trim && lossy.is_none() {
+        return Err("Nothing to do, use --trim or --lossy".to_string());
+    }
+    let (fdata, mut f) = load_face(out, file)?;
+    // a smaller file needs a new layout
+    f.original_layout = None;
+    let mut result = json!({ "out": out_file, "old_size": fdata.len() });
+    if trim {
+        out.progress("Trimming");
+        let report = f.trim()?;
+        out.done();
+        for w in report.warnings {
+            out.warn(w);
         }
+        out.text(&format!("Trimmed {} images, saving {} bytes of image data.", report.images, report.bytes_saved));
+        result["trimmed"] = json!({ "images": report.images, "bytes_saved": report.bytes_saved });
+    }
+    if let Some(tolerance) = lossy {
+        out.progress("Quantising");
+        let report = f.quantize(tolerance, alpha_snap.min(127))?;
+        out.done();
+        out.text(&format!("Changed {} images, saving {} bytes of image data. Maximum error {}.", report.images, report.bytes_saved, report.max_error));
+        result["lossy"] = json!({ "images": report.images, "bytes_saved": report.bytes_saved, "max_error": report.max_error });
+    }
+    let size = save_face(out, &f, out_file)?;
+    out.text(&format!("File size {} bytes, was {}.", size, fdata.len()));
+    result["size"] = json!(size);
+    Ok(result)
+}
 
-        if dump {
-            // create folder if it doesn't exist
-            let path = PathBuf::from(folder_name);
-            if !path.is_dir() {
-                match std::fs::create_dir_all(path.clone()) {
-                    Ok(_) => {},
-                    Err(e) => { println!("ERROR: Unable to create folder '{}': {}", folder_name, e); return; },
-                };
+fn layout_cmd(out: &mut Output, file: &str, out_file: &str, picture: LayoutPicture) -> Result<Value, String> {
+    let (_, f) = load_face(out, file)?;
+    let (w, h) = f.screen_size();
+    let img = match picture {
+        LayoutPicture::None => None,
+        LayoutPicture::Preview => Some(img::Img::from_img_data(&f.preview_img_data)),
+        LayoutPicture::Background => {
+            let bg = f.elements.iter().find_map(|el| match el {
+                elements::Element::Image(e) if e.x == 0 && e.y == 0 && e.img_data.w == w && e.img_data.h == h => Some(&e.img_data),
+                _ => None,
+            });
+            match bg {
+                Some(id) => Some(img::Img::from_img_data(id)),
+                None => { out.warn("There is no full screen background image.".to_string()); None },
             }
-            
-            // generate image filenames
-            f.generate_file_names(&format);
-            
-            // save the images
-            if debug >= 1 { print!("Saving images ... "); }
-            f.write_imgs(&folder_name, &format);
-            if debug >= 1 { println!("done."); }
-
-            // file to save json data to        
-            let output_file_name = "watchface.json";
-            let path: PathBuf = [ folder_name, output_file_name ].iter().collect();
-
-            let json_data = match serde_json::to_string_pretty(&f) {
-                Ok(s) => s,
-                Err(e) => { println!("ERROR: Unable to serialize watchface: {}", e); return; },
-            };
+        },
+        LayoutPicture::Render => Some(f.render(&render::RenderState::new(), false)),
+    };
+    out.progress(&format!("Saving '{}'", out_file));
+    let svg = f.layout_svg(img.as_ref())?;
+    fs::write(out_file, &svg).map_err(|e| format!("Unable to save '{}': {}", out_file, e))?;
+    out.done();
+    Ok(json!({ "out": out_file, "w": w, "h": h }))
+}
+
+fn retarget_cmd(out: &mut Output, file: &str, out_file: &str, size: (u16, u16), filter: img::ResizeFilter, policy: retarget::FitPolicy, tpls: Option<u16>) -> Result<Value, String> {
+    let (_, mut f) = load_face(out, file)?;
+    f.original_layout = None;           // every image is resized
+    out.progress(&format!("Retargeting to {}x{}", size.0, size.1));
+    let warnings = f.retarget(size.0, size.1, filter, policy, tpls)?;
+    out.done();
+    for w in warnings {
+        out.warn(w);
+    }
+    let bytes = save_face(out, &f, out_file)?;
+    Ok(json!({ "out": out_file, "size": bytes, "w": size.0, "h": size.1, "tpls": f.tpls }))
+}
+
+fn recolor_cmd(out: &mut Output, file: &str, out_file: &str, ops: &[recolor::RecolorOp], only: &[String]) -> Result<Value, String> {
+    let (_, mut f) = load_face(out, file)?;
+    let count = recolor_face(out, &mut f, ops, only)?;
+    let bytes = save_face(out, &f, out_file)?;
+    Ok(json!({ "out": out_file, "size": bytes, "images": count }))
+}
+
+fn simulate(out: &mut Output, file: &str) -> Result<Value, String> {
+    // the results are saved next to the image
+    let path = PathBuf::from(file);
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let file_data: Vec<u8> = fs::read(&path).map_err(|e| format!("Unable to read '{}': {}", file, e))?;
+    let img = img::Img::from_bmp(&file_data).map_err(|e| format!("Unable to understand BMP file '{}': {}", file, e))?;
+    let device_img = img.simulate_device();
+    let (heatmap, err) = img.error_heatmap(&device_img);
+    out.text(&format!("Pixels changed:   {} of {}", err.changed, img.w * img.h));
+    out.text(&format!("Maximum error:    {}", err.max));
+    out.text(&format!("Mean error:       {:.2}", err.mean));
+    let mut saved: Vec<String> = Vec::new();
+    for (suffix, img) in [ ("_device.bmp", &device_img), ("_error.bmp", &heatmap) ] {
+        let out_path = path.with_file_name(stem.clone() + suffix);
+        out.progress(&format!("Saving '{}'", out_path.display()));
+        fs::write(&out_path, img.to_bmp()).map_err(|e| format!("Unable to save '{}': {}", out_path.display(), e))?;
+        out.done();
+        saved.push(out_path.display().to_string());
+    }
+    Ok(json!({ "changed": err.changed, "pixels": img.w * img.h, "max_error": err.max, "mean_error": err.mean, "saved": saved }))
+}
+
+fn export_ora(out: &mut Output, folder_name: &str, out_file: &str) -> Result<Value, String> {
+    let face = load_folder(out, folder_name, false)?.face;
+    out.progress(&format!("Saving '{}'", out_file));
+    let data = ora::export(&face)?;
+    fs::write(out_file, &data).map_err(|e| format!("Unable to save '{}': {}", out_file, e))?;
+    out.done();
+    Ok(json!({ "out": out_file, "size": data.len() }))
+}
+
+fn import_ora(out: &mut Output, file: &str, folder_name: &str) -> Result<Value, String> {
+    if !PathBuf::from(folder_name).is_dir() {
+        return Err(format!("'{}' is not a folder", folder_name));
+    }
+    let mut face = load_folder(out, folder_name, false)?.face;
+    out.progress(&format!("Reading '{}'", file));
+    let data: Vec<u8> = fs::read(file).map_err(|e| format!("Unable to read '{}': {}", file, e))?;
+    let report = ora::import(&mut face, &data)?;
+    out.done();
+    for w in report.warnings {
+        out.warn(w);
+    }
 
-            // save the json data
-            if debug >= 1 { print!("Saving '{}' ... ", output_file_name); }
-            match fs::write(path, json_data) {
-                Ok(_) => {},
-                Err(e) => { println!("ERROR: Unable to save '{}': {}", output_file_name, e); return; },
+    // save the changed images in the same format they were in
+    out.progress("Saving images");
+    for (n, (_, id)) in face.all_img_data_mut().into_iter().enumerate() {
+        if report.changed.contains(&n) {
+            // embedded images were embedded again by the import, and are saved with the json
+            if id.embedded.is_some() {
+                continue;
+            }
+            let format = match id.file_name.as_deref().and_then(DumpFormat::from_file_name).unwrap_or(DumpFormat::BMP) {
+                // keep the pixel layout of raw images
+                DumpFormat::RAW(layout) => {
+                    let path = sane_file_name::file_path(folder_name, id.file_name.as_deref().unwrap_or(""))?;
+                    DumpFormat::RAW(raw::read_sidecar(&path)?.map(|info| info.format).unwrap_or(layout))
+                },
+                f => f,
             };
-            if debug >= 1 { println!("done."); }
-        }
-    } else {    // PACK
-        // read in the json file
-        let path = PathBuf::from(folder_name);
-        if !path.is_dir() {
-            panic!("ERROR: path provided for --pack is not a folder")
+            id.write_img(folder_name, &format)?;
         }
+    }
+    out.done();
 
-        if debug >= 1 { print!("Reading 'watchface.json' ... "); }
-        let path: PathBuf = [ folder_name, "watchface.json" ].iter().collect();
-        let file_data = match std::fs::read(path) {
-            Ok(fd) => fd,
-            Err(e) => { println!("ERROR: Unable to read file: {}", e); return; }
-        };
+    let json_data = serde_json::to_string_pretty(&face).map_err(|e| format!("Unable to serialize watchface: {}", e))?;
+    out.progress("Saving 'watchface.json'");
+    let path: PathBuf = [ folder_name, "watchface.json" ].iter().collect();
+    fs::write(path, json_data).map_err(|e| format!("Unable to save 'watchface.json': {}", e))?;
+    out.done();
+    out.text(&format!("{} images changed, {} elements moved.", report.changed.len(), report.moved));
+    Ok(json!({ "folder": folder_name, "changed": report.changed.len(), "moved": report.moved }))
+}
 
-        let mut face: face::FaceN = match serde_json::from_slice(&file_data) {
-            Ok(fd) => fd,
-            Err(e) => { println!("ERROR: Unable to understand JSON file: {}", e); return; }
-        };
+fn upload_cmd(out: &mut Output, file: &str, device: UploadDevice, mtu: usize, mock_faults: usize) -> Result<Value, String> {
+    // only send files we understand
+    let (fdata, _) = load_face(out, file)?;
+    let mut mock = match device {
+        UploadDevice::Mock => upload::MockDevice::new(mtu),
+    };
+    mock.corrupt_every = mock_faults;
+    let mut uploader = upload::Uploader::new(&mut mock)?;
+    out.progress(&format!("Uploading '{}'", file));
+    let show = !out.json && out.debug >= 2;
+    let report = uploader.upload(&fdata, |sent, total| {
+        if show { print!("\rUploading '{}' ... {} of {} bytes", file, sent, total); }
+    })?;
+    if show { println!(); }
+    out.done();
+    if mock.installed.as_deref() != Some(&fdata[..]) {
+        return Err("The mock watch did not end up with the same file".to_string());
+    }
+    out.text(&format!("Sent {} bytes in {} chunks, {} packets sent again. The mock watch installed an identical face.",
+        report.size, report.chunks, report.resent));
+    Ok(json!({ "size": report.size, "chunks": report.chunks, "resent": report.resent, "packets": mock.packets_received, "installed": true }))
+}
 
-        if debug >= 1 { println!("done."); }
+fn stats_cmd(out: &mut Output, folder: &str) -> Result<Value, String> {
+    let mut corpus = stats::Corpus::new();
+    let failed = corpus.add_folder(folder, out.debug)?;
+    if failed > 0 {
+        out.warn(format!("{} files could not be analysed.", failed));
+    }
+    let report = corpus.report();
+    if !out.json { print!("{}", report); }
+    Ok(json!({ "failed": failed, "report": report }))
+}
 
-        // Read in the images
-        if debug >= 1 { println!("Reading in bitmaps ..."); }
-        face.read_imgs(folder_name);
-        if debug >= 1 { println!("... done."); }
+fn run(out: &mut Output, command: &Command) -> Result<Value, String> {
+    match command {
+        Command::Dump { file, folder, images, raw_layout, tga_rle, embed, flat } => {
+            let format = match images {
+                DumpFormat::RAW(_) => DumpFormat::RAW(*raw_layout),
+                DumpFormat::TGA { .. } => DumpFormat::TGA { rle: *tga_rle },
+                f => *f,
+            };
+            dump(out, file, folder, format, *embed, *flat)
+        },
+        Command::Pack { folder, out: out_file, watch, no_cache, sizes, make_preview, check_preview, recolor, recolor_options } => {
+            let ops = parse_recolor(recolor, recolor_options.tolerance)?;
+            let options = PackOptions { watch: *watch, use_cache: !*no_cache, sizes: *sizes, make_preview: *make_preview, check_preview: *check_preview,
+                recolor_ops: &ops, only: &recolor_options.only };
+            pack(out, folder, out_file, &options)
+        },
+        Command::Info { file, verbose } => info(out, file, *verbose),
+        Command::Verify { file } => verify(out, file),
+        Command::Render { file, out: out_file, time, markers } => {
+            let state = time.clone().unwrap_or_else(render::RenderState::new);
+            render_cmd(out, file, out_file, &state, *markers)
+        },
+        Command::Layout { file, out: out_file, picture } => layout_cmd(out, file, out_file, *picture),
+        Command::Retarget { file, out: out_file, size, filter, policy, tpls } => retarget_cmd(out, file, out_file, *size, *filter, *policy, *tpls),
+        Command::Optimize { file, out: out_file, trim, lossy, alpha_snap } => optimize_cmd(out, file, out_file, *trim, *lossy, *alpha_snap),
+        Command::Recolor { file, out: out_file, ops, recolor_options } => {
+            let ops = parse_recolor(ops, recolor_options.tolerance)?;
+            recolor_cmd(out, file, out_file, &ops, &recolor_options.only)
+        },
+        Command::Simulate { image } => simulate(out, image),
+        Command::Stats { folder } => stats_cmd(out, folder),
+        Command::Serve { folder, port } => {
+            let mut server = serve::Server::new(folder, out.debug)?;
+            server.run(*port)?;
+            Ok(Value::Null)
+        },
+        Command::Tui { file } => {
+            let fdata: Vec<u8> = fs::read(file).map_err(|e| format!("Unable to read '{}': {}", file, e))?;
+            tui::Inspector::new(&fdata).map_err(|e| format!("Unable to understand '{}': {}", file, e))?.run()?;
+            Ok(Value::Null)
+        },
+        Command::Upload { file, device, mtu, mock_faults } => upload_cmd(out, file, *device, *mtu, *mock_faults),
+        Command::ExportOra { folder, out: out_file } => export_ora(out, folder, out_file),
+        Command::ImportOra { file, folder } => import_ora(out, file, folder),
+    }
+}
 
-        // Print debug info
-        // println!("{:#?}", face);
+fn main() {
+    let cli = match Cli::try_parse() {
+        Ok(c) => c,
+        Err(e) => {
+            // help and version are not errors
+            if !e.use_stderr() {
+                e.exit();
+            }
+            // the arguments didn't parse, so look for --format json ourselves
+            let argv: Vec<String> = std::env::args().collect();
+            let json = argv.iter().any(|a| a == "--format=json") || argv.windows(2).any(|w| w[0] == "--format" && w[1] == "json");
+            if json {
+                println!("{}", json!({ "ok": false, "error": e.kind().to_string(), "message": e.to_string() }));
+                std::process::exit(EXIT_USAGE);
+            }
+            e.exit();
+        },
+    };
 
-        // Get binary data
-        let bin_data = face.to_bin();
+    let mut out = Output { json: cli.format == OutputFormat::Json, debug: cli.debug, warnings: Vec::new() };
 
-        // Write to output file
-        if debug >= 1 { println!("Saving '{}' ... ", file_name); }
-        match fs::write(file_name, bin_data) {
-            Ok(_) => {},
-            Err(e) => { println!("ERROR: Unable to save '{}': {}", file_name, e); return; },
-        };
-        if debug >= 1 { println!("... done."); }
+    // display basic program header
+    if !out.json && out.debug >= 1 { eprintln!("\nextrathunder watchface tool: For 'new' Mo Young / Da Fit binary watch face files.\n"); }
 
+    let name = cli.command.name();
+    let result = catch("Internal error", std::panic::AssertUnwindSafe(|| run(&mut out, &cli.command))).and_then(|r| r);
+    match result {
+        Ok(result) => {
+            if out.json {
+                println!("{}", json!({ "command": name, "ok": true, "warnings": out.warnings, "result": result }));
+            }
+        },
+        Err(e) => {
+            if out.json {
+                println!("{}", json!({ "command": name, "ok": false, "warnings": out.warnings, "error": e }));
+            } else {
+                eprintln!("ERROR: {}", e);
+            }
+            std::process::exit(EXIT_FAILED);
+        },
     }
 }
@@ -0,0 +1,225 @@
+//  netpbm.rs - Netpbm PPM (P6) and PAM (P7) image files
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// A text header, then the pixels as bytes, top row first.
+//  P6 (PPM) is RGB with no alpha. Writing one draws the image on black, like the watch screen.
+//  P7 (PAM) has a TUPLTYPE, we read GRAYSCALE, GRAYSCALE_ALPHA, RGB and RGB_ALPHA, and write RGB_ALPHA.
+// A maxval other than 255 is scaled to 0 to 255. Values above 255 are two bytes, high byte first.
+
+
+use crate::img::{Img, ImgFormat};
+
+
+// reads the whitespace separated words of a header, skipping # comments
+struct Header<'a>
+{
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Header<'a>
+{
+    fn word(&mut self) -> Result<&'a str, String> {
+        loop {
+            match self.bytes.get(self.pos) {
+                Some(b'#') => while self.pos < self.bytes.len() && self.bytes[self.pos] != b'\n' { self.pos += 1 },
+                Some(c) if c.is_ascii_whitespace() => self.pos += 1,
+                Some(_) => break,
+                None => return Err("Netpbm header is incomplete".to_string()),
+            }
+        }
+        let start = self.pos;
+        while self.pos < self.bytes.len() && !self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos]).map_err(|_| "Netpbm header is not text".to_string())
+    }
+
+    fn number(&mut self, what: &str) -> Result<u32, String> {
+        let w = self.word()?;
+        w.parse::<u32>().map_err(|_| format!("Netpbm {} '{}' is not a number", what, w))
+    }
+
+    // the single whitespace character that ends the header of a P6 file
+    fn end(&mut self) -> usize {
+        self.pos + 1
+    }
+}
+
+impl Img {
+    // reads P6 and P7, the result is Argb8888
+    pub fn from_netpbm(bytes: &[u8]) -> Result<Img, String> {
+        let mut h = Header { bytes, pos: 0 };
+        let (w, ht, depth, maxval, tupltype, start) = match h.word()? {
+            "P6" => {
+                let w = h.number("width")?;
+                let ht = h.number("height")?;
+                let maxval = h.number("maxval")?;
+                (w, ht, 3, maxval, "RGB".to_string(), h.end())
+            },
+            "P7" => {
+                let (mut w, mut ht, mut depth, mut maxval, mut tupltype) = (0, 0, 0, 0, String::new());
+                loop {
+                    match h.word()? {
+                        "WIDTH" => w = h.number("WIDTH")?,
+                        "HEIGHT" => ht = h.number("HEIGHT")?,
+                        "DEPTH" => depth = h.number("DEPTH")?,
+                        "MAXVAL" => maxval = h.number("MAXVAL")?,
+                        "TUPLTYPE" => tupltype = h.word()?.to_string(),
+                        "ENDHDR" => break,
+                        other => return Err(format!("PAM header has unknown field '{}'", other)),
+                    }
+                }
+                // ENDHDR is followed by a newline
+                (w, ht, depth, maxval, tupltype, h.end())
+            },
+            _ => return Err("Not a P6 PPM or P7 PAM file".to_string()),
+        };
+
+        let channels_ok = match tupltype.as_str() {
+            "GRAYSCALE" => depth == 1,
+            "GRAYSCALE_ALPHA" => depth == 2,
+            "RGB" => depth == 3,
+            "RGB_ALPHA" => depth == 4,
+            _ => return Err(format!("PAM TUPLTYPE '{}' is not supported, use GRAYSCALE, GRAYSCALE_ALPHA, RGB or RGB_ALPHA", tupltype)),
+        };
+        if !channels_ok {
+            return Err(format!("PAM DEPTH {} doesn't match TUPLTYPE {}", depth, tupltype));
+        }
+        if w == 0 || ht == 0 || w > u16::MAX as u32 || ht > u16::MAX as u32 {
+            return Err(format!("Netpbm size {}x{} is not supported", w, ht));
+        }
+        if !(1..=65535).contains(&maxval) {
+            return Err(format!("Netpbm maxval {} is not valid", maxval));
+        }
+
+        let sample_size = if maxval > 255 { 2 } else { 1 };
+        let pixel_size = depth as usize * sample_size;
+        let size = w as usize * ht as usize * pixel_size;
+        let data = bytes.get(start..start + size).ok_or_else(|| "Netpbm file is too small".to_string())?;
+
+        let sample = |s: &[u8]| -> u8 {
+            let v = if sample_size == 2 { (s[0] as u32) << 8 | s[1] as u32 } else { s[0] as u32 };
+            ((v * 255 + maxval / 2) / maxval).min(255) as u8
+        };
+        let mut img = Img::new(w, ht);
+        for (i, p) in data.chunks_exact(pixel_size).enumerate() {
+            let v: Vec<u8> = p.chunks_exact(sample_size).map(sample).collect();
+            let argb = match depth {
+                1 => [ 255, v[0], v[0], v[0] ],
+                2 => [ v[1], v[0], v[0], v[0] ],
+                3 => [ 255, v[0], v[1], v[2] ],
+                _ => [ v[3], v[0], v[1], v[2] ],
+            };
+            img.data[i * 4..i * 4 + 4].copy_from_slice(&argb);
+        }
+        Ok(img)
+    }
+
+    // P6, drawn on black
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut img = self.clone();
+        img.convert_format(ImgFormat::Argb8888);
+        let mut b: Vec<u8> = format!("P6\n{} {}\n255\n", img.w, img.h).into_bytes();
+        for p in img.data.chunks_exact(4) {
+            b.extend((1..4).map(|c| (p[c] as u32 * p[0] as u32 / 255) as u8));
+        }
+        b
+    }
+
+    // P7 RGB_ALPHA
+    pub fn to_pam(&self) -> Vec<u8> {
+        let mut img = self.clone();
+        img.convert_format(ImgFormat::Argb8888);
+        let mut b: Vec<u8> = format!("P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n", img.w, img.h).into_bytes();
+        // we store ARGB, the PAM file wants RGBA
+        for p in img.data.chunks_exact(4) {
+            b.extend([ p[1], p[2], p[3], p[0] ]);
+        }
+        b
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // opaque red, half see-through green, and clear blue, in one row
+    fn three_pixels() -> Img {
+        let mut img = Img::new(3, 1);
+        img.put_pixel(0, 0, [ 255, 255, 0, 0 ]);
+        img.put_pixel(1, 0, [ 128, 0, 200, 0 ]);
+        img.put_pixel(2, 0, [ 0, 0, 0, 255 ]);
+        img
+    }
+
+    #[test]
+    fn pam_round_trip() {
+        let img = three_pixels();
+        let b = img.to_pam();
+        assert!(b.starts_with(b"P7\nWIDTH 3\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n"));
+        assert_eq!(Img::from_netpbm(&b).unwrap().data, img.data);
+    }
+
+    #[test]
+    fn ppm_is_drawn_on_black() {
+        let b = three_pixels().to_ppm();
+        assert_eq!(b, [ b"P6\n3 1\n255\n".as_slice(), &[ 255, 0, 0,  0, 100, 0,  0, 0, 0 ] ].concat());
+        let back = Img::from_netpbm(&b).unwrap();
+        assert_eq!(back.get_pixel(1, 0), [ 255, 0, 100, 0 ]);
+        assert_eq!(back.get_pixel(2, 0), [ 255, 0, 0, 0 ]);
+    }
+
+    #[test]
+    fn maxval_is_scaled() {
+        // maxval 15, with a comment in the header
+        let b = [ b"P6 # small\n2 1 15\n".as_slice(), &[ 15, 0, 7,  1, 8, 15 ] ].concat();
+        let img = Img::from_netpbm(&b).unwrap();
+        assert_eq!(img.get_pixel(0, 0), [ 255, 255, 0, 119 ]);
+        assert_eq!(img.get_pixel(1, 0), [ 255, 17, 136, 255 ]);
+
+        // 16 bit samples are high byte first
+        let b = [ b"P6\n1 1\n65535\n".as_slice(), &[ 0xFF, 0xFF,  0x80, 0x00,  0x00, 0x80 ] ].concat();
+        assert_eq!(Img::from_netpbm(&b).unwrap().get_pixel(0, 0), [ 255, 255, 128, 0 ]);
+    }
+
+    #[test]
+    fn sixteen_bit_pam() {
+        let header = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 4\nMAXVAL 65535\nTUPLTYPE RGB_ALPHA\nENDHDR\n";
+        let pixels = [ 0xFF, 0xFF,  0, 0,  0, 0,  0xFF, 0xFF,      0, 0,  0x33, 0x33,  0, 0,  0x80, 0x00 ];
+        let img = Img::from_netpbm(&[ header.as_slice(), &pixels ].concat()).unwrap();
+        assert_eq!((img.w, img.h), (2, 1));
+        assert_eq!(img.get_pixel(0, 0), [ 255, 255, 0, 0 ]);
+        assert_eq!(img.get_pixel(1, 0), [ 128, 0, 51, 0 ]);
+    }
+
+    #[test]
+    fn grayscale_pam() {
+        let b = [ b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 2\nMAXVAL 255\nTUPLTYPE GRAYSCALE_ALPHA\nENDHDR\n".as_slice(), &[ 10, 255,  200, 0 ] ].concat();
+        let img = Img::from_netpbm(&b).unwrap();
+        assert_eq!(img.get_pixel(0, 0), [ 255, 10, 10, 10 ]);
+        assert_eq!(img.get_pixel(1, 0), [ 0, 200, 200, 200 ]);
+    }
+
+    #[test]
+    fn bad_files() {
+        for (b, error) in [
+            (b"P5\n1 1\n255\n\0".as_slice(), "Not a P6 PPM or P7 PAM file"),
+            (b"P6\n2 1\n255\n\0\0\0", "Netpbm file is too small"),
+            (b"P6\n1 1\n0\n\0\0\0", "Netpbm maxval 0 is not valid"),
+            (b"P6\n1 x\n255\n\0\0\0", "Netpbm height 'x' is not a number"),
+            (b"P6\n1", "Netpbm header is incomplete"),
+            (b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 3\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n\0\0\0\0", "PAM DEPTH 3 doesn't match TUPLTYPE RGB_ALPHA"),
+        ] {
+            assert_eq!(Img::from_netpbm(b).err().as_deref(), Some(error));
+        }
+    }
+}
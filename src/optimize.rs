@@ -0,0 +1,401 @@
+//  optimize.rs - make a watch face smaller without changing how it looks
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// Trimming crops transparent borders off the images, and moves the elements so the images stay where they were.
+// Images that share a position (day names, bar displays, weather icons, battery fill images) and digit sets,
+// which must be the same size, are cropped by one box that fits them all.
+// Digit sets used by heart_rate_num, steps_num or k_cal_num are only cropped top and bottom, because the
+// watch places those digits one digit width apart.
+// The preview is not trimmed, and neither is dash, as it has no position.
+//
+// Lossy quantisation changes pixels a little so the RLE encoding finds longer runs. Along each row, a pixel
+// that is within the tolerance of the pixel that started the current run is made the same as it. Alpha
+// close to 0 or 255 is snapped to 0 or 255 first, and fully transparent pixels are all made the same.
+
+
+use crate::elements::{*};
+use crate::face::FaceN;
+use crate::img::{Img, ImgFormat, rgb565_to_888};
+use crate::img_data::ImgData;
+use crate::render::decode;
+
+
+// x0, y0, x1, y1, with x1 and y1 exclusive
+type Bounds = (u32, u32, u32, u32);
+
+#[derive(Default)]
+pub struct TrimReport
+{
+    pub images: usize,              // images that were cropped
+    pub bytes_saved: i64,
+    pub warnings: Vec<String>,
+}
+
+// the box that holds the visible part of every image
+fn common_bounds(ids: &[&ImgData]) -> Option<Bounds> {
+    ids.iter().filter_map(|id| decode(id).opaque_bounds()).reduce(|a, b| (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3)))
+}
+
+// grow the box to hold a point
+fn include(b: Bounds, x: u32, y: u32) -> Bounds {
+    (b.0.min(x), b.1.min(y), b.2.max(x + 1), b.3.max(y + 1))
+}
+
+fn shift(v: &mut u16, d: u32) {
+    *v = (*v as u32 + d).min(u16::MAX as u32) as u16;
+}
+
+impl TrimReport
+{
+    // crop each image to the box. the box may be larger than a small image, that part is transparent.
+    fn crop(&mut self, ids: Vec<&mut ImgData>, b: Bounds) -> Result<(), String> {
+        for id in ids {
+            if b == (0, 0, id.w as u32, id.h as u32) {
+                continue;
+            }
+            let old_size = (id.header.len() + id.data.len()) as i64;
+            let img = decode(id).crop(b.0, b.1, b.2 - b.0, b.3 - b.1);
+            let file_name = id.file_name.take();
+            *id = ImgData::from_img(&img)?;
+            id.file_name = file_name;
+            self.images += 1;
+            self.bytes_saved += old_size - (id.header.len() + id.data.len()) as i64;
+        }
+        Ok(())
+    }
+
+    // crop a group of images that share a position. returns how far the top left moved.
+    fn group(&mut self, ids: Vec<&mut ImgData>, extra: &[(u32, u32)], what: &str) -> Result<Option<(u32, u32)>, String> {
+        let mut b = match common_bounds(&ids.iter().map(|id| &**id).collect::<Vec<&ImgData>>()) {
+            Some(b) => b,
+            None => {
+                if !ids.is_empty() {
+                    self.warnings.push(format!("{} is fully transparent, not trimming it", what));
+                }
+                return Ok(None);
+            },
+        };
+        // points that have to stay inside the image, like a hand pivot
+        for (x, y) in extra {
+            b = include(b, *x, *y);
+        }
+        self.crop(ids, b)?;
+        Ok(Some((b.0, b.1)))
+    }
+}
+
+#[derive(Default)]
+pub struct LossyReport
+{
+    pub images: usize,              // images that were changed
+    pub bytes_saved: i64,
+    pub max_error: u8,              // the largest change to any channel of any pixel, 0 to 255
+}
+
+// ARGB8565 pixel as [A, R, G, B]
+fn expand(p: &[u8]) -> [u8; 4] {
+    let rgb = rgb565_to_888(p[1], p[2]);
+    [ p[0], rgb[0], rgb[1], rgb[2] ]
+}
+
+// how different two ARGB8565 pixels look. the colour of fully transparent pixels doesn't matter.
+fn difference(a: &[u8], b: &[u8]) -> u8 {
+    let (a, b) = (expand(a), expand(b));
+    if a[0] == 0 && b[0] == 0 {
+        return 0;
+    }
+    (0..4).map(|c| a[c].abs_diff(b[c])).max().unwrap_or(0)
+}
+
+// quantise an Argb8565 image in place
+fn quantize(img: &mut Img, tolerance: u8, alpha_snap: u8) {
+    for p in img.data.chunks_exact_mut(3) {
+        if p[0] <= alpha_snap {
+            p.copy_from_slice(&[ 0, 0, 0 ]);
+        } else if p[0] >= 255 - alpha_snap {
+            p[0] = 255;
+        }
+    }
+    let row_size = img.w as usize * 3;
+    for row in img.data.chunks_exact_mut(row_size.max(1)) {
+        let mut start = 0;
+        for x in 1..img.w as usize {
+            let (done, rest) = row.split_at_mut(x * 3);
+            let run = &done[start * 3..start * 3 + 3];
+            if difference(run, &rest[..3]) <= tolerance {
+                rest[..3].copy_from_slice(run);
+            } else {
+                start = x;
+            }
+        }
+    }
+}
+
+impl FaceN
+{
+    // change pixels a little so the images compress better
+    pub fn quantize(&mut self, tolerance: u8, alpha_snap: u8) -> Result<LossyReport, String> {
+        let mut r = LossyReport::default();
+        for (_, id) in self.all_img_data_mut() {
+            let mut img = Img::from_img_data(id);
+            img.convert_format(ImgFormat::Argb8565);
+            let original = img.data.clone();
+            quantize(&mut img, tolerance, alpha_snap);
+            if img.data == original {
+                continue;
+            }
+            let error = original.chunks_exact(3).zip(img.data.chunks_exact(3)).map(|(a, b)| difference(a, b)).max().unwrap_or(0);
+            r.max_error = r.max_error.max(error);
+            let old_size = (id.header.len() + id.data.len()) as i64;
+            let file_name = id.file_name.take();
+            *id = ImgData::from_img(&img)?;
+            id.file_name = file_name;
+            r.images += 1;
+            r.bytes_saved += old_size - (id.header.len() + id.data.len()) as i64;
+        }
+        Ok(r)
+    }
+
+    // crop transparent borders off the images, moving the elements to match
+    pub fn trim(&mut self) -> Result<TrimReport, String> {
+        let mut r = TrimReport::default();
+
+        // digit sets that must keep their width
+        let mut keep_width: Vec<bool> = vec![ false; self.digits.len() ];
+        for el in self.elements.iter() {
+            let set = match el {
+                Element::HeartRateNum(e) => e.digit_set,
+                Element::StepsNum(e) => e.digit_set,
+                Element::KCalNum(e) => e.digit_set,
+                _ => continue,
+            };
+            if let Some(k) = keep_width.get_mut(set as usize) {
+                *k = true;
+            }
+        }
+
+        let mut digit_shifts: Vec<(u32, u32)> = vec![ (0, 0); self.digits.len() ];
+        for (n, d) in self.digits.iter_mut().enumerate() {
+            let mut b = match common_bounds(&d.img_data.iter().collect::<Vec<&ImgData>>()) {
+                Some(b) => b,
+                None => { r.warnings.push(format!("Digit set {} is fully transparent, not trimming it", n)); continue; },
+            };
+            if keep_width[n] {
+                b.0 = 0;
+                b.2 = d.img_data.iter().map(|id| id.w as u32).max().unwrap_or(0);
+            }
+            r.crop(d.img_data.iter_mut().collect(), b)?;
+            digit_shifts[n] = (b.0, b.1);
+        }
+        let digit_shift = |set: u8| digit_shifts.get(set as usize).cloned().unwrap_or((0, 0));
+
+        for (n, el) in self.elements.iter_mut().enumerate() {
+            let what = format!("Element {} ({})", n, el.type_name());
+            match el {
+                Element::Image(e) => {
+                    if let Some((dx, dy)) = r.group(vec![ &mut e.img_data ], &[], &what)? {
+                        shift(&mut e.x, dx);
+                        shift(&mut e.y, dy);
+                    }
+                },
+                Element::DayName(e) => {
+                    if let Some((dx, dy)) = r.group(e.img_data.iter_mut().collect(), &[], &what)? {
+                        shift(&mut e.x, dx);
+                        shift(&mut e.y, dy);
+                    }
+                },
+                Element::BarDisplay(e) => {
+                    if let Some((dx, dy)) = r.group(e.img_data.iter_mut().collect(), &[], &what)? {
+                        shift(&mut e.x, dx);
+                        shift(&mut e.y, dy);
+                    }
+                },
+                Element::Weather(e) => {
+                    if let Some((dx, dy)) = r.group(e.img_data.iter_mut().collect(), &[], &what)? {
+                        shift(&mut e.x, dx);
+                        shift(&mut e.y, dy);
+                    }
+                },
+                Element::BatteryFill(e) => {
+                    // the fill area is relative to the image, so it has to stay inside it
+                    let fill = [ (e.x1 as u32, e.y1 as u32), (e.x2 as u32, e.y2 as u32) ];
+                    if let Some((dx, dy)) = r.group(vec![ &mut e.img_data, &mut e.image_data1, &mut e.image_data2 ], &fill, &what)? {
+                        shift(&mut e.x, dx);
+                        shift(&mut e.y, dy);
+                        e.x1 -= dx as u8;
+                        e.y1 -= dy as u8;
+                        e.x2 -= dx as u8;
+                        e.y2 -= dy as u8;
+                    }
+                },
+                Element::TimeHand(e) => {
+                    // the pivot stays on the dial centre, so only the pivot moves
+                    let pivot = [ (e.pivot_x as u32, e.pivot_y as u32) ];
+                    if let Some((dx, dy)) = r.group(vec![ &mut e.img_data ], &pivot, &what)? {
+                        e.pivot_x -= dx as u16;
+                        e.pivot_y -= dy as u16;
+                    }
+                },
+                Element::TimeNum(e) => {
+                    for i in 0..4 {
+                        let (dx, dy) = digit_shift(e.digit_sets[i]);
+                        shift(&mut e.xys[i].x, dx);
+                        shift(&mut e.xys[i].y, dy);
+                    }
+                },
+                Element::DayNum(e) => {
+                    let (dx, dy) = digit_shift(e.digit_set);
+                    e.xys.iter_mut().for_each(|xy| { shift(&mut xy.x, dx); shift(&mut xy.y, dy); });
+                },
+                Element::MonthNum(e) => {
+                    let (dx, dy) = digit_shift(e.digit_set);
+                    e.xys.iter_mut().for_each(|xy| { shift(&mut xy.x, dx); shift(&mut xy.y, dy); });
+                },
+                Element::HeartRateNum(e) => shift(&mut e.y, digit_shift(e.digit_set).1),
+                Element::StepsNum(e) => shift(&mut e.y, digit_shift(e.digit_set).1),
+                Element::KCalNum(e) => shift(&mut e.y, digit_shift(e.digit_set).1),
+                Element::Dash(_) | Element::Unknown29(_) | Element::Unknown => {},
+            }
+        }
+        Ok(r)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+    use crate::face_builder::FaceBuilder;
+    use crate::render::RenderState;
+    use crate::xy::XY;
+
+    // a transparent image with an opaque box in it
+    fn boxed(w: u32, h: u32, b: Bounds) -> Img {
+        let mut img = Img::new(w, h);
+        for y in b.1..b.3 {
+            for x in b.0..b.2 {
+                img.put_pixel(x, y, [ 255, 255, (x * 20) as u8, (y * 5) as u8 ]);
+            }
+        }
+        img
+    }
+
+    fn render(face: &FaceN) -> Vec<u8> {
+        face.render(&RenderState::new(), false).data
+    }
+
+    #[test]
+    fn trim_keeps_the_face_looking_the_same() {
+        let mut face = FaceBuilder::new(&DEVICES[0]).preview(Img::new(4, 4))
+            .image(10, 10, boxed(20, 20, (3, 6, 8, 10)))
+            .hand(HandType::Minute, boxed(10, 50, (4, 0, 6, 40)), XY { x: 5, y: 45 })
+            .build().unwrap();
+        let before = render(&face);
+        let r = face.trim().unwrap();
+        assert_eq!((r.images, r.warnings.len()), (2, 0));
+        assert!(r.bytes_saved > 0);
+        match (&face.elements[0], &face.elements[1]) {
+            (Element::Image(i), Element::TimeHand(h)) => {
+                assert_eq!((i.x, i.y, i.img_data.w, i.img_data.h), (13, 16, 5, 4));
+                // the pivot is outside the opaque part, so the hand keeps it inside
+                assert_eq!((h.pivot_x, h.pivot_y, h.img_data.w, h.img_data.h), (1, 45, 2, 46));
+            },
+            _ => panic!("elements changed type"),
+        }
+        assert!(render(&face) == before, "the render changed");
+    }
+
+    #[test]
+    fn trim_digit_sets() {
+        // set 0 is only used by time_num, set 1 by steps_num which needs the whole width
+        let digits = || (0..10).map(|_| boxed(10, 16, (2, 4, 8, 12))).collect::<Vec<Img>>();
+        let mut face = FaceBuilder::new(&DEVICES[0]).preview(Img::new(4, 4)).digit_set(digits()).digit_set(digits())
+            .time_num(0, [ XY { x: 0, y: 0 }, XY { x: 10, y: 0 }, XY { x: 30, y: 0 }, XY { x: 40, y: 0 } ])
+            .steps_num(1, 0, 50, 50)
+            .image(0, 100, Img::new(5, 5))
+            .build().unwrap();
+        let before = render(&face);
+        let r = face.trim().unwrap();
+        assert_eq!(r.images, 20);
+        assert_eq!(r.warnings, vec![ "Element 2 (image) is fully transparent, not trimming it".to_string() ]);
+        assert_eq!((face.digits[0].img_data[0].w, face.digits[0].img_data[0].h), (6, 8));
+        assert_eq!((face.digits[1].img_data[0].w, face.digits[1].img_data[0].h), (10, 8));
+        match (&face.elements[0], &face.elements[1]) {
+            (Element::TimeNum(t), Element::StepsNum(s)) => {
+                assert_eq!((t.xys[0].x, t.xys[0].y, t.xys[3].x), (2, 4, 42));
+                assert_eq!((s.x, s.y), (50, 54));
+            },
+            _ => panic!("elements changed type"),
+        }
+        assert!(render(&face) == before, "the render changed");
+    }
+
+    // an Argb8565 row of opaque reds, from their 5 bit values
+    fn reds(values: &[u8]) -> Img {
+        let data = values.iter().flat_map(|r| [ 255, r << 3, 0 ]).collect();
+        Img { w: values.len() as u32, h: 1, format: ImgFormat::Argb8565, data, rle_header: None }
+    }
+
+    fn palette(img: &Img) -> Vec<[u8; 3]> {
+        let mut p: Vec<[u8; 3]> = img.data.chunks_exact(3).map(|c| [ c[0], c[1], c[2] ]).collect();
+        p.sort();
+        p.dedup();
+        p
+    }
+
+    #[test]
+    fn quantize_joins_close_pixels() {
+        // the reds expand to 132, 140, 148 and 198. 140 is within 8 of the run start, 148 isn't.
+        let mut img = reds(&[ 16, 17, 18, 24 ]);
+        quantize(&mut img, 8, 0);
+        assert_eq!(img.data, reds(&[ 16, 16, 18, 24 ]).data);
+        let mut img = reds(&[ 16, 17, 18, 24 ]);
+        quantize(&mut img, 16, 0);
+        assert_eq!(img.data, reds(&[ 16, 16, 16, 24 ]).data);
+        assert_eq!(palette(&img), vec![ [ 255, 128, 0 ], [ 255, 192, 0 ] ]);
+    }
+
+    #[test]
+    fn quantize_snaps_alpha() {
+        let mut img = Img { w: 4, h: 1, format: ImgFormat::Argb8565, data: vec![ 3, 0x12, 0x34, 8, 0xFF, 0xFF, 9, 0, 0, 250, 0x80, 0 ], rle_header: None };
+        quantize(&mut img, 0, 8);
+        assert_eq!(img.data, vec![ 0, 0, 0, 0, 0, 0, 9, 0, 0, 255, 0x80, 0 ]);
+    }
+
+    #[test]
+    fn quantize_face() {
+        // a gentle gradient, every pixel a little redder than the one before
+        let mut gradient = Img::new(32, 4);
+        for y in 0..4 {
+            for x in 0..32 {
+                gradient.put_pixel(x, y, [ 255, (x * 8) as u8, 0, 0 ]);
+            }
+        }
+        let mut face = FaceBuilder::new(&DEVICES[0]).preview(Img::new(4, 4)).image(0, 0, gradient).build().unwrap();
+        let colours = |face: &mut FaceN| {
+            let mut img = Img::from_img_data(face.all_img_data_mut()[1].1);
+            img.convert_format(ImgFormat::Argb8565);
+            palette(&img).len()
+        };
+        assert_eq!(colours(&mut face), 32);
+        let r = face.quantize(16, 0).unwrap();
+        assert_eq!(r.images, 1);
+        assert!(r.bytes_saved > 0);
+        assert!(r.max_error > 0 && r.max_error <= 16, "{}", r.max_error);
+        assert_eq!(colours(&mut face), 12);
+        match &face.elements[0] {
+            Element::Image(e) => assert_eq!((e.img_data.w, e.img_data.h), (32, 4)),
+            _ => panic!("element changed type"),
+        }
+        // nothing more to join
+        assert_eq!(face.quantize(0, 0).unwrap().images, 0);
+    }
+}
@@ -0,0 +1,310 @@
+//  ora.rs - export and import OpenRaster layered images, for GIMP and Krita
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// An .ora file is a zip of PNG layers, and stack.xml which says where each layer goes.
+//
+// Every image of the face is a layer, named "N TYPE I": the element number, its e_type and the image number,
+// e.g. "3 day_name 2". The digit sets are "digits N I" and the preview is "preview". Layers that the face
+// doesn't show at once (the other day names, the digits, the preview) are hidden.
+//
+// Importing matches the layers by name. Changed pixels replace the image, and moving the first layer of an
+// element moves the element. Time hand layers are placed so the pivot is on the dial centre.
+
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::elements::Element;
+use crate::face::FaceN;
+use crate::img::{Img, ResizeFilter};
+use crate::img_data::ImgData;
+use crate::render::decode;
+use crate::render::RenderState;
+
+
+const THUMBNAIL_SIZE: u32 = 256;
+
+// where one image of the face goes in the layered image
+struct LayerInfo
+{
+    name: String,
+    element: Option<usize>,         // the element it positions, for the first image of an element
+    x: i32,
+    y: i32,
+    visible: bool,
+}
+
+// the top left of an element's images on the screen
+fn position(el: &Element) -> Option<(i32, i32)> {
+    match el {
+        Element::Image(e) => Some((e.x as i32, e.y as i32)),
+        Element::DayName(e) => Some((e.x as i32, e.y as i32)),
+        Element::BatteryFill(e) => Some((e.x as i32, e.y as i32)),
+        Element::TimeHand(e) => Some((e.x as i32 - e.pivot_x as i32, e.y as i32 - e.pivot_y as i32)),
+        Element::BarDisplay(e) => Some((e.x as i32, e.y as i32)),
+        Element::Weather(e) => Some((e.x as i32, e.y as i32)),
+        _ => None,
+    }
+}
+
+fn set_position(el: &mut Element, x: i32, y: i32) -> Result<(), String> {
+    let (x, y) = match el {
+        Element::TimeHand(e) => (x + e.pivot_x as i32, y + e.pivot_y as i32),
+        _ => (x, y),
+    };
+    if x < 0 || y < 0 || x > u16::MAX as i32 || y > u16::MAX as i32 {
+        return Err(format!("{} can't be at {},{}", el.type_name(), x, y));
+    }
+    let (x, y) = (x as u16, y as u16);
+    match el {
+        Element::Image(e) => { e.x = x; e.y = y; },
+        Element::DayName(e) => { e.x = x; e.y = y; },
+        Element::BatteryFill(e) => { e.x = x; e.y = y; },
+        Element::TimeHand(e) => { e.x = x; e.y = y; },
+        Element::BarDisplay(e) => { e.x = x; e.y = y; },
+        Element::Weather(e) => { e.x = x; e.y = y; },
+        _ => {},
+    }
+    Ok(())
+}
+
+fn img_count(el: &Element) -> usize {
+    match el {
+        Element::Image(_) | Element::TimeHand(_) | Element::Dash(_) => 1,
+        Element::BatteryFill(_) => 3,
+        Element::DayName(e) => e.img_data.len(),
+        Element::BarDisplay(e) => e.img_data.len(),
+        Element::Weather(e) => e.img_data.len(),
+        _ => 0,
+    }
+}
+
+// one LayerInfo for each image, in the same order as FaceN::all_img_data_mut()
+fn layer_infos(face: &FaceN) -> Vec<LayerInfo> {
+    let mut v: Vec<LayerInfo> = Vec::new();
+    let hidden = |name: String| LayerInfo { name, element: None, x: 0, y: 0, visible: false };
+    v.push(hidden("preview".to_string()));
+    for (n, d) in face.digits.iter().enumerate() {
+        v.extend((0..d.img_data.len()).map(|i| hidden(format!("digits {} {}", n, i))));
+    }
+    for (n, el) in face.elements.iter().enumerate() {
+        // elements without a position, like dash, can't be moved
+        let pos = position(el);
+        let (x, y) = pos.unwrap_or((0, 0));
+        for i in 0..img_count(el) {
+            let element = if i == 0 && pos.is_some() { Some(n) } else { None };
+            v.push(LayerInfo { name: format!("{} {} {}", n, el.type_name(), i), element, x, y, visible: i == 0 });
+        }
+    }
+    v
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+// the attributes of every <layer> in stack.xml. layers in groups are included, the group offsets are not.
+fn parse_layers(xml: &str) -> Vec<HashMap<String, String>> {
+    let mut layers: Vec<HashMap<String, String>> = Vec::new();
+    for part in xml.split("<layer").skip(1) {
+        if !part.starts_with(|c: char| c.is_whitespace() || c == '/' || c == '>') {
+            continue;
+        }
+        let tag = &part[..part.find('>').unwrap_or(part.len())];
+        let mut attrs: HashMap<String, String> = HashMap::new();
+        let mut rest = tag;
+        while let Some(eq) = rest.find('=') {
+            let name = rest[..eq].trim().to_string();
+            let after = rest[eq + 1..].trim_start();
+            let quote = match after.chars().next() {
+                Some(q) if q == '"' || q == '\'' => q,
+                _ => break,
+            };
+            let value_end = match after[1..].find(quote) {
+                Some(e) => e + 1,
+                None => break,
+            };
+            attrs.insert(name, unescape(&after[1..value_end]));
+            rest = &after[value_end + 1..];
+        }
+        layers.push(attrs);
+    }
+    layers
+}
+
+fn add_file(zip: &mut ZipWriter<Cursor<Vec<u8>>>, name: &str, data: &[u8], method: CompressionMethod) -> Result<(), String> {
+    zip.start_file(name, SimpleFileOptions::default().compression_method(method)).map_err(|e| e.to_string())?;
+    zip.write_all(data).map_err(|e| e.to_string())
+}
+
+pub fn export(face: &FaceN) -> Result<Vec<u8>, String> {
+    let (w, h) = face.screen_size();
+    let infos = layer_infos(face);
+    let mut face = face.clone();
+    let imgs = face.all_img_data_mut();
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    // the mimetype must come first, and not be compressed
+    add_file(&mut zip, "mimetype", b"image/openraster", CompressionMethod::Stored)?;
+
+    let mut stack = String::new();
+    stack += "<?xml version='1.0' encoding='UTF-8'?>\n";
+    stack += &format!("<image version=\"0.0.5\" w=\"{}\" h=\"{}\">\n <stack>\n", w, h);
+    // stack.xml lists the top layer first. the elements are drawn in order, so the last is on top.
+    // the hidden layers go at the bottom.
+    let mut order: Vec<usize> = (0..infos.len()).filter(|n| infos[*n].visible).rev().collect();
+    order.extend((0..infos.len()).filter(|n| !infos[*n].visible).rev());
+    for n in order {
+        let info = &infos[n];
+        let src = format!("data/layer{}.png", n);
+        let png = decode(imgs[n].1).to_png()?;
+        add_file(&mut zip, &src, &png, CompressionMethod::Stored)?;
+        stack += &format!("  <layer name=\"{}\" src=\"{}\" x=\"{}\" y=\"{}\" visibility=\"{}\" opacity=\"1.0\"/>\n",
+            escape(&info.name), src, info.x, info.y, if info.visible { "visible" } else { "hidden" });
+    }
+    stack += " </stack>\n</image>\n";
+    add_file(&mut zip, "stack.xml", stack.as_bytes(), CompressionMethod::Deflated)?;
+
+    let merged = face.render(&RenderState::new(), false);
+    add_file(&mut zip, "mergedimage.png", &merged.to_png()?, CompressionMethod::Stored)?;
+    let scale = (THUMBNAIL_SIZE as f32 / w.max(h) as f32).min(1.0);
+    let thumbnail = merged.resize(((w as f32 * scale) as u32).max(1), ((h as f32 * scale) as u32).max(1), ResizeFilter::Bilinear);
+    add_file(&mut zip, "Thumbnails/thumbnail.png", &thumbnail.to_png()?, CompressionMethod::Stored)?;
+
+    let cursor = zip.finish().map_err(|e| e.to_string())?;
+    Ok(cursor.into_inner())
+}
+
+#[derive(Default)]
+pub struct ImportReport
+{
+    pub changed: Vec<usize>,        // images with new pixels, as indexes into FaceN::all_img_data_mut()
+    pub moved: usize,               // elements with a new position
+    pub warnings: Vec<String>,
+}
+
+// update the images and element positions of the face from an .ora file
+pub fn import(face: &mut FaceN, ora: &[u8]) -> Result<ImportReport, String> {
+    let mut zip = ZipArchive::new(Cursor::new(ora)).map_err(|e| format!("Not an OpenRaster file: {}", e))?;
+    let mut read = |name: &str| -> Result<Vec<u8>, String> {
+        let mut file = zip.by_name(name).map_err(|e| format!("Unable to find '{}' in the OpenRaster file: {}", name, e))?;
+        let mut data: Vec<u8> = Vec::new();
+        file.read_to_end(&mut data).map_err(|e| format!("Unable to read '{}' from the OpenRaster file: {}", name, e))?;
+        Ok(data)
+    };
+    let stack = String::from_utf8(read("stack.xml")?).map_err(|_| "stack.xml is not UTF-8".to_string())?;
+    let mut layers: HashMap<String, HashMap<String, String>> = HashMap::new();
+    for attrs in parse_layers(&stack) {
+        if let Some(name) = attrs.get("name").cloned() {
+            layers.insert(name, attrs);
+        }
+    }
+
+    let mut report = ImportReport::default();
+    let infos = layer_infos(face);
+    let mut positions: Vec<(usize, i32, i32)> = Vec::new();
+    for (n, (info, (_, id))) in infos.iter().zip(face.all_img_data_mut()).enumerate() {
+        let attrs = match layers.remove(&info.name) {
+            Some(a) => a,
+            None => { report.warnings.push(format!("No layer '{}', keeping the image", info.name)); continue; },
+        };
+        let coord = |key: &str| attrs.get(key).and_then(|v| v.trim().parse::<i32>().ok()).unwrap_or(0);
+        let (x, y) = (coord("x"), coord("y"));
+        if let Some(n) = info.element {
+            if (x, y) != (info.x, info.y) {
+                positions.push((n, x, y));
+            }
+        } else if (x, y) != (info.x, info.y) {
+            report.warnings.push(format!("Layer '{}' was moved, but only the first layer of an element with a position can move it", info.name));
+        }
+
+        let src = attrs.get("src").ok_or_else(|| format!("Layer '{}' has no src", info.name))?;
+        let png = read(src)?;
+        let img = Img::from_png(&png).map_err(|e| format!("Unable to understand PNG '{}' of layer '{}': {}", src, info.name, e))?;
+        if img.w > u16::MAX as u32 || img.h > u16::MAX as u32 {
+            return Err(format!("Layer '{}' is too large", info.name));
+        }
+        let old = decode(id);
+        if (img.w, img.h) != (old.w, old.h) || img.data != old.data {
+            let (file_name, embedded) = (id.file_name.clone(), id.embedded.as_ref().map(|e| e.format));
+            *id = ImgData::from_img(&img)?;
+            id.file_name = file_name;
+            // an embedded image stays embedded, in the same format
+            if let Some(format) = embedded {
+                id.embed(format)?;
+            }
+            report.changed.push(n);
+        }
+    }
+    let mut extra: Vec<&String> = layers.keys().collect();
+    extra.sort();
+    for name in extra {
+        report.warnings.push(format!("Ignoring layer '{}', it isn't part of the face", name));
+    }
+
+    for (n, x, y) in positions {
+        set_position(&mut face.elements[n], x, y)?;
+        report.moved += 1;
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+    use crate::face_builder::FaceBuilder;
+    use crate::img_data::EmbedFormat;
+
+    fn solid(w: u32, h: u32, argb: [u8; 4]) -> Img {
+        let mut img = Img::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                img.put_pixel(x, y, argb);
+            }
+        }
+        img
+    }
+
+    fn face(argb: [u8; 4]) -> FaceN {
+        FaceBuilder::new(&DEVICES[0]).preview(solid(4, 4, [255, 0, 0, 0])).image(10, 20, solid(8, 8, argb)).build().unwrap()
+    }
+
+    // dump --embed png, then import-ora, then pack
+    #[test]
+    fn embedded_image_stays_embedded() {
+        let red = face([255, 255, 0, 0]);
+        let mut blue = face([255, 0, 0, 255]);
+        for (_, id) in blue.all_img_data_mut() {
+            id.embed(EmbedFormat::Png).unwrap();
+        }
+
+        let report = import(&mut blue, &export(&red).unwrap()).unwrap();
+        assert_eq!(report.changed, vec![ 1 ]);
+
+        // as it would be read back from watchface.json
+        let mut face: FaceN = serde_json::from_str(&serde_json::to_string(&blue).unwrap()).unwrap();
+        let mut red = red;
+        for ((_, id), (_, want)) in face.all_img_data_mut().into_iter().zip(red.all_img_data_mut()) {
+            assert_eq!(id.embedded.as_ref().map(|e| e.format), Some(EmbedFormat::Png));
+            id.read_img("").unwrap();
+            assert_eq!(decode(id).data, decode(want).data);
+        }
+        face.to_bin().unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+//  pack_cache.rs - keep encoded images between packs, so only changed images are encoded again
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// The cache is a JSON file in the dump folder. Each entry is an RLE encoded image, keyed by a SHA-256 of
+// everything the encoding depends on: the source file (or embedded data), its format, the raw sidecar,
+// the w and h from watchface.json, and the encoder version.
+// Entries that weren't used by a pack are dropped when it is saved, so the file doesn't grow forever.
+// BIN files and embedded RLE images are already encoded, so they are not cached.
+
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::face::FaceN;
+use crate::img_data::{DumpFormat, EmbedFormat, ImgData};
+use crate::raw::sidecar_path;
+use crate::sane_file_name::file_path;
+use crate::util::{from_base64, to_base64, to_hex};
+
+
+pub const CACHE_FILE_NAME: &str = ".extrathundertool-cache.json";
+
+// change this when the RLE encoder changes, so old entries aren't used
+const ENCODER_VERSION: &str = "rle_new 1";
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CacheEntry {
+    w: u16,
+    h: u16,
+    rle: String,                    // base64 of the header and data
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PackCache {
+    encoder: String,
+    entries: HashMap<String, CacheEntry>,
+    #[serde(skip)]
+    used: HashSet<String>,
+    #[serde(skip)]
+    pub hits: usize,
+    #[serde(skip)]
+    pub encoded: usize,
+}
+
+impl PackCache {
+    fn path(folder_name: &str) -> PathBuf {
+        [ folder_name, CACHE_FILE_NAME ].iter().collect()
+    }
+
+    // an empty cache if there isn't one, or it is from another version
+    pub fn load(folder_name: &str) -> PackCache {
+        let empty = PackCache { encoder: ENCODER_VERSION.to_string(), ..Default::default() };
+        let data = match fs::read(Self::path(folder_name)) {
+            Ok(d) => d,
+            Err(_) => return empty,
+        };
+        match serde_json::from_slice::<PackCache>(&data) {
+            Ok(c) if c.encoder == ENCODER_VERSION => c,
+            _ => empty,
+        }
+    }
+
+    pub fn save(&mut self, folder_name: &str) -> Result<(), String> {
+        let used = &self.used;
+        self.entries.retain(|k, _| used.contains(k));
+        let json_data = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        fs::write(Self::path(folder_name), json_data).map_err(|e| format!("Unable to save '{}': {}", CACHE_FILE_NAME, e))
+    }
+
+    // the key for an image, or None if it shouldn't be cached
+    fn key(id: &ImgData, folder_name: &str) -> Option<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(ENCODER_VERSION);
+        hasher.update(id.w.to_le_bytes());
+        hasher.update(id.h.to_le_bytes());
+        if let Some(e) = &id.embedded {
+            if e.format == EmbedFormat::Rle {
+                return None;
+            }
+            hasher.update(format!("embedded {:?}", e.format));
+            hasher.update(&e.data);
+        } else {
+            let file_name = id.file_name.as_ref()?;
+            let path = file_path(folder_name, file_name).ok()?;
+            let format = DumpFormat::from_file_name(file_name).unwrap_or(DumpFormat::BMP);
+            if format == DumpFormat::BIN {
+                return None;
+            }
+            let (_, extn) = file_name.rsplit_once('.').unwrap_or(("", ""));
+            hasher.update(format!("file {}", extn.to_lowercase()));
+            // a missing file isn't cached, so read_img reports it
+            hasher.update(fs::read(&path).ok()?);
+            if let DumpFormat::RAW(_) = format {
+                hasher.update(fs::read(sidecar_path(&path)).unwrap_or_default());
+            }
+        }
+        Some(to_hex(&hasher.finalize()))
+    }
+
+    // read an image, from the cache if it hasn't changed
+    pub fn read_img(&mut self, id: &mut ImgData, folder_name: &str) -> Result<(), String> {
+        let key = match Self::key(id, folder_name) {
+            Some(k) => k,
+            None => return id.read_img(folder_name),
+        };
+        self.used.insert(key.clone());
+        if let Some(entry) = self.entries.get(&key) {
+            if let Ok(b) = from_base64(&entry.rle) {
+                let header_size = entry.h as usize * 4;
+                if b.len() >= header_size {
+                    id.w = entry.w;
+                    id.h = entry.h;
+                    id.header = b[..header_size].to_vec();
+                    id.data = b[header_size..].to_vec();
+                    self.hits += 1;
+                    return Ok(());
+                }
+            }
+        }
+        id.read_img(folder_name)?;
+        self.encoded += 1;
+        let mut b = id.header.clone();
+        b.extend(id.data.iter());
+        self.entries.insert(key, CacheEntry { w: id.w, h: id.h, rle: to_base64(&b) });
+        Ok(())
+    }
+}
+
+impl FaceN
+{
+    // like read_imgs, but only images that changed since the last pack are encoded
+    pub fn read_imgs_cached(&mut self, folder_name: &str, cache: &mut PackCache) -> Result<(), String> {
+        for (_, id) in self.all_img_data_mut() {
+            cache.read_img(id, folder_name)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::device::DEVICES;
+    use crate::elements::Element;
+    use crate::face_builder::FaceBuilder;
+    use crate::img::Img;
+    use crate::raw::RawLayout;
+    use crate::Output;
+
+    fn temp_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("extrathundertool-cache-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    fn pattern(w: u32, h: u32, seed: u8) -> Img {
+        let mut img = Img::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                img.put_pixel(x, y, [ 255, seed, (x * 40) as u8, (y * 40) as u8 ]);
+            }
+        }
+        img
+    }
+
+    // a dumped face with two images, the preview and an image element
+    fn dump(folder: &Path, format: DumpFormat) -> FaceN {
+        let mut face = FaceBuilder::new(&DEVICES[0]).preview(pattern(4, 4, 1)).image(0, 0, pattern(6, 5, 2)).build().unwrap();
+        face.generate_file_names(&format, false);
+        face.write_imgs(folder.to_str().unwrap(), &format).unwrap();
+        fs::write(folder.join("watchface.json"), serde_json::to_string(&face).unwrap()).unwrap();
+        face
+    }
+
+    // read the images like pack does, returning the hits and the images encoded
+    fn pack(folder: &Path, face: &FaceN) -> (usize, usize) {
+        let folder_name = folder.to_str().unwrap();
+        let mut face = face.clone();
+        let mut cache = PackCache::load(folder_name);
+        face.read_imgs_cached(folder_name, &mut cache).unwrap();
+        cache.save(folder_name).unwrap();
+        (cache.hits, cache.encoded)
+    }
+
+    fn preview_path(folder: &Path, face: &FaceN) -> PathBuf {
+        folder.join(face.preview_img_data.file_name.as_ref().unwrap())
+    }
+
+    #[test]
+    fn unchanged_images_come_from_the_cache() {
+        let folder = temp_folder("hits");
+        let face = dump(&folder, DumpFormat::BMP);
+        assert_eq!(pack(&folder, &face), (0, 2));
+        assert_eq!(pack(&folder, &face), (2, 0));
+
+        // a changed file
+        fs::write(preview_path(&folder, &face), pattern(4, 4, 3).to_bmp()).unwrap();
+        assert_eq!(pack(&folder, &face), (1, 1));
+        assert_eq!(pack(&folder, &face), (2, 0));
+
+        // a changed size in watchface.json
+        let mut resized = face.clone();
+        if let Element::Image(e) = &mut resized.elements[0] {
+            e.img_data.w = 7;
+        }
+        assert_eq!(pack(&folder, &resized), (1, 1));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn changed_sidecar_is_encoded_again() {
+        let folder = temp_folder("sidecar");
+        let face = dump(&folder, DumpFormat::RAW(RawLayout::Argb8888));
+        assert_eq!(pack(&folder, &face), (0, 2));
+        assert_eq!(pack(&folder, &face), (2, 0));
+        fs::write(sidecar_path(&preview_path(&folder, &face)), r#"{"w":4,"h":4,"format":"rgba8888"}"#).unwrap();
+        assert_eq!(pack(&folder, &face), (1, 1));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn other_encoder_version_is_ignored() {
+        let folder = temp_folder("version");
+        let face = dump(&folder, DumpFormat::BMP);
+        assert_eq!(pack(&folder, &face), (0, 2));
+        let path = folder.join(CACHE_FILE_NAME);
+        let mut cache: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        cache["encoder"] = "rle_new 0".into();
+        fs::write(&path, cache.to_string()).unwrap();
+        assert_eq!(pack(&folder, &face), (0, 2));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn corrupt_cache_is_ignored() {
+        let folder = temp_folder("corrupt");
+        let face = dump(&folder, DumpFormat::BMP);
+        let path = folder.join(CACHE_FILE_NAME);
+        fs::write(&path, "{ not json").unwrap();
+        assert_eq!(pack(&folder, &face), (0, 2));
+
+        // entries that aren't base64, or are too short for their height
+        let mut cache: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        for (n, entry) in cache["entries"].as_object_mut().unwrap().values_mut().enumerate() {
+            entry["rle"] = if n == 0 { "@@".into() } else { to_base64(&[ 1, 2, 3 ]).into() };
+        }
+        fs::write(&path, cache.to_string()).unwrap();
+        assert_eq!(pack(&folder, &face), (0, 2));
+        assert_eq!(pack(&folder, &face), (2, 0));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn json_file_has_no_cache() {
+        let folder = temp_folder("json");
+        dump(&folder, DumpFormat::BMP);
+        let mut out = Output { json: true, debug: 0, warnings: Vec::new() };
+        let loaded = crate::load_folder(&mut out, folder.join("watchface.json").to_str().unwrap(), true).unwrap();
+        assert!(loaded.cache.is_none());
+        assert!(!folder.join(CACHE_FILE_NAME).exists());
+        let loaded = crate::load_folder(&mut out, folder.to_str().unwrap(), true).unwrap();
+        assert!(loaded.cache.is_some());
+        assert!(folder.join(CACHE_FILE_NAME).exists());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
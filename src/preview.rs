@@ -0,0 +1,121 @@
+//  preview.rs - make the preview image from the face, or check it still looks like the face
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// The preview is the face rendered with RenderState::new() (10:08 with sample sensor values), scaled down to
+// the size of the current preview. It is shrunk with bicubic, which averages every pixel, so fine lines don't alias. Checking compares that with the current preview, drawn on black as the
+// watch shows it. Hands or numbers showing a different time only change a few pixels, a preview of
+// another design changes most of them.
+
+
+use crate::face::FaceN;
+use crate::img::{ColourError, Img, ImgFormat, ResizeFilter};
+use crate::img_data::ImgData;
+use crate::render::RenderState;
+
+
+// the mean error, 0 to 255, above which the preview is too different from the face
+pub const PREVIEW_TOLERANCE: f32 = 12.0;
+
+impl FaceN
+{
+    // the size the preview should be. a face without one gets half the screen size.
+    fn preview_size(&self) -> (u32, u32) {
+        let id = &self.preview_img_data;
+        if id.w > 0 && id.h > 0 {
+            return (id.w as u32, id.h as u32);
+        }
+        let (w, h) = self.screen_size();
+        ((w as u32 / 2).max(1), (h as u32 / 2).max(1))
+    }
+
+    pub fn render_preview(&self) -> Img {
+        let (w, h) = self.preview_size();
+        let (sw, sh) = self.screen_size();
+        let full = self.render(&RenderState::new(), false);
+        let filter = if w >= sw as u32 && h >= sh as u32 { ResizeFilter::Nearest } else { ResizeFilter::Bicubic };
+        full.resize(w, h, filter)
+    }
+
+    // replace the preview with a render of the face. the file name stays, so a dump writes it to the same file,
+    // and an embedded preview stays embedded in the same format.
+    pub fn make_preview(&mut self) -> Result<(), String> {
+        let img = self.render_preview();
+        let file_name = self.preview_img_data.file_name.take();
+        let embedded = self.preview_img_data.embedded.as_ref().map(|e| e.format);
+        self.preview_img_data = ImgData::from_img(&img)?;
+        self.preview_img_data.file_name = file_name;
+        if let Some(format) = embedded {
+            self.preview_img_data.embed(format)?;
+        }
+        Ok(())
+    }
+
+    // how different the preview is from a render of the face
+    pub fn check_preview(&self) -> ColourError {
+        let render = self.render_preview().simulate_device();
+        let mut current = Img::from_img_data(&self.preview_img_data);
+        current.convert_format(ImgFormat::Argb8888);
+        if (current.w, current.h) != (render.w, render.h) {
+            return ColourError { max: 255, mean: 255.0, changed: (render.w * render.h) as usize };
+        }
+        let mut on_black = Img::new(current.w, current.h);
+        for y in 0..on_black.h {
+            for x in 0..on_black.w {
+                on_black.put_pixel(x, y, [255, 0, 0, 0]);
+            }
+        }
+        on_black.draw(&current, 0, 0);
+        render.error_heatmap(&on_black).1
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+    use crate::face_builder::FaceBuilder;
+    use crate::img_data::EmbedFormat;
+
+    // a white line every 4 pixels on black, a quarter white overall
+    fn lines(w: u32, h: u32) -> Img {
+        let mut img = Img::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let v = if x % 4 == 0 { 255 } else { 0 };
+                img.put_pixel(x, y, [ 255, v, v, v ]);
+            }
+        }
+        img
+    }
+
+    #[test]
+    fn shrinking_averages_fine_lines() {
+        let face = FaceBuilder::new(&DEVICES[0]).preview(Img::new(60, 74)).background(lines(240, 296)).build().unwrap();
+        let preview = face.render_preview();
+        assert_eq!((preview.w, preview.h), (60, 74));
+        for x in 1..59 {
+            let p = preview.get_pixel(x, 30);
+            assert!((p[1] as i32 - 64).abs() <= 8, "pixel {} is {:?}", x, p);
+        }
+    }
+
+    #[test]
+    fn made_preview_stays_embedded() {
+        let mut face = FaceBuilder::new(&DEVICES[0]).preview(Img::new(60, 74)).background(lines(240, 296)).build().unwrap();
+        face.preview_img_data.embed(EmbedFormat::Png).unwrap();
+        assert!(face.check_preview().mean > PREVIEW_TOLERANCE);
+        face.make_preview().unwrap();
+        let id = &face.preview_img_data;
+        assert_eq!((id.w, id.h), (60, 74));
+        assert_eq!(id.embedded.as_ref().map(|e| e.format), Some(EmbedFormat::Png));
+        assert!(face.check_preview().mean < 1.0);
+    }
+}
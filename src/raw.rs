@@ -0,0 +1,256 @@
+//  raw.rs - uncompressed pixel dumps, and the sidecar file that describes them
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// A raw file is just the pixels, row by row from the top, so other tools can use it directly.
+// Next to each one is a sidecar, e.g. image_0.raw.json, with the width, height and pixel layout:
+//  { "w": 240, "h": 296, "format": "argb8565" }
+//
+// Layouts:
+//  argb8565    3 bytes: alpha, then RGB565 high byte first. what the watch uses.
+//  rgb565le    2 bytes, low byte first. no alpha, so the image is drawn on black.
+//  rgb565be    2 bytes, high byte first. no alpha, so the image is drawn on black.
+//  argb8888    4 bytes: A, R, G, B
+//  rgba8888    4 bytes: R, G, B, A
+//
+// Raw files without a sidecar (from older versions) are the size in watchface.json, and the layout is guessed
+// from the file size. That is argb8565 for files this tool wrote.
+
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+use crate::img::{Img, ImgFormat, rgb565_to_888, rgb888_to_565};
+
+
+#[derive(Serialize, Deserialize, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RawLayout {
+    Argb8565,
+    Rgb565Le,
+    Rgb565Be,
+    Argb8888,
+    Rgba8888,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct RawInfo {
+    pub w: u16,
+    pub h: u16,
+    pub format: RawLayout,
+}
+
+impl RawLayout {
+    pub fn from_name(name: &str) -> Result<RawLayout, String> {
+        match name.to_lowercase().as_str() {
+            "argb8565" => Ok(RawLayout::Argb8565),
+            "rgb565le" => Ok(RawLayout::Rgb565Le),
+            "rgb565be" => Ok(RawLayout::Rgb565Be),
+            "argb8888" => Ok(RawLayout::Argb8888),
+            "rgba8888" => Ok(RawLayout::Rgba8888),
+            _ => Err(format!("Unknown raw layout '{}', expected argb8565, rgb565le, rgb565be, argb8888 or rgba8888", name)),
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            RawLayout::Rgb565Le | RawLayout::Rgb565Be => 2,
+            RawLayout::Argb8565 => 3,
+            RawLayout::Argb8888 | RawLayout::Rgba8888 => 4,
+        }
+    }
+
+    pub fn encode(&self, img: &Img) -> Vec<u8> {
+        let mut img = img.clone();
+        if *self == RawLayout::Argb8565 {
+            img.convert_format(ImgFormat::Argb8565);
+            return img.data;
+        }
+        img.convert_format(ImgFormat::Argb8888);
+        let mut b: Vec<u8> = Vec::with_capacity(img.w as usize * img.h as usize * self.bytes_per_pixel());
+        for p in img.data.chunks_exact(4) {
+            match self {
+                RawLayout::Rgb565Le | RawLayout::Rgb565Be => {
+                    // the watch screen is black underneath
+                    let on_black: Vec<u8> = (1..4).map(|c| (p[c] as u32 * p[0] as u32 / 255) as u8).collect();
+                    let rgb565 = rgb888_to_565(&on_black);
+                    if *self == RawLayout::Rgb565Le {
+                        b.extend([ rgb565[1], rgb565[0] ]);
+                    } else {
+                        b.extend(rgb565);
+                    }
+                },
+                RawLayout::Argb8888 => b.extend(p),
+                RawLayout::Rgba8888 => b.extend([ p[1], p[2], p[3], p[0] ]),
+                RawLayout::Argb8565 => {},
+            }
+        }
+        b
+    }
+
+    pub fn decode(&self, data: &[u8], w: u16, h: u16) -> Result<Img, String> {
+        let expected = w as usize * h as usize * self.bytes_per_pixel();
+        if data.len() != expected {
+            return Err(format!("{} bytes is the wrong size for {}x{} {:?}, expected {}", data.len(), w, h, self, expected));
+        }
+        if *self == RawLayout::Argb8565 {
+            return Ok(Img { w: w as u32, h: h as u32, format: ImgFormat::Argb8565, data: data.to_vec(), rle_header: None });
+        }
+        let mut img = Img::new(w as u32, h as u32);
+        for (i, p) in data.chunks_exact(self.bytes_per_pixel()).enumerate() {
+            let argb = match self {
+                RawLayout::Rgb565Le => { let c = rgb565_to_888(p[1], p[0]); [ 255, c[0], c[1], c[2] ] },
+                RawLayout::Rgb565Be => { let c = rgb565_to_888(p[0], p[1]); [ 255, c[0], c[1], c[2] ] },
+                RawLayout::Argb8888 => [ p[0], p[1], p[2], p[3] ],
+                RawLayout::Rgba8888 => [ p[3], p[0], p[1], p[2] ],
+                RawLayout::Argb8565 => [ 0, 0, 0, 0 ],
+            };
+            img.data[i * 4..i * 4 + 4].copy_from_slice(&argb);
+        }
+        Ok(img)
+    }
+}
+
+// a guess at the layout of a raw file without a sidecar, from its size. 4 bytes per pixel could be either order.
+pub fn detect(len: usize, w: u16, h: u16) -> Option<RawLayout> {
+    let pixels = w as usize * h as usize;
+    match len {
+        _ if len == pixels * 3 => Some(RawLayout::Argb8565),
+        _ if len == pixels * 2 => Some(RawLayout::Rgb565Le),
+        _ if len == pixels * 4 => Some(RawLayout::Argb8888),
+        _ => None,
+    }
+}
+
+// image_0.raw -> image_0.raw.json
+pub fn sidecar_path(path: &Path) -> std::path::PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(".json");
+    s.into()
+}
+
+pub fn write_sidecar(path: &Path, info: &RawInfo) -> Result<(), String> {
+    let json_data = serde_json::to_string(info).map_err(|e| e.to_string())?;
+    let sidecar = sidecar_path(path);
+    fs::write(&sidecar, json_data).map_err(|e| format!("Unable to save '{}': {}", sidecar.display(), e))
+}
+
+// the sidecar for a raw file, if there is one
+pub fn read_sidecar(path: &Path) -> Result<Option<RawInfo>, String> {
+    let sidecar = sidecar_path(path);
+    if !sidecar.exists() {
+        return Ok(None);
+    }
+    let data = fs::read(&sidecar).map_err(|e| format!("Unable to read '{}': {}", sidecar.display(), e))?;
+    serde_json::from_slice(&data).map(Some).map_err(|e| format!("Unable to understand '{}': {}", sidecar.display(), e))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+    use crate::face::FaceN;
+    use crate::face_builder::FaceBuilder;
+    use crate::img_data::{DumpFormat, ImgData};
+
+    const LAYOUTS: [RawLayout; 5] = [ RawLayout::Argb8565, RawLayout::Rgb565Le, RawLayout::Rgb565Be, RawLayout::Argb8888, RawLayout::Rgba8888 ];
+
+    // opaque red, then half transparent green
+    fn two_pixels() -> Img {
+        let mut img = Img::new(2, 1);
+        img.put_pixel(0, 0, [ 255, 255, 0, 0 ]);
+        img.put_pixel(1, 0, [ 128, 0, 255, 0 ]);
+        img
+    }
+
+    #[test]
+    fn layouts() {
+        let img = two_pixels();
+        assert_eq!(RawLayout::Argb8565.encode(&img), [ 255, 0xF8, 0x00, 128, 0x07, 0xE0 ]);
+        // no alpha, so the green is drawn on black: 128 is 0x20 in 6 bits
+        assert_eq!(RawLayout::Rgb565Le.encode(&img), [ 0x00, 0xF8, 0x00, 0x04 ]);
+        assert_eq!(RawLayout::Rgb565Be.encode(&img), [ 0xF8, 0x00, 0x04, 0x00 ]);
+        assert_eq!(RawLayout::Argb8888.encode(&img), [ 255, 255, 0, 0, 128, 0, 255, 0 ]);
+        assert_eq!(RawLayout::Rgba8888.encode(&img), [ 255, 0, 0, 255, 0, 255, 0, 128 ]);
+        for layout in LAYOUTS {
+            assert_eq!(layout.encode(&img).len(), 2 * layout.bytes_per_pixel());
+            assert_eq!(RawLayout::from_name(&serde_json::to_value(layout).unwrap().as_str().unwrap().to_uppercase()), Ok(layout));
+        }
+        assert!(RawLayout::from_name("rgb888").is_err());
+    }
+
+    // any bytes decode to an image that encodes back to the same bytes
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..48).map(|i| (i * 37 + 11) as u8).collect();
+        for layout in LAYOUTS {
+            let (w, h) = (12 / layout.bytes_per_pixel() as u16, 4);
+            let bytes = &data[..w as usize * h as usize * layout.bytes_per_pixel()];
+            let img = layout.decode(bytes, w, h).unwrap();
+            assert_eq!((img.w, img.h), (w as u32, h as u32));
+            if layout == RawLayout::Rgb565Le || layout == RawLayout::Rgb565Be {
+                // opaque, so drawing on black changes nothing
+                assert!(img.data.chunks_exact(4).all(|p| p[0] == 255));
+            }
+            assert_eq!(layout.encode(&img), bytes, "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn wrong_size_is_an_error() {
+        assert_eq!(RawLayout::Argb8888.decode(&[ 0; 15 ], 2, 2).err().unwrap(), "15 bytes is the wrong size for 2x2 Argb8888, expected 16");
+        assert!(RawLayout::Rgb565Le.decode(&[ 0; 10 ], 2, 2).is_err());
+    }
+
+    #[test]
+    fn detect_from_size() {
+        assert_eq!(detect(12, 2, 2), Some(RawLayout::Argb8565));
+        assert_eq!(detect(8, 2, 2), Some(RawLayout::Rgb565Le));
+        assert_eq!(detect(16, 2, 2), Some(RawLayout::Argb8888));
+        assert_eq!(detect(13, 2, 2), None);
+    }
+
+    #[test]
+    fn sidecar() {
+        let folder = std::env::temp_dir().join(format!("extrathundertool-raw-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("image_0.raw");
+        assert_eq!(sidecar_path(&path), folder.join("image_0.raw.json"));
+        assert_eq!(read_sidecar(&path), Ok(None));
+        let info = RawInfo { w: 240, h: 296, format: RawLayout::Rgb565Be };
+        write_sidecar(&path, &info).unwrap();
+        assert_eq!(fs::read_to_string(sidecar_path(&path)).unwrap(), r#"{"w":240,"h":296,"format":"rgb565be"}"#);
+        assert_eq!(read_sidecar(&path), Ok(Some(info)));
+        fs::write(sidecar_path(&path), r#"{"w":240,"h":296,"format":"rgb888"}"#).unwrap();
+        assert!(read_sidecar(&path).is_err());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    // dumped as each layout with alpha and read back, the images are the same
+    #[test]
+    fn dump_and_read() {
+        let folder = std::env::temp_dir().join(format!("extrathundertool-raw-dump-{}", std::process::id()));
+        for layout in [ RawLayout::Argb8565, RawLayout::Argb8888, RawLayout::Rgba8888 ] {
+            fs::create_dir_all(&folder).unwrap();
+            let folder_name = folder.to_str().unwrap();
+            let mut face = FaceBuilder::new(&DEVICES[0]).preview(two_pixels()).image(5, 5, two_pixels()).build().unwrap();
+            let want: Vec<ImgData> = face.all_img_data_mut().into_iter().map(|(_, id)| id.clone()).collect();
+            face.generate_file_names(&DumpFormat::RAW(layout), false);
+            face.write_imgs(folder_name, &DumpFormat::RAW(layout)).unwrap();
+            let mut back: FaceN = serde_json::from_str(&serde_json::to_string(&face).unwrap()).unwrap();
+            back.read_imgs(folder_name).unwrap();
+            for ((_, id), want) in back.all_img_data_mut().into_iter().zip(want.iter()) {
+                assert_eq!((id.w, id.h, &id.header, &id.data), (want.w, want.h, &want.header, &want.data), "{:?}", layout);
+            }
+            fs::remove_dir_all(&folder).unwrap();
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//  recolor.rs - make colour variants of a watch face
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// Images are decoded to ARGB8888, recoloured, and compressed again to RleNew.
+// Alpha is never changed.
+
+
+use crate::face::FaceN;
+use crate::img::{Img, ImgFormat};
+use crate::img_data::ImgData;
+
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum RecolorOp {
+    Hue(f32),                           // rotate the hue by this many degrees
+    Saturation(f32),                    // multiply the saturation
+    Brightness(f32),                    // multiply the brightness (HSV value)
+    Map(Vec<([u8; 3], [u8; 3])>, u8),   // replace source colours with target colours, with a tolerance per channel
+}
+
+fn parse_rgb(s: &str) -> Result<[u8; 3], String> {
+    let s = s.trim_start_matches('#');
+    if s.len() != 6 {
+        return Err(format!("Colour '{}' should be RRGGBB", s));
+    }
+    let v = u32::from_str_radix(s, 16).map_err(|_| format!("Colour '{}' should be RRGGBB", s))?;
+    Ok([ (v >> 16) as u8, (v >> 8) as u8, v as u8 ])
+}
+
+impl RecolorOp {
+    // hue:DEGREES, sat:FACTOR, bright:FACTOR, or map:RRGGBB=RRGGBB,RRGGBB=RRGGBB
+    pub fn from_spec(spec: &str, tolerance: u8) -> Result<RecolorOp, String> {
+        let (name, value) = match spec.split_once(':') {
+            Some(v) => v,
+            None => return Err(format!("Recolor '{}' should be hue:DEGREES, sat:FACTOR, bright:FACTOR or map:RRGGBB=RRGGBB", spec)),
+        };
+        let factor = || value.parse::<f32>().map_err(|_| format!("Recolor '{}' needs a number", spec));
+        match name {
+            "hue" => Ok(RecolorOp::Hue(factor()?)),
+            "sat" => Ok(RecolorOp::Saturation(factor()?)),
+            "bright" => Ok(RecolorOp::Brightness(factor()?)),
+            "map" => {
+                let mut pairs = Vec::new();
+                for pair in value.split(',') {
+                    let (from, to) = pair.split_once('=').ok_or(format!("Colour map '{}' should be RRGGBB=RRGGBB", pair))?;
+                    pairs.push((parse_rgb(from)?, parse_rgb(to)?));
+                }
+                Ok(RecolorOp::Map(pairs, tolerance))
+            },
+            _ => Err(format!("Unknown recolor operation '{}'", name)),
+        }
+    }
+
+    fn apply(&self, rgb: [u8; 3]) -> [u8; 3] {
+        match self {
+            RecolorOp::Hue(deg) => {
+                let (h, s, v) = rgb_to_hsv(rgb);
+                hsv_to_rgb((h + deg).rem_euclid(360.0), s, v)
+            },
+            RecolorOp::Saturation(f) => {
+                let (h, s, v) = rgb_to_hsv(rgb);
+                hsv_to_rgb(h, (s * f).clamp(0.0, 1.0), v)
+            },
+            RecolorOp::Brightness(f) => {
+                let (h, s, v) = rgb_to_hsv(rgb);
+                hsv_to_rgb(h, s, (v * f).clamp(0.0, 1.0))
+            },
+            RecolorOp::Map(pairs, tolerance) => {
+                for (from, to) in pairs.iter() {
+                    if (0..3).all(|c| rgb[c].abs_diff(from[c]) <= *tolerance) {
+                        // keep the small difference from the source colour, so anti-aliased edges stay smooth
+                        let mut out = [0; 3];
+                        for c in 0..3 {
+                            out[c] = (to[c] as i32 + rgb[c] as i32 - from[c] as i32).clamp(0, 255) as u8;
+                        }
+                        return out;
+                    }
+                }
+                rgb
+            },
+        }
+    }
+}
+
+// h in degrees 0..360, s and v 0..1
+fn rgb_to_hsv(rgb: [u8; 3]) -> (f32, f32, f32) {
+    let r = rgb[0] as f32 / 255.0;
+    let g = rgb[1] as f32 / 255.0;
+    let b = rgb[2] as f32 / 255.0;
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let d = max - min;
+    let h = if d == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / d).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / d + 2.0)
+    } else {
+        60.0 * ((r - g) / d + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { d / max };
+    (h, s, max)
+}
+
+fn hsv_to_rgb(h: f32, s: f32, v: f32) -> [u8; 3] {
+    let c = v * s;
+    let x = c * (1.0 - ((h / 60.0).rem_euclid(2.0) - 1.0).abs());
+    let m = v - c;
+    let (r, g, b) = match (h / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    [ ((r + m) * 255.0).round() as u8, ((g + m) * 255.0).round() as u8, ((b + m) * 255.0).round() as u8 ]
+}
+
+pub fn recolor_img_data(id: &mut ImgData, ops: &[RecolorOp]) -> Result<(), String> {
+    let mut img = Img::from_img_data(id);
+    img.convert_format(ImgFormat::Argb8888);
+    for y in 0..img.h {
+        for x in 0..img.w {
+            let p = img.get_pixel(x, y);
+            let rgb = ops.iter().fold([p[1], p[2], p[3]], |rgb, op| op.apply(rgb));
+            img.put_pixel(x, y, [p[0], rgb[0], rgb[1], rgb[2]]);
+        }
+    }
+    let mut new_id = ImgData::from_img(&img)?;
+    new_id.file_name = id.file_name.take();
+    *id = new_id;
+    Ok(())
+}
+
+impl FaceN
+{
+    // recolour the images. only is a list of "preview", "digits" or element type names, empty for everything.
+    // returns the number of images recoloured.
+    pub fn recolor(&mut self, ops: &[RecolorOp], only: &[String]) -> Result<usize, String> {
+        let mut count = 0;
+        for (name, id) in self.all_img_data_mut() {
+            if only.is_empty() || only.iter().any(|o| o == name) {
+                recolor_img_data(id, ops)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+    use crate::elements::Element;
+    use crate::face_builder::FaceBuilder;
+    use crate::render::decode;
+
+    const RED: [u8; 3] = [ 255, 0, 0 ];
+    const GREEN: [u8; 3] = [ 0, 255, 0 ];
+    const BLUE: [u8; 3] = [ 0, 0, 255 ];
+    const WHITE: [u8; 3] = [ 255, 255, 255 ];
+
+    #[test]
+    fn known_colours() {
+        assert_eq!(RecolorOp::Hue(120.0).apply(RED), GREEN);
+        assert_eq!(RecolorOp::Hue(-120.0).apply(RED), BLUE);
+        assert_eq!(RecolorOp::Hue(480.0).apply(GREEN), BLUE);
+        assert_eq!(RecolorOp::Saturation(0.0).apply(RED), WHITE);
+        assert_eq!(RecolorOp::Saturation(0.5).apply(RED), [ 255, 128, 128 ]);
+        assert_eq!(RecolorOp::Brightness(0.5).apply(WHITE), [ 128, 128, 128 ]);
+        assert_eq!(RecolorOp::Brightness(2.0).apply([ 100, 50, 0 ]), [ 200, 100, 0 ]);
+    }
+
+    #[test]
+    fn map_keeps_the_difference() {
+        let op = RecolorOp::from_spec("map:FFFFFF=FF8000,#000000=202020", 8).unwrap();
+        assert_eq!(op.apply(WHITE), [ 255, 128, 0 ]);
+        assert_eq!(op.apply([ 250, 250, 250 ]), [ 250, 123, 0 ]);
+        assert_eq!(op.apply([ 4, 0, 8 ]), [ 36, 32, 40 ]);
+        assert_eq!(op.apply([ 200, 200, 200 ]), [ 200, 200, 200 ]);
+        assert_eq!(op.apply([ 0, 0, 9 ]), [ 0, 0, 9 ]);
+    }
+
+    #[test]
+    fn specs() {
+        assert_eq!(RecolorOp::from_spec("hue:90", 8).unwrap(), RecolorOp::Hue(90.0));
+        assert_eq!(RecolorOp::from_spec("sat:1.5", 8).unwrap(), RecolorOp::Saturation(1.5));
+        assert_eq!(RecolorOp::from_spec("bright:0.8", 8).unwrap(), RecolorOp::Brightness(0.8));
+        for bad in [ "hue", "hue:x", "tint:3", "map:FFF=000", "map:FFFFFF", "map:GGGGGG=000000" ] {
+            assert!(RecolorOp::from_spec(bad, 8).is_err(), "{}", bad);
+        }
+    }
+
+    // red, blue / transparent red, half transparent blue
+    fn pixels() -> Img {
+        let mut img = Img::new(2, 2);
+        img.put_pixel(0, 0, [ 255, 255, 0, 0 ]);
+        img.put_pixel(1, 0, [ 255, 0, 0, 255 ]);
+        img.put_pixel(0, 1, [ 0, 255, 0, 0 ]);
+        img.put_pixel(1, 1, [ 128, 0, 0, 255 ]);
+        img
+    }
+
+    #[test]
+    fn only_the_chosen_images_change() {
+        let mut face = FaceBuilder::new(&DEVICES[0]).preview(pixels()).image(0, 0, pixels()).build().unwrap();
+        assert_eq!(face.recolor(&[ RecolorOp::Hue(120.0) ], &[ "image".to_string() ]).unwrap(), 1);
+        assert_eq!(decode(&face.preview_img_data).data, decode(&ImgData::from_img(&pixels()).unwrap()).data);
+        let img = match &face.elements[0] {
+            Element::Image(e) => decode(&e.img_data),
+            _ => panic!("element changed type"),
+        };
+        // alpha never changes
+        assert_eq!(img.get_pixel(0, 0), [ 255, 0, 255, 0 ]);
+        assert_eq!(img.get_pixel(1, 0), [ 255, 255, 0, 0 ]);
+        assert_eq!(img.get_pixel(0, 1)[0], 0);
+        assert_eq!(img.get_pixel(1, 1), [ 128, 255, 0, 0 ]);
+    }
+}
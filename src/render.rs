@@ -0,0 +1,283 @@
+//  render.rs - draw a watch face, as the watch would
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// Elements are drawn in the order they appear in the file, the same as the watch does.
+// Sensor values come from RenderState. Battery fill and weather are only approximate, as how the watch
+// draws them isn't fully known.
+
+
+use crate::device::{Device, DEVICES};
+use crate::elements::{*};
+use crate::face::FaceN;
+use crate::img::{Img, ImgFormat};
+use crate::img_data::ImgData;
+use crate::xy::XY;
+
+
+// the time and sensor values to show on the face
+#[derive(Clone, PartialEq, Debug)]
+pub struct RenderState
+{
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub weekday: u8,            // the day_name image to show, 0 to 6
+    pub day: u8,
+    pub month: u8,
+    pub heart_rate: u32,
+    pub steps: u32,
+    pub kcal: u32,
+    pub battery: u8,            // percent
+}
+
+impl RenderState
+{
+    // 10:08:36 - the traditional watch advertising time, the hands don't cover anything important.
+    // the sensor values are typical of a day, so every digit position of a number is used.
+    pub fn new() -> Self {
+        Self {
+            hour: 10,
+            minute: 8,
+            second: 36,
+            weekday: 1,
+            day: 18,
+            month: 10,
+            heart_rate: 72,
+            steps: 6824,
+            kcal: 251,
+            battery: 80,
+        }
+    }
+
+    // how full a bar_display is, 0.0 to 1.0, for its b_type
+    pub fn bar_level(&self, b_type: u8) -> f32 {
+        let level = match b_type {
+            0 => self.steps as f32 / 10000.0,               // the usual daily goals
+            2 => self.kcal as f32 / 500.0,
+            5 => self.heart_rate as f32 / 200.0,
+            6 => self.battery as f32 / 100.0,
+            _ => 0.5,
+        };
+        level.clamp(0.0, 1.0)
+    }
+
+    // parse a time in the form HH:MM or HH:MM:SS
+    pub fn from_time_str(s: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(format!("Time '{}' should be HH:MM or HH:MM:SS", s));
+        }
+        let mut v: [u8; 3] = [0; 3];
+        let limits: [u8; 3] = [23, 59, 59];
+        for (i, p) in parts.iter().enumerate() {
+            v[i] = match p.parse::<u8>() {
+                Ok(n) if n <= limits[i] => n,
+                _ => return Err(format!("Time '{}' should be HH:MM or HH:MM:SS", s)),
+            };
+        }
+        let mut state = Self::new();
+        state.hour = v[0];
+        state.minute = v[1];
+        state.second = v[2];
+        Ok(state)
+    }
+
+    // clockwise degrees from 12 o'clock
+    pub fn hand_angle(&self, h_type: HandType) -> f32 {
+        let h = self.hour as f32;
+        let m = self.minute as f32;
+        let s = self.second as f32;
+        match h_type {
+            HandType::Hour => ((h % 12.0) + m / 60.0) * 30.0,
+            HandType::Minute => (m + s / 60.0) * 6.0,
+            HandType::Second => s * 6.0,
+        }
+    }
+}
+
+// an image from the file, ready to draw
+pub fn decode(id: &ImgData) -> Img {
+    let mut img = Img::from_img_data(id);
+    img.convert_format(ImgFormat::Argb8888);
+    img
+}
+
+// draw a number with a digit set. align is 0:L, 1:R, 2:C about x.
+fn draw_number(img: &mut Img, face: &FaceN, digit_set: u8, align: u8, x: u16, y: u16, value: u32) {
+    let ds = match face.digits.get(digit_set as usize) {
+        Some(ds) if ds.img_data.len() >= 10 => ds,
+        _ => return,
+    };
+    let digits: Vec<usize> = value.to_string().bytes().map(|b| (b - b'0') as usize).collect();
+    let digit_w = ds.img_data[0].w as i32;
+    let width = digit_w * digits.len() as i32;
+    let x0 = match align {
+        1 => x as i32 - width,
+        2 => x as i32 - width / 2,
+        _ => x as i32,
+    };
+    for (i, d) in digits.iter().enumerate() {
+        img.draw(&decode(&ds.img_data[*d]), x0 + digit_w * i as i32, y as i32);
+    }
+}
+
+// draw a two digit number at the digit positions
+fn draw_two_digits(img: &mut Img, face: &FaceN, digit_set: u8, xys: &[XY; 2], value: u8) {
+    if let Some(ds) = face.digits.get(digit_set as usize) {
+        for (i, d) in [ value / 10 % 10, value % 10 ].iter().enumerate() {
+            if let Some(id) = ds.img_data.get(*d as usize) {
+                img.draw(&decode(id), xys[i].x as i32, xys[i].y as i32);
+            }
+        }
+    }
+}
+
+// draw a small cross, for checking alignment
+fn draw_marker(img: &mut Img, x: i32, y: i32, colour: [u8; 4]) {
+    for d in -4..=4 {
+        img.blend_pixel(x + d, y, colour);
+        img.blend_pixel(x, y + d, colour);
+    }
+}
+
+impl FaceN
+{
+    // the screen size of the watch this face is for
+    pub fn screen_size(&self) -> (u16, u16) {
+        if let Some(d) = Device::from_tpls(self.tpls) {
+            return (d.w, d.h);
+        }
+        // faces read from a binary file don't know their tpls, so use the size of the background
+        for el in self.elements.iter() {
+            if let Element::Image(e) = el {
+                if e.x == 0 && e.y == 0 {
+                    return (e.img_data.w, e.img_data.h);
+                }
+            }
+        }
+        (DEVICES[0].w, DEVICES[0].h)
+    }
+
+    // look for hands that won't line up with the dial centre
+    pub fn check_hands(&self) -> Vec<String> {
+        let (w, h) = self.screen_size();
+        let mut problems: Vec<String> = Vec::new();
+        let mut centre: Option<(u16, u16)> = None;
+        for el in self.elements.iter() {
+            if let Element::TimeHand(e) = el {
+                if e.pivot_x >= e.img_data.w || e.pivot_y >= e.img_data.h {
+                    problems.push(format!("time_hand {} pivot {},{} is outside its {}x{} image", e.h_type, e.pivot_x, e.pivot_y, e.img_data.w, e.img_data.h));
+                }
+                if e.x != w / 2 || e.y != h / 2 {
+                    problems.push(format!("time_hand {} centre {},{} is not the screen centre {},{}", e.h_type, e.x, e.y, w / 2, h / 2));
+                }
+                match centre {
+                    Some((x, y)) if x != e.x || y != e.y => problems.push(format!("time_hand {} centre {},{} doesn't match the other hands at {},{}", e.h_type, e.x, e.y, x, y)),
+                    None => centre = Some((e.x, e.y)),
+                    _ => {},
+                }
+            }
+        }
+        problems
+    }
+
+    // draw the face. if markers is true, the screen centre and the hand centres are marked.
+    pub fn render(&self, state: &RenderState, markers: bool) -> Img {
+        let (w, h) = self.screen_size();
+        let mut img = Img::new(w as u32, h as u32);
+
+        // the watch screen is black underneath
+        for y in 0..img.h {
+            for x in 0..img.w {
+                img.put_pixel(x, y, [255, 0, 0, 0]);
+            }
+        }
+
+        for el in self.elements.iter() {
+            match el {
+                Element::Image(e) => {
+                    img.draw(&decode(&e.img_data), e.x as i32, e.y as i32);
+                },
+                Element::TimeNum(e) => {
+                    let values = [ state.hour / 10, state.hour % 10, state.minute / 10, state.minute % 10 ];
+                    for (i, v) in values.iter().enumerate() {
+                        let id = self.digits.get(e.digit_sets[i] as usize).and_then(|ds| ds.img_data.get(*v as usize));
+                        if let Some(id) = id {
+                            img.draw(&decode(id), e.xys[i].x as i32, e.xys[i].y as i32);
+                        }
+                    }
+                },
+                Element::TimeHand(e) => {
+                    if let Some(h_type) = HandType::from_h_type(e.h_type) {
+                        let pivot = (e.pivot_x as f32, e.pivot_y as f32);
+                        let centre = (e.x as f32, e.y as f32);
+                        img.draw_rotated(&decode(&e.img_data), pivot, centre, state.hand_angle(h_type));
+                    }
+                },
+                Element::DayName(e) => {
+                    if let Some(id) = e.img_data.get(state.weekday as usize) {
+                        img.draw(&decode(id), e.x as i32, e.y as i32);
+                    }
+                },
+                Element::DayNum(e) => draw_two_digits(&mut img, self, e.digit_set, &e.xys, state.day),
+                Element::MonthNum(e) => draw_two_digits(&mut img, self, e.digit_set, &e.xys, state.month),
+                Element::HeartRateNum(e) => draw_number(&mut img, self, e.digit_set, e.align, e.x, e.y, state.heart_rate),
+                Element::StepsNum(e) => draw_number(&mut img, self, e.digit_set, e.align, e.x, e.y, state.steps),
+                Element::KCalNum(e) => draw_number(&mut img, self, e.digit_set, e.align, e.x, e.y, state.kcal),
+                Element::BarDisplay(e) => {
+                    // the last image is a full bar
+                    let last = e.img_data.len().saturating_sub(1);
+                    let i = (state.bar_level(e.b_type) * last as f32).round() as usize;
+                    if let Some(id) = e.img_data.get(i) {
+                        img.draw(&decode(id), e.x as i32, e.y as i32);
+                    }
+                },
+                Element::Weather(e) => {
+                    if let Some(id) = e.img_data.first() {
+                        img.draw(&decode(id), e.x as i32, e.y as i32);
+                    }
+                },
+                // how the watch fills the battery isn't known yet, so only the background is drawn
+                Element::BatteryFill(e) => img.draw(&decode(&e.img_data), e.x as i32, e.y as i32),
+                _ => {},
+            }
+        }
+
+        if markers {
+            draw_marker(&mut img, w as i32 / 2, h as i32 / 2, [255, 255, 0, 255]);
+            for el in self.elements.iter() {
+                if let Element::TimeHand(e) = el {
+                    draw_marker(&mut img, e.x as i32, e.y as i32, [255, 0, 255, 255]);
+                }
+            }
+        }
+        img
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+    use crate::face_builder::FaceBuilder;
+
+    #[test]
+    fn short_digit_set_does_not_panic() {
+        let digits: Vec<Img> = (0..10).map(|_| Img::new(10, 16)).collect();
+        let xys = [ XY { x: 0, y: 0 }, XY { x: 10, y: 0 }, XY { x: 30, y: 0 }, XY { x: 40, y: 0 } ];
+        let mut face = FaceBuilder::new(&DEVICES[0]).preview(Img::new(4, 4)).digit_set(digits)
+            .time_num(0, xys).day_num(0, 0, [ XY { x: 0, y: 20 }, XY { x: 10, y: 20 } ]).steps_num(0, 0, 0, 40)
+            .build().unwrap();
+        face.digits[0].img_data.truncate(3);
+        let img = face.render(&RenderState::new(), false);
+        assert_eq!((img.w, img.h), (DEVICES[0].w as u32, DEVICES[0].h as u32));
+    }
+}
@@ -0,0 +1,340 @@
+//  retarget.rs - port a watch face to a different screen resolution
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// Every image is scaled by the same amount, so digit sets stay the same size as each other.
+// Fit: the whole face is visible, with a border if the aspect ratios differ.
+// Fill: the whole screen is covered, some of the face may be cut off.
+// The part of an image that is off the screen is cropped, so it still lines up with the rest of the face.
+// Anything else that would be off the top or left, like digits or a hand centre, can't be moved without changing the
+// layout, so it is an error.
+
+
+use crate::device::Device;
+use crate::elements::{*};
+use crate::face::FaceN;
+use crate::img::{Img, ImgFormat, ResizeFilter};
+use crate::img_data::ImgData;
+use crate::xy::XY;
+
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum FitPolicy {
+    Fit,
+    Fill,
+}
+
+impl FitPolicy {
+    pub fn from_name(name: &str) -> Result<FitPolicy, String> {
+        match name.to_lowercase().as_str() {
+            "fit" => Ok(FitPolicy::Fit),
+            "fill" => Ok(FitPolicy::Fill),
+            _ => Err(format!("Unknown policy '{}', expected fit or fill", name)),
+        }
+    }
+}
+
+struct Transform {
+    scale: f32,
+    offset_x: f32,
+    offset_y: f32,
+    w: u16,                 // target screen size
+    h: u16,
+    filter: ResizeFilter,
+    warnings: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl Transform {
+    fn len(&self, v: u16) -> u16 {
+        ((v as f32 * self.scale).round() as u16).max(1)
+    }
+
+    fn scaled(&self, x: u16, y: u16) -> (f32, f32) {
+        ((x as f32 * self.scale + self.offset_x).round(), (y as f32 * self.scale + self.offset_y).round())
+    }
+
+    // position on the screen
+    fn pos(&mut self, x: u16, y: u16, what: &str) -> (u16, u16) {
+        let (nx, ny) = self.scaled(x, y);
+        if nx < 0.0 || ny < 0.0 {
+            self.errors.push(format!("{} at {},{} would be at {},{}, off the top or left of the screen", what, x, y, nx, ny));
+        }
+        (nx.max(0.0) as u16, ny.max(0.0) as u16)
+    }
+
+    // position of an image on the screen, cropping off any part that is off the screen
+    fn pos_cropped(&mut self, x: u16, y: u16, id: &mut ImgData, what: &str) -> (u16, u16) {
+        let (nx, ny) = self.scaled(x, y);
+        let (iw, ih) = (id.w as f32, id.h as f32);
+        let (left, top) = ((-nx).max(0.0), (-ny).max(0.0));
+        let (right, bottom) = ((nx + iw - self.w as f32).max(0.0), (ny + ih - self.h as f32).max(0.0));
+        if left + right >= iw || top + bottom >= ih {
+            self.errors.push(format!("{} at {},{} would be entirely off the screen", what, x, y));
+            return (0, 0);
+        }
+        if left == 0.0 && top == 0.0 && right == 0.0 && bottom == 0.0 {
+            return self.pos(x, y, what);
+        }
+        let mut img = Img::from_img_data(id);
+        img.convert_format(ImgFormat::Argb8888);
+        let img = img.crop(left as u32, top as u32, (iw - left - right) as u32, (ih - top - bottom) as u32);
+        match ImgData::from_img(&img) {
+            Ok(mut new_id) => {
+                new_id.file_name = id.file_name.take();
+                *id = new_id;
+            },
+            Err(e) => self.errors.push(e),
+        }
+        (nx.max(0.0) as u16, ny.max(0.0) as u16)
+    }
+
+    // a point inside an image, moved with the image as it was scaled from old_len to new_len
+    fn inside(v: u16, old_len: u16, new_len: u16) -> u16 {
+        let n = (v as f32 * new_len as f32 / old_len.max(1) as f32).round() as u16;
+        n.min(new_len.saturating_sub(1))
+    }
+
+    fn xy(&mut self, xy: &XY, what: &str) -> XY {
+        let (x, y) = self.pos(xy.x, xy.y, what);
+        XY { x, y }
+    }
+
+    // position relative to an image, stored in a byte
+    fn rel_u8(&mut self, v: u8, what: &str) -> u8 {
+        let n = (v as f32 * self.scale).round();
+        if n > 255.0 {
+            self.warnings.push(format!("{} would be {}, which doesn't fit in a byte", what, n));
+        }
+        n.min(255.0) as u8
+    }
+
+    fn img(&mut self, id: &mut ImgData) {
+        let img = Img::from_img_data(id).resize(self.len(id.w) as u32, self.len(id.h) as u32, self.filter);
+        match ImgData::from_img(&img) {
+            Ok(mut new_id) => {
+                new_id.file_name = id.file_name.take();
+                *id = new_id;
+            },
+            Err(e) => self.errors.push(e),
+        }
+    }
+
+    // warn about anything that doesn't fit on the screen
+    fn check(&mut self, x: u16, y: u16, id: &ImgData, what: &str) {
+        if x as u32 + id.w as u32 > self.w as u32 || y as u32 + id.h as u32 > self.h as u32 {
+            self.warnings.push(format!("{} at {},{} size {}x{} extends off the {}x{} screen", what, x, y, id.w, id.h, self.w, self.h));
+        }
+    }
+}
+
+impl FaceN
+{
+    // scale the whole face to a new screen size. returns a list of warnings.
+    // tpls is the new device, or None to use the one with that screen size.
+    pub fn retarget(&mut self, w: u16, h: u16, filter: ResizeFilter, policy: FitPolicy, tpls: Option<u16>) -> Result<Vec<String>, String> {
+        let (src_w, src_h) = self.screen_size();
+        let sx = w as f32 / src_w as f32;
+        let sy = h as f32 / src_h as f32;
+        let scale = match policy {
+            FitPolicy::Fit => sx.min(sy),
+            FitPolicy::Fill => sx.max(sy),
+        };
+        let mut t = Transform {
+            scale,
+            offset_x: (w as f32 - src_w as f32 * scale) / 2.0,
+            offset_y: (h as f32 - src_h as f32 * scale) / 2.0,
+            w,
+            h,
+            filter,
+            warnings: Vec::new(),
+            errors: Vec::new(),
+        };
+
+        // the preview is not on the screen, it is just scaled
+        t.img(&mut self.preview_img_data);
+
+        for d in self.digits.iter_mut() {
+            for id in d.img_data.iter_mut() {
+                t.img(id);
+            }
+        }
+
+        for (n, el) in self.elements.iter_mut().enumerate() {
+            let what = format!("element {} ({})", n, el.type_name());
+            match el {
+                Element::Image(e) => {
+                    t.img(&mut e.img_data);
+                    (e.x, e.y) = t.pos_cropped(e.x, e.y, &mut e.img_data, &what);
+                    t.check(e.x, e.y, &e.img_data, &what);
+                },
+                Element::TimeNum(e) => {
+                    for i in 0..4 {
+                        e.xys[i] = t.xy(&e.xys[i], &what);
+                    }
+                },
+                Element::DayName(e) => {
+                    e.img_data.iter_mut().for_each(|id| t.img(id));
+                    (e.x, e.y) = t.pos(e.x, e.y, &what);
+                    if let Some(id) = e.img_data.first() {
+                        t.check(e.x, e.y, id, &what);
+                    }
+                },
+                Element::BatteryFill(e) => {
+                    t.img(&mut e.img_data);
+                    t.img(&mut e.image_data1);
+                    t.img(&mut e.image_data2);
+                    (e.x, e.y) = t.pos(e.x, e.y, &what);
+                    e.x1 = t.rel_u8(e.x1, &what);
+                    e.y1 = t.rel_u8(e.y1, &what);
+                    e.x2 = t.rel_u8(e.x2, &what);
+                    e.y2 = t.rel_u8(e.y2, &what);
+                    t.check(e.x, e.y, &e.img_data, &what);
+                },
+                Element::HeartRateNum(e) => { (e.x, e.y) = t.pos(e.x, e.y, &what); },
+                Element::StepsNum(e) => { (e.x, e.y) = t.pos(e.x, e.y, &what); },
+                Element::KCalNum(e) => { (e.x, e.y) = t.pos(e.x, e.y, &what); },
+                Element::TimeHand(e) => {
+                    let (old_w, old_h) = (e.img_data.w, e.img_data.h);
+                    t.img(&mut e.img_data);
+                    e.pivot_x = Transform::inside(e.pivot_x, old_w, e.img_data.w);
+                    e.pivot_y = Transform::inside(e.pivot_y, old_h, e.img_data.h);
+                    (e.x, e.y) = t.pos(e.x, e.y, &what);
+                    if e.x >= w || e.y >= h {
+                        t.warnings.push(format!("{} centre {},{} is off the screen", what, e.x, e.y));
+                    }
+                },
+                Element::DayNum(e) => {
+                    for i in 0..2 {
+                        e.xys[i] = t.xy(&e.xys[i], &what);
+                    }
+                },
+                Element::MonthNum(e) => {
+                    for i in 0..2 {
+                        e.xys[i] = t.xy(&e.xys[i], &what);
+                    }
+                },
+                Element::BarDisplay(e) => {
+                    e.img_data.iter_mut().for_each(|id| t.img(id));
+                    (e.x, e.y) = t.pos(e.x, e.y, &what);
+                    if let Some(id) = e.img_data.first() {
+                        t.check(e.x, e.y, id, &what);
+                    }
+                },
+                Element::Weather(e) => {
+                    e.img_data.iter_mut().for_each(|id| t.img(id));
+                    (e.x, e.y) = t.pos(e.x, e.y, &what);
+                    if let Some(id) = e.img_data.first() {
+                        t.check(e.x, e.y, id, &what);
+                    }
+                },
+                Element::Dash(e) => t.img(&mut e.img_data),
+                Element::Unknown29(_) => {},
+                Element::Unknown => {},
+            }
+        }
+
+        // check the digits fit where they are used
+        for (n, el) in self.elements.iter().enumerate() {
+            let what = format!("element {} ({})", n, el.type_name());
+            let uses: Vec<(u8, XY)> = match el {
+                Element::TimeNum(e) => (0..4).map(|i| (e.digit_sets[i], e.xys[i].clone())).collect(),
+                Element::DayNum(e) => (0..2).map(|i| (e.digit_set, e.xys[i].clone())).collect(),
+                Element::MonthNum(e) => (0..2).map(|i| (e.digit_set, e.xys[i].clone())).collect(),
+                Element::HeartRateNum(e) => vec![ (e.digit_set, XY { x: e.x, y: e.y }) ],
+                Element::StepsNum(e) => vec![ (e.digit_set, XY { x: e.x, y: e.y }) ],
+                Element::KCalNum(e) => vec![ (e.digit_set, XY { x: e.x, y: e.y }) ],
+                _ => Vec::new(),
+            };
+            for (set, xy) in uses {
+                if let Some(id) = self.digits.get(set as usize).and_then(|ds| ds.img_data.first()) {
+                    t.check(xy.x, xy.y, id, &what);
+                }
+            }
+        }
+
+        if !t.errors.is_empty() {
+            return Err(t.errors.join("; "));
+        }
+        match tpls.or(Device::from_size(w, h).map(|d| d.tpls)) {
+            Some(tpls) => self.tpls = tpls,
+            None => t.warnings.push(format!("No known watch has a {}x{} screen, keeping tpls {}. Use --tpls to set it", w, h, self.tpls)),
+        }
+        Ok(t.warnings)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+    use crate::face_builder::FaceBuilder;
+
+    fn face() -> FaceBuilder {
+        FaceBuilder::new(&DEVICES[0]).preview(Img::new(120, 148)).background(Img::new(240, 296)).image(10, 100, Img::new(20, 20))
+    }
+
+    fn position(el: &Element) -> (u16, u16, u16, u16) {
+        match el {
+            Element::Image(e) => (e.x, e.y, e.img_data.w, e.img_data.h),
+            _ => panic!("elements changed type"),
+        }
+    }
+
+    #[test]
+    fn fill_crops_images_off_the_screen() {
+        // 2x scale, with 56 pixels off the top and the bottom
+        let mut f = face().image(100, 250, Img::new(20, 20)).image(100, 20, Img::new(20, 20)).build().unwrap();
+        f.retarget(480, 480, ResizeFilter::Nearest, FitPolicy::Fill, None).unwrap();
+        assert_eq!(position(&f.elements[0]), (0, 0, 480, 480));
+        assert_eq!(position(&f.elements[1]), (20, 144, 40, 40));
+        assert_eq!(position(&f.elements[2]), (200, 444, 40, 36));
+        assert_eq!(position(&f.elements[3]), (200, 0, 40, 24));
+    }
+
+    #[test]
+    fn image_entirely_off_the_screen_is_an_error() {
+        let mut f = face().image(100, 290, Img::new(6, 6)).build().unwrap();
+        let err = f.retarget(480, 480, ResizeFilter::Nearest, FitPolicy::Fill, None).unwrap_err();
+        assert!(err.contains("entirely off the screen"), "{}", err);
+    }
+
+    #[test]
+    fn pivot_stays_inside_the_hand() {
+        // 0.7x scale, the 2x2 hand becomes 1x1
+        let mut f = face().hand(HandType::Minute, Img::new(2, 2), XY { x: 1, y: 1 }).build().unwrap();
+        f.retarget(168, 207, ResizeFilter::Nearest, FitPolicy::Fit, None).unwrap();
+        match &f.elements[2] {
+            Element::TimeHand(e) => {
+                assert_eq!((e.img_data.w, e.img_data.h), (1, 1));
+                assert_eq!((e.pivot_x, e.pivot_y), (0, 0));
+            },
+            _ => panic!("elements changed type"),
+        }
+    }
+
+    #[test]
+    fn fill_rejects_digits_off_the_screen() {
+        let digits: Vec<Img> = (0..10).map(|_| Img::new(10, 16)).collect();
+        let mut f = face().digit_set(digits).day_num(0, 0, [ XY { x: 0, y: 10 }, XY { x: 10, y: 10 } ]).build().unwrap();
+        let err = f.retarget(480, 480, ResizeFilter::Nearest, FitPolicy::Fill, None).unwrap_err();
+        assert!(err.contains("off the top or left"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_size_keeps_tpls() {
+        let mut f = face().build().unwrap();
+        let warnings = f.retarget(300, 300, ResizeFilter::Nearest, FitPolicy::Fit, None).unwrap();
+        assert_eq!(f.tpls, DEVICES[0].tpls);
+        assert!(warnings.iter().any(|w| w.contains("--tpls")));
+        f.retarget(300, 300, ResizeFilter::Nearest, FitPolicy::Fit, Some(99)).unwrap();
+        assert_eq!(f.tpls, 99);
+    }
+}
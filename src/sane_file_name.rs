@@ -1,4 +1,3 @@
-
 //  sane_file_name.rs - check if a file name is sane/sensible
 //
 // 	ExtraThunder WatchFace Tool
@@ -9,12 +8,25 @@
 // 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
 
 
-// returns TRUE if the file name is sane, returns FALSE if it is dodgy
-pub fn sane_file_name(fname: &str) -> bool {
+// A file name can be a relative path inside the dump folder, like digits/set0/3.bmp.
+// Either / or \ separates the folders, and we always write /.
+// It can't be absolute or go up out of the folder with .., and every part of it must be sane on Windows too.
+
+
+use std::path::PathBuf;
+
+
+// must not be any of the weird windows strings, with or without an extension
+const WEIRD_STRINGS: [&str; 32] = [ "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$",
+    "COM0", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "COM¹", "COM²", "COM³",
+    "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9", "LPT¹", "LPT²", "LPT³" ];
+
+// returns TRUE if one part of a path is sane, returns FALSE if it is dodgy
+fn sane_component(fname: &str) -> bool {
     // fname must have at least one character
-    if fname.len() < 1 {
+    if fname.is_empty() {
         return false;
-    }            
+    }
     // must not contain dodgy chars like wildcards or path characters
     let dodgy_chars = "/\\|?*<>:\"";
     if fname.chars().any(|c| dodgy_chars.contains(c)) {
@@ -24,18 +36,76 @@ pub fn sane_file_name(fname: &str) -> bool {
     if fname.chars().any(|c| (c as u32) < 32) {
         return false;
     }
-    // must not end in space or dot
+    // must not end in space or dot. this also stops . and ..
     let chs: Vec<char> = fname.chars().collect();
     let c = chs[chs.len()-1];
     if c == ' ' || c == '.' {
         return false;
     }
-    // must not be any of the weird windows strings
-    let weird_strings = [ "CON", "PRN", "AUX", "NUL",
-        "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
-        "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9" ];
-    if weird_strings.iter().any(|s| fname.to_uppercase() == *s) {
+    let stem = fname.split('.').next().unwrap_or("").trim_end().to_uppercase();
+    if WEIRD_STRINGS.iter().any(|s| stem == *s) {
         return false;
     }
-    return true;
+    true
+}
+
+// the file name with / between the folders and any ./ removed, or why it isn't sane
+pub fn normalise_file_name(fname: &str) -> Result<String, String> {
+    if fname.starts_with('/') || fname.starts_with('\\') {
+        return Err(format!("'{}' is an absolute path, it must be inside the folder", fname));
+    }
+    let mut parts: Vec<&str> = Vec::new();
+    for part in fname.split(['/', '\\']) {
+        match part {
+            "." => continue,
+            ".." => return Err(format!("'{}' uses .., it must stay inside the folder", fname)),
+            _ if !sane_component(part) => return Err(format!("'{}' is not a sensible file name", fname)),
+            _ => parts.push(part),
+        }
+    }
+    if parts.is_empty() {
+        return Err(format!("'{}' is not a sensible file name", fname));
+    }
+    Ok(parts.join("/"))
+}
+
+// returns TRUE if the file name is sane, returns FALSE if it is dodgy
+pub fn sane_file_name(fname: &str) -> bool {
+    normalise_file_name(fname).is_ok()
+}
+
+// where a sane file name is, inside the folder
+pub fn file_path(folder_name: &str, fname: &str) -> Result<PathBuf, String> {
+    let mut path = PathBuf::from(folder_name);
+    path.extend(normalise_file_name(fname)?.split('/'));
+    Ok(path)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sane_names() {
+        assert_eq!(normalise_file_name("a/./b").unwrap(), "a/b");
+        assert_eq!(normalise_file_name("digits\\set0\\3.bmp").unwrap(), "digits/set0/3.bmp");
+        assert_eq!(normalise_file_name("./preview.bmp").unwrap(), "preview.bmp");
+        assert_eq!(normalise_file_name("console.bmp").unwrap(), "console.bmp");
+        assert_eq!(normalise_file_name("COM10.bmp").unwrap(), "COM10.bmp");
+    }
+
+    #[test]
+    fn dodgy_names() {
+        for name in [ "../x.bmp", "a/../../x.bmp", "/etc/x", "\\server\\x.bmp", "C:x", "C:\\x.bmp", "", ".", "a/", "x.bmp.",
+            "CON.bmp", "con", "a/nul.bmp", "COM0.bmp", "LPT0", "CONIN$", "conout$.bmp", "COM¹.bmp", "a\u{1}.bmp", "a*.bmp" ] {
+            assert!(normalise_file_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn path_is_inside_the_folder() {
+        assert_eq!(file_path("dump", "a\\b.bmp").unwrap(), PathBuf::from("dump").join("a").join("b.bmp"));
+        assert!(file_path("dump", "../b.bmp").is_err());
+    }
 }
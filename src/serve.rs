@@ -0,0 +1,288 @@
+//  serve.rs - a layout editor in the web browser
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// A tiny HTTP server, for localhost only. It serves one page (serve.html), which edits watchface.json.
+//
+//  GET  /                  the editor page
+//  GET  /face.json         the watch face, as in watchface.json
+//  GET  /render.bmp        the rendered face. optional query: time=HH:MM:SS, markers=1
+//  POST /face.json         replace the watch face, save watchface.json. returns the validation warnings.
+//
+// Images are only read from the folder when a file_name is new, so editing positions is quick.
+//
+// Any web page the user visits can send requests to localhost, so requests must have a Host of 127.0.0.1 or
+// localhost with our port (which stops DNS rebinding), must not come from another site's Origin, and a POST
+// must be application/json, which a page on another site can't send without asking first.
+
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::face::FaceN;
+use crate::img_data::ImgData;
+use crate::render::RenderState;
+
+
+const PAGE: &str = include_str!("serve.html");
+const MAX_BODY: usize = 16 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);         // so a stalled connection can't block the server
+
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    host: Option<String>,
+    origin: Option<String>,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    // why the request isn't allowed, if it isn't
+    fn forbidden(&self, port: u16) -> Option<&'static str> {
+        let ours = [ format!("127.0.0.1:{}", port), format!("localhost:{}", port) ];
+        let is_ours = |host: &str| ours.iter().any(|o| o.eq_ignore_ascii_case(host));
+        match &self.host {
+            Some(h) if is_ours(h) => {},
+            _ => return Some("Wrong Host"),
+        }
+        if let Some(o) = &self.origin {
+            if !o.strip_prefix("http://").map(is_ours).unwrap_or(false) {
+                return Some("Wrong Origin");
+            }
+        }
+        if self.method == "POST" {
+            let ct = self.content_type.as_deref().unwrap_or("");
+            if !ct.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("application/json") {
+                return Some("Content-Type must be application/json");
+            }
+        }
+        None
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: Vec<u8>) -> Self {
+        Self { status: "200 OK", content_type, body }
+    }
+
+    fn json(status: &'static str, value: serde_json::Value) -> Self {
+        Self { status, content_type: "application/json", body: value.to_string().into_bytes() }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Self::json(status, serde_json::json!({ "error": message }))
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<Request, String> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() < 2 {
+        return Err("Bad request line".to_string());
+    }
+    let method = parts[0].to_string();
+    let (path, query_str) = parts[1].split_once('?').unwrap_or((parts[1], ""));
+    let query: HashMap<String, String> = query_str.split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.replace("%3A", ":").replace("%3a", ":")))
+        .collect();
+
+    // the headers we need
+    let mut content_length = 0;
+    let (mut host, mut origin, mut content_type) = (None, None, None);
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(|e| e.to_string())?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse().map_err(|_| "Bad Content-Length".to_string())?,
+                "host" => host = Some(value.to_string()),
+                "origin" => origin = Some(value.to_string()),
+                "content-type" => content_type = Some(value.to_string()),
+                _ => {},
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err("Request is too large".to_string());
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+
+    Ok(Request { method, path: path.to_string(), query, host, origin, content_type, body })
+}
+
+fn write_response(stream: &mut TcpStream, r: &Response) -> std::io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        r.status, r.content_type, r.body.len())?;
+    stream.write_all(&r.body)?;
+    stream.flush()
+}
+
+pub struct Server<'a> {
+    folder_name: &'a str,
+    face: FaceN,
+    debug: u8,
+}
+
+impl<'a> Server<'a> {
+    // read watchface.json and its images from the folder
+    pub fn new(folder_name: &'a str, debug: u8) -> Result<Self, String> {
+        let path: PathBuf = [ folder_name, "watchface.json" ].iter().collect();
+        let file_data = fs::read(path).map_err(|e| format!("Unable to read 'watchface.json': {}", e))?;
+        let mut face: FaceN = serde_json::from_slice(&file_data).map_err(|e| format!("Unable to understand JSON file: {}", e))?;
+        face.read_imgs(folder_name)?;
+        Ok(Self { folder_name, face, debug })
+    }
+
+    // take the images we already have from the old face, and read any new ones
+    fn update(&mut self, mut face: FaceN) -> Result<(), String> {
+        let mut loaded: HashMap<String, ImgData> = HashMap::new();
+        for (_, id) in self.face.all_img_data_mut() {
+            if let Some(f) = &id.file_name {
+                loaded.insert(f.clone(), id.clone());
+            }
+        }
+        for (_, id) in face.all_img_data_mut() {
+            match id.file_name.as_ref().and_then(|f| loaded.get(f)) {
+                Some(old) => *id = old.clone(),
+                None => id.read_img(self.folder_name)?,
+            }
+        }
+        self.face = face;
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let json_data = serde_json::to_string_pretty(&self.face).map_err(|e| format!("Unable to serialize watchface: {}", e))?;
+        let path: PathBuf = [ self.folder_name, "watchface.json" ].iter().collect();
+        fs::write(path, json_data).map_err(|e| format!("Unable to save 'watchface.json': {}", e))
+    }
+
+    fn handle(&mut self, req: &Request) -> Response {
+        match (req.method.as_str(), req.path.as_str()) {
+            ("GET", "/") => Response::ok("text/html; charset=utf-8", PAGE.as_bytes().to_vec()),
+            ("GET", "/face.json") => match serde_json::to_vec(&self.face) {
+                Ok(v) => Response::ok("application/json", v),
+                Err(e) => Response::error("500 Internal Server Error", &e.to_string()),
+            },
+            ("GET", "/render.bmp") => {
+                let state = match req.query.get("time") {
+                    Some(t) => match RenderState::from_time_str(t) {
+                        Ok(s) => s,
+                        Err(e) => return Response::error("400 Bad Request", &e),
+                    },
+                    None => RenderState::new(),
+                };
+                let markers = req.query.get("markers").map(|m| m == "1").unwrap_or(false);
+                Response::ok("image/bmp", self.face.render(&state, markers).to_bmp())
+            },
+            ("POST", "/face.json") => {
+                let face: FaceN = match serde_json::from_slice(&req.body) {
+                    Ok(f) => f,
+                    Err(e) => return Response::error("400 Bad Request", &format!("Unable to understand JSON: {}", e)),
+                };
+                if let Err(e) = self.update(face).and_then(|_| self.save()) {
+                    return Response::error("500 Internal Server Error", &e);
+                }
+                let mut warnings = self.face.validate();
+                warnings.extend(self.face.check_hands());
+                Response::json("200 OK", serde_json::json!({ "warnings": warnings }))
+            },
+            _ => Response::error("404 Not Found", "Not found"),
+        }
+    }
+
+    // serve until the program is stopped
+    pub fn run(&mut self, port: u16) -> Result<(), String> {
+        // localhost only, there is no authentication
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("Unable to listen on port {}: {}", port, e))?;
+        let port = listener.local_addr().map(|a| a.port()).unwrap_or(port);
+        println!("Editing '{}' at http://127.0.0.1:{}/ , press Ctrl+C to stop.", self.folder_name, port);
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            if stream.set_read_timeout(Some(TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(TIMEOUT))).is_err() {
+                continue;
+            }
+            let response = match read_request(&mut stream) {
+                Ok(req) => {
+                    if self.debug >= 2 { println!("{} {}", req.method, req.path); }
+                    match req.forbidden(port) {
+                        Some(why) => Response::error("403 Forbidden", why),
+                        // a face that makes rendering panic shouldn't stop the server
+                        None => crate::catch("Unable to handle the request", AssertUnwindSafe(|| self.handle(&req)))
+                            .unwrap_or_else(|e| Response::error("500 Internal Server Error", &e)),
+                    }
+                },
+                Err(e) => Response::error("400 Bad Request", &e),
+            };
+            if let Err(e) = write_response(&mut stream, &response) {
+                if self.debug >= 2 { eprintln!("WARNING: Unable to send response: {}", e); }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, host: Option<&str>, origin: Option<&str>, content_type: Option<&str>) -> Request {
+        Request {
+            method: method.to_string(),
+            path: "/face.json".to_string(),
+            query: HashMap::new(),
+            host: host.map(str::to_string),
+            origin: origin.map(str::to_string),
+            content_type: content_type.map(str::to_string),
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn only_our_own_page_is_allowed() {
+        assert_eq!(request("GET", Some("127.0.0.1:8080"), None, None).forbidden(8080), None);
+        assert_eq!(request("GET", Some("LOCALHOST:8080"), Some("http://localhost:8080"), None).forbidden(8080), None);
+        assert_eq!(request("POST", Some("localhost:8080"), Some("http://127.0.0.1:8080"), Some("application/json; charset=utf-8")).forbidden(8080), None);
+
+        assert_eq!(request("GET", None, None, None).forbidden(8080), Some("Wrong Host"));
+        assert_eq!(request("GET", Some("evil.example:8080"), None, None).forbidden(8080), Some("Wrong Host"));
+        assert_eq!(request("GET", Some("127.0.0.1:9090"), None, None).forbidden(8080), Some("Wrong Host"));
+        assert_eq!(request("GET", Some("127.0.0.1:8080"), Some("http://evil.example"), None).forbidden(8080), Some("Wrong Origin"));
+        assert_eq!(request("GET", Some("127.0.0.1:8080"), Some("null"), None).forbidden(8080), Some("Wrong Origin"));
+        assert!(request("POST", Some("127.0.0.1:8080"), None, Some("text/plain")).forbidden(8080).is_some());
+        assert!(request("POST", Some("127.0.0.1:8080"), None, None).forbidden(8080).is_some());
+    }
+}
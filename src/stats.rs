@@ -0,0 +1,339 @@
+//  stats.rs - corpus analysis, to help decode the unknown fields
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// We parse a whole folder of watch faces, and for each unknown field (per e_type) we report
+// the distribution of values, and any strong relationship with the fields we do understand.
+
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use crate::face::FaceN;
+use crate::elements::Element;
+use crate::img_data::ImgData;
+
+
+type UnknownFields = Vec<(String, u32)>;
+type KnownFields = Vec<(String, f64)>;
+
+// one instance of a header, digit set or element
+struct Sample {
+    unknowns: UnknownFields,
+    knowns: KnownFields,
+}
+
+// all the samples for one kind of structure
+#[derive(Default)]
+struct Group {
+    samples: Vec<Sample>,
+}
+
+fn unknown_array(name: &str, a: &[u8]) -> UnknownFields {
+    a.iter().enumerate().map(|(i, b)| (format!("{}[{}]", name, i), *b as u32)).collect()
+}
+
+fn img_fields(name: &str, id: &ImgData) -> KnownFields {
+    vec![ (format!("{}.w", name), id.w as f64),
+          (format!("{}.h", name), id.h as f64),
+          (format!("{}.size", name), (id.header.len() + id.data.len()) as f64) ]
+}
+
+// returns the unknown fields, and the known fields, of an element
+fn element_fields(el: &Element) -> (UnknownFields, KnownFields) {
+    let mut u: UnknownFields = Vec::new();
+    let mut k: KnownFields = Vec::new();
+    match el {
+        Element::Image(e) => {
+            k.push(("x".to_string(), e.x as f64));
+            k.push(("y".to_string(), e.y as f64));
+            k.extend(img_fields("img_data", &e.img_data));
+        },
+        Element::TimeNum(e) => {
+            u.extend(unknown_array("unknown", &e.unknown));
+            for i in 0..4 {
+                k.push((format!("digit_sets[{}]", i), e.digit_sets[i] as f64));
+                k.push((format!("xys[{}].x", i), e.xys[i].x as f64));
+                k.push((format!("xys[{}].y", i), e.xys[i].y as f64));
+            }
+        },
+        Element::DayName(e) => {
+            k.push(("n_type".to_string(), e.n_type as f64));
+            k.push(("x".to_string(), e.x as f64));
+            k.push(("y".to_string(), e.y as f64));
+            k.extend(img_fields("img_data[0]", &e.img_data[0]));
+        },
+        Element::BatteryFill(e) => {
+            u.push(("unknown0".to_string(), e.unknown0));
+            u.push(("unknown1".to_string(), e.unknown1));
+            k.push(("x".to_string(), e.x as f64));
+            k.push(("y".to_string(), e.y as f64));
+            k.push(("x1".to_string(), e.x1 as f64));
+            k.push(("y1".to_string(), e.y1 as f64));
+            k.push(("x2".to_string(), e.x2 as f64));
+            k.push(("y2".to_string(), e.y2 as f64));
+            k.extend(img_fields("img_data", &e.img_data));
+            k.extend(img_fields("image_data1", &e.image_data1));
+            k.extend(img_fields("image_data2", &e.image_data2));
+        },
+        Element::HeartRateNum(e) => {
+            u.extend(unknown_array("unknown", &e.unknown));
+            k.push(("digit_set".to_string(), e.digit_set as f64));
+            k.push(("align".to_string(), e.align as f64));
+            k.push(("x".to_string(), e.x as f64));
+            k.push(("y".to_string(), e.y as f64));
+        },
+        Element::StepsNum(e) => {
+            u.extend(unknown_array("unknown", &e.unknown));
+            k.push(("digit_set".to_string(), e.digit_set as f64));
+            k.push(("align".to_string(), e.align as f64));
+            k.push(("x".to_string(), e.x as f64));
+            k.push(("y".to_string(), e.y as f64));
+        },
+        Element::KCalNum(e) => {
+            u.extend(unknown_array("unknown", &e.unknown));
+            k.push(("digit_set".to_string(), e.digit_set as f64));
+            k.push(("align".to_string(), e.align as f64));
+            k.push(("x".to_string(), e.x as f64));
+            k.push(("y".to_string(), e.y as f64));
+        },
+        Element::TimeHand(e) => {
+            k.push(("h_type".to_string(), e.h_type as f64));
+            k.push(("pivot_x".to_string(), e.pivot_x as f64));
+            k.push(("pivot_y".to_string(), e.pivot_y as f64));
+            k.push(("x".to_string(), e.x as f64));
+            k.push(("y".to_string(), e.y as f64));
+            k.extend(img_fields("img_data", &e.img_data));
+        },
+        Element::DayNum(e) => {
+            k.push(("digit_set".to_string(), e.digit_set as f64));
+            k.push(("align".to_string(), e.align as f64));
+            for i in 0..2 {
+                k.push((format!("xys[{}].x", i), e.xys[i].x as f64));
+                k.push((format!("xys[{}].y", i), e.xys[i].y as f64));
+            }
+        },
+        Element::MonthNum(e) => {
+            k.push(("digit_set".to_string(), e.digit_set as f64));
+            k.push(("align".to_string(), e.align as f64));
+            for i in 0..2 {
+                k.push((format!("xys[{}].x", i), e.xys[i].x as f64));
+                k.push((format!("xys[{}].y", i), e.xys[i].y as f64));
+            }
+        },
+        Element::BarDisplay(e) => {
+            k.push(("b_type".to_string(), e.b_type as f64));
+            k.push(("count".to_string(), e.count as f64));
+            k.push(("x".to_string(), e.x as f64));
+            k.push(("y".to_string(), e.y as f64));
+        },
+        Element::Weather(e) => {
+            k.push(("count".to_string(), e.count as f64));
+            k.push(("x".to_string(), e.x as f64));
+            k.push(("y".to_string(), e.y as f64));
+        },
+        Element::Unknown29(e) => {
+            u.push(("unknown".to_string(), e.unknown as u32));
+        },
+        Element::Dash(e) => {
+            k.extend(img_fields("img_data", &e.img_data));
+        },
+        _ => {},
+    }
+    (u, k)
+}
+
+// fields of the whole face, which are added to every sample
+fn face_fields(f: &FaceN) -> KnownFields {
+    vec![ ("face.api_ver".to_string(), f.api_ver as f64),
+          ("face.preview.w".to_string(), f.preview_img_data.w as f64),
+          ("face.preview.h".to_string(), f.preview_img_data.h as f64),
+          ("face.digits.len".to_string(), f.digits.len() as f64),
+          ("face.elements.len".to_string(), f.elements.len() as f64) ]
+}
+
+// pearson correlation coefficient, or None if either series is constant
+fn correlation(a: &[f64], b: &[f64]) -> Option<f64> {
+    let n = a.len() as f64;
+    let mean_a = a.iter().sum::<f64>() / n;
+    let mean_b = b.iter().sum::<f64>() / n;
+    let mut cov = 0.0;
+    let mut var_a = 0.0;
+    let mut var_b = 0.0;
+    for i in 0..a.len() {
+        cov += (a[i] - mean_a) * (b[i] - mean_b);
+        var_a += (a[i] - mean_a) * (a[i] - mean_a);
+        var_b += (b[i] - mean_b) * (b[i] - mean_b);
+    }
+    if var_a == 0.0 || var_b == 0.0 {
+        return None;
+    }
+    Some(cov / (var_a * var_b).sqrt())
+}
+
+pub struct Corpus {
+    groups: BTreeMap<String, Group>,
+    face_count: usize,
+}
+
+impl Corpus {
+    pub fn new() -> Self {
+        Self {
+            groups: BTreeMap::new(),
+            face_count: 0,
+        }
+    }
+
+    fn add_sample(&mut self, group: &str, unknowns: UnknownFields, mut knowns: KnownFields, face_knowns: &[(String, f64)]) {
+        knowns.extend(face_knowns.iter().cloned());
+        self.groups.entry(group.to_string()).or_default().samples.push(Sample { unknowns, knowns });
+    }
+
+    pub fn add_face(&mut self, f: &FaceN) {
+        let fk = face_fields(f);
+        self.face_count += 1;
+
+        // the main header
+        self.add_sample("header", vec![ ("unknown".to_string(), f.unknown as u32) ], Vec::new(), &fk);
+
+        // the digit sets
+        for (n, d) in f.digits.iter().enumerate() {
+            let mut k = vec![ ("set".to_string(), n as f64) ];
+            k.extend(img_fields("img_data[0]", &d.img_data[0]));
+            self.add_sample("digits", vec![ ("unknown".to_string(), d.unknown as u32) ], k, &fk);
+        }
+
+        // the elements
+        for el in f.elements.iter() {
+            let (u, k) = element_fields(el);
+            if u.is_empty() {
+                continue;
+            }
+            let name = format!("e_type {:2} {}", el.e_type(), el.type_name());
+            self.add_sample(&name, u, k, &fk);
+        }
+    }
+
+    // load every binary watch face file in a folder. returns the number of files that could not be parsed.
+    pub fn add_folder(&mut self, folder_name: &str, debug: u8) -> Result<usize, String> {
+        let entries = fs::read_dir(folder_name).map_err(|e| format!("Unable to read folder '{}': {}", folder_name, e))?;
+        let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_file()).collect();
+        paths.sort();
+        let mut failed = 0;
+        for path in paths.iter() {
+            let file_data = match fs::read(path) {
+                Ok(fd) => fd,
+                Err(e) => { eprintln!("WARNING: Unable to read '{}': {}", path.display(), e); failed += 1; continue; },
+            };
+            // the parser returns errors for damaged files, but one bad file still mustn't stop the whole run
+            let f = match crate::catch("unable to parse it", || FaceN::from_bin(&file_data)).and_then(|r| r) {
+                Ok(f) => f,
+                Err(e) => { eprintln!("WARNING: Skipping '{}': {}", path.display(), e); failed += 1; continue; },
+            };
+            if debug >= 2 { eprintln!("Loaded '{}'", path.display()); }
+            self.add_face(&f);
+        }
+        Ok(failed)
+    }
+
+    pub fn report(&self) -> String {
+        let mut s = String::new();
+        s += &format!("Faces analysed: {}\n", self.face_count);
+        for (name, group) in self.groups.iter() {
+            let n = group.samples.len();
+            s += &format!("\n== {} ({} samples) ==\n", name, n);
+
+            // every sample in a group has the same fields, in the same order
+            let first = &group.samples[0];
+            for (ui, (uname, _)) in first.unknowns.iter().enumerate() {
+                let values: Vec<u32> = group.samples.iter().map(|sm| sm.unknowns[ui].1).collect();
+
+                // distribution of values
+                let mut dist: BTreeMap<u32, usize> = BTreeMap::new();
+                for v in values.iter() {
+                    *dist.entry(*v).or_insert(0) += 1;
+                }
+                if dist.len() == 1 {
+                    s += &format!("  {:<14} constant 0x{:X} ({})\n", uname, values[0], values[0]);
+                    continue;
+                }
+                let mut dist: Vec<(u32, usize)> = dist.into_iter().collect();
+                dist.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                let shown: Vec<String> = dist.iter().take(12).map(|(v, c)| format!("0x{:X}({}):{}", v, v, c)).collect();
+                s += &format!("  {:<14} {} distinct: {}{}\n", uname, dist.len(), shown.join(" "), if dist.len() > 12 { " ..." } else { "" });
+
+                // relationships with the known fields
+                let uv: Vec<f64> = values.iter().map(|v| *v as f64).collect();
+                for (ki, (kname, _)) in first.knowns.iter().enumerate() {
+                    let kv: Vec<f64> = group.samples.iter().map(|sm| sm.knowns[ki].1).collect();
+                    let equal = uv.iter().zip(kv.iter()).filter(|(a, b)| a == b).count();
+                    if equal * 10 >= n * 9 {
+                        s += &format!("      equals {} in {}/{} samples\n", kname, equal, n);
+                    } else if let Some(r) = correlation(&uv, &kv) {
+                        if r.abs() >= 0.9 {
+                            s += &format!("      correlates with {} (r = {:.3})\n", kname, r);
+                        }
+                    }
+                }
+            }
+        }
+        s
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+    use crate::face_builder::FaceBuilder;
+    use crate::img::Img;
+    use crate::Output;
+
+    // a face with one heart rate number, whose first unknown byte is its x
+    fn face(x: u16) -> FaceN {
+        let digits: Vec<Img> = (0..10).map(|_| Img::new(4, 6)).collect();
+        let mut f = FaceBuilder::new(&DEVICES[0]).preview(Img::new(4, 4)).digit_set(digits)
+            .heart_rate_num(0, 0, x, 20).build().unwrap();
+        if let Element::HeartRateNum(e) = &mut f.elements[0] {
+            e.unknown[0] = x as u8;
+        }
+        f
+    }
+
+    #[test]
+    fn counts_samples_across_faces() {
+        let mut corpus = Corpus::new();
+        corpus.add_face(&face(10));
+        corpus.add_face(&face(30));
+        assert_eq!(corpus.face_count, 2);
+        let counts: Vec<(&str, usize)> = corpus.groups.iter().map(|(k, g)| (k.as_str(), g.samples.len())).collect();
+        assert_eq!(counts, vec![ ("digits", 2), ("e_type  6 heart_rate_num", 2), ("header", 2) ]);
+        let report = corpus.report();
+        assert!(report.starts_with("Faces analysed: 2\n"));
+        assert!(report.contains("unknown[0]     2 distinct: 0xA(10):1 0x1E(30):1\n      equals x in 2/2 samples\n"));
+        assert!(report.contains("unknown[1]     constant 0x0 (0)\n"));
+    }
+
+    #[test]
+    fn stats_command_result() {
+        let folder = std::env::temp_dir().join(format!("extrathundertool-stats-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("a.bin"), face(10).to_bin().unwrap()).unwrap();
+        fs::write(folder.join("b.bin"), face(30).to_bin().unwrap()).unwrap();
+        fs::write(folder.join("c.bin"), [ 4, 0, 0 ]).unwrap();
+        let mut out = Output { json: true, debug: 0, warnings: Vec::new() };
+        let result = crate::stats_cmd(&mut out, folder.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(result["failed"], 1);
+        assert!(result["report"].as_str().unwrap().starts_with("Faces analysed: 2\n"));
+        assert_eq!(result.as_object().unwrap().len(), 2);
+        assert_eq!(out.warnings, vec![ "1 files could not be analysed.".to_string() ]);
+    }
+}
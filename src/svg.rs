@@ -0,0 +1,233 @@
+//  svg.rs - a layout diagram of the watch face, as an SVG file
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// One pixel of the watch screen is one unit of the SVG. Each element is a labelled rectangle in the colour of
+// its e_type. Digit positions are numbered dashed boxes, the battery fill area is hatched and hand pivots
+// are crosses. A picture of the face can go underneath.
+
+
+use crate::elements::Element;
+use crate::face::FaceN;
+use crate::img::Img;
+use crate::util::to_base64;
+
+
+// colours for each e_type, so the same kind of element always looks the same
+fn colour(el: &Element) -> &'static str {
+    match el {
+        Element::Image(_) => "#9e9e9e",
+        Element::TimeNum(_) => "#e53935",
+        Element::DayName(_) => "#8e24aa",
+        Element::BatteryFill(_) => "#43a047",
+        Element::HeartRateNum(_) => "#d81b60",
+        Element::StepsNum(_) => "#1e88e5",
+        Element::KCalNum(_) => "#fb8c00",
+        Element::TimeHand(_) => "#00acc1",
+        Element::DayNum(_) => "#5e35b1",
+        Element::MonthNum(_) => "#3949ab",
+        Element::BarDisplay(_) => "#7cb342",
+        Element::Weather(_) => "#039be5",
+        Element::Unknown29(_) => "#6d4c41",
+        Element::Dash(_) => "#546e7a",
+        Element::Unknown => "#000000",
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+struct Svg
+{
+    body: String,
+}
+
+impl Svg
+{
+    fn rect(&mut self, x: i32, y: i32, w: u16, h: u16, colour: &str, style: &str) {
+        self.body += &format!("  <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" stroke=\"{}\" {}/>\n", x, y, w, h, colour, style);
+    }
+
+    fn label(&mut self, x: i32, y: i32, colour: &str, text: &str) {
+        self.body += &format!("  <text x=\"{}\" y=\"{}\" fill=\"{}\">{}</text>\n", x, y, colour, escape(text));
+    }
+
+    fn cross(&mut self, x: i32, y: i32, colour: &str) {
+        self.body += &format!("  <path d=\"M{} {}h9M{} {}v9\" stroke=\"{}\"/>\n", x - 4, y, x, y - 4, colour);
+        self.body += &format!("  <circle cx=\"{}\" cy=\"{}\" r=\"2\" fill=\"none\" stroke=\"{}\"/>\n", x, y, colour);
+    }
+
+    // a labelled box for an element
+    fn element(&mut self, n: usize, el: &Element, x: i32, y: i32, w: u16, h: u16) {
+        let c = colour(el);
+        self.rect(x, y, w, h, c, &format!("fill=\"{}\" fill-opacity=\"0.15\"", c));
+        self.label(x + 1, y + 8, c, &format!("{} {}", n, el.type_name()));
+    }
+
+    // numbered boxes where each digit goes
+    fn digits(&mut self, el: &Element, xys: &[(u16, u16)], sizes: &[(u16, u16)]) {
+        let c = colour(el);
+        for (i, ((x, y), (w, h))) in xys.iter().zip(sizes).enumerate() {
+            self.rect(*x as i32, *y as i32, *w, *h, c, "stroke-dasharray=\"2 1\"");
+            self.label(*x as i32 + 1, *y as i32 + *h as i32 - 2, c, &i.to_string());
+        }
+    }
+}
+
+// the size of a digit in a digit set. 10x10 if the set doesn't exist.
+fn digit_size(face: &FaceN, set: u8) -> (u16, u16) {
+    face.digits.get(set as usize).and_then(|d| d.img_data.first()).map(|id| (id.w, id.h)).unwrap_or((10, 10))
+}
+
+// the box around a list of boxes
+fn bounds(xys: &[(u16, u16)], sizes: &[(u16, u16)]) -> (i32, i32, u16, u16) {
+    let x0 = xys.iter().map(|p| p.0).min().unwrap_or(0);
+    let y0 = xys.iter().map(|p| p.1).min().unwrap_or(0);
+    let x1 = xys.iter().zip(sizes).map(|(p, s)| p.0.saturating_add(s.0)).max().unwrap_or(0);
+    let y1 = xys.iter().zip(sizes).map(|(p, s)| p.1.saturating_add(s.1)).max().unwrap_or(0);
+    (x0 as i32, y0 as i32, x1 - x0, y1 - y0)
+}
+
+impl FaceN
+{
+    // background is drawn underneath, stretched to the screen
+    pub fn layout_svg(&self, background: Option<&Img>) -> Result<String, String> {
+        let (w, h) = self.screen_size();
+        let mut svg = Svg { body: String::new() };
+
+        if let Some(img) = background {
+            svg.body += &format!("  <image x=\"0\" y=\"0\" width=\"{}\" height=\"{}\" preserveAspectRatio=\"none\" opacity=\"0.6\" href=\"data:image/png;base64,{}\"/>\n",
+                w, h, to_base64(&img.to_png()?));
+        }
+
+        for (n, el) in self.elements.iter().enumerate() {
+            match el {
+                Element::Image(e) => svg.element(n, el, e.x as i32, e.y as i32, e.img_data.w, e.img_data.h),
+                Element::DayName(e) => {
+                    let (iw, ih) = e.img_data.first().map(|id| (id.w, id.h)).unwrap_or((0, 0));
+                    svg.element(n, el, e.x as i32, e.y as i32, iw, ih);
+                },
+                Element::BarDisplay(e) => {
+                    let (iw, ih) = e.img_data.first().map(|id| (id.w, id.h)).unwrap_or((0, 0));
+                    svg.element(n, el, e.x as i32, e.y as i32, iw, ih);
+                },
+                Element::Weather(e) => {
+                    let (iw, ih) = e.img_data.first().map(|id| (id.w, id.h)).unwrap_or((0, 0));
+                    svg.element(n, el, e.x as i32, e.y as i32, iw, ih);
+                },
+                Element::BatteryFill(e) => {
+                    svg.element(n, el, e.x as i32, e.y as i32, e.img_data.w, e.img_data.h);
+                    // the part the watch fills in, from the image top left
+                    let fx = e.x as i32 + e.x1 as i32;
+                    let fy = e.y as i32 + e.y1 as i32;
+                    let fw = (e.x2 as u16).saturating_sub(e.x1 as u16);
+                    let fh = (e.y2 as u16).saturating_sub(e.y1 as u16);
+                    svg.rect(fx, fy, fw, fh, colour(el), "fill=\"url(#hatch)\"");
+                },
+                Element::TimeNum(e) => {
+                    let xys: Vec<(u16, u16)> = e.xys.iter().map(|xy| (xy.x, xy.y)).collect();
+                    let sizes: Vec<(u16, u16)> = e.digit_sets.iter().map(|s| digit_size(self, *s)).collect();
+                    let (bx, by, bw, bh) = bounds(&xys, &sizes);
+                    svg.element(n, el, bx, by, bw, bh);
+                    svg.digits(el, &xys, &sizes);
+                },
+                Element::DayNum(e) => {
+                    let xys: Vec<(u16, u16)> = e.xys.iter().map(|xy| (xy.x, xy.y)).collect();
+                    let sizes = vec![ digit_size(self, e.digit_set); xys.len() ];
+                    let (bx, by, bw, bh) = bounds(&xys, &sizes);
+                    svg.element(n, el, bx, by, bw, bh);
+                    svg.digits(el, &xys, &sizes);
+                },
+                Element::MonthNum(e) => {
+                    let xys: Vec<(u16, u16)> = e.xys.iter().map(|xy| (xy.x, xy.y)).collect();
+                    let sizes = vec![ digit_size(self, e.digit_set); xys.len() ];
+                    let (bx, by, bw, bh) = bounds(&xys, &sizes);
+                    svg.element(n, el, bx, by, bw, bh);
+                    svg.digits(el, &xys, &sizes);
+                },
+                // the number of digits shown changes, so only the first digit is boxed
+                Element::HeartRateNum(e) => {
+                    let (dw, dh) = digit_size(self, e.digit_set);
+                    svg.element(n, el, e.x as i32, e.y as i32, dw, dh);
+                },
+                Element::StepsNum(e) => {
+                    let (dw, dh) = digit_size(self, e.digit_set);
+                    svg.element(n, el, e.x as i32, e.y as i32, dw, dh);
+                },
+                Element::KCalNum(e) => {
+                    let (dw, dh) = digit_size(self, e.digit_set);
+                    svg.element(n, el, e.x as i32, e.y as i32, dw, dh);
+                },
+                Element::TimeHand(e) => {
+                    // the hand pointing to 12 o'clock, and its pivot on the screen
+                    let c = colour(el);
+                    svg.rect(e.x as i32 - e.pivot_x as i32, e.y as i32 - e.pivot_y as i32, e.img_data.w, e.img_data.h, c, "stroke-dasharray=\"4 2\"");
+                    svg.cross(e.x as i32, e.y as i32, c);
+                    svg.label(e.x as i32 + 4, e.y as i32 + 10 + 8 * e.h_type as i32, c, &format!("{} time_hand {}", n, e.h_type));
+                },
+                Element::Unknown29(_) | Element::Dash(_) | Element::Unknown => {},     // no position
+            }
+        }
+
+        let mut s = String::new();
+        s += "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+        s += &format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n", w, h, w, h);
+        s += "  <defs>\n";
+        s += "    <pattern id=\"hatch\" width=\"4\" height=\"4\" patternUnits=\"userSpaceOnUse\"><path d=\"M0 4L4 0\" stroke=\"#43a047\"/></pattern>\n";
+        s += "  </defs>\n";
+        s += &format!("  <rect width=\"{}\" height=\"{}\" fill=\"#000\"/>\n", w, h);
+        s += "  <g fill=\"none\" stroke-width=\"1\" font-family=\"sans-serif\" font-size=\"7\">\n";
+        s += &svg.body;
+        s += "  </g>\n</svg>\n";
+        Ok(s)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DEVICES;
+    use crate::elements::HandType;
+    use crate::face_builder::FaceBuilder;
+    use crate::xy::XY;
+
+    #[test]
+    fn one_image_snapshot() {
+        let face = FaceBuilder::new(&DEVICES[0]).preview(Img::new(4, 4)).image(10, 20, Img::new(30, 40)).build().unwrap();
+        assert_eq!(face.layout_svg(None).unwrap(), r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="240" height="296" viewBox="0 0 240 296">
+  <defs>
+    <pattern id="hatch" width="4" height="4" patternUnits="userSpaceOnUse"><path d="M0 4L4 0" stroke="#43a047"/></pattern>
+  </defs>
+  <rect width="240" height="296" fill="#000"/>
+  <g fill="none" stroke-width="1" font-family="sans-serif" font-size="7">
+  <rect x="10" y="20" width="30" height="40" stroke="#9e9e9e" fill="#9e9e9e" fill-opacity="0.15"/>
+  <text x="11" y="28" fill="#9e9e9e">0 image</text>
+  </g>
+</svg>
+"##);
+    }
+
+    #[test]
+    fn digits_and_hand() {
+        let digits: Vec<Img> = (0..10).map(|_| Img::new(8, 12)).collect();
+        let face = FaceBuilder::new(&DEVICES[0]).preview(Img::new(4, 4)).digit_set(digits)
+            .day_num(0, 0, [ XY { x: 50, y: 60 }, XY { x: 58, y: 60 } ])
+            .hand(HandType::Minute, Img::new(6, 100), XY { x: 3, y: 90 }).build().unwrap();
+        let svg = face.layout_svg(None).unwrap();
+        // the box around both digits, then each digit
+        assert!(svg.contains("<rect x=\"50\" y=\"60\" width=\"16\" height=\"12\" stroke=\"#5e35b1\" fill=\"#5e35b1\" fill-opacity=\"0.15\"/>\n"));
+        assert!(svg.contains("<rect x=\"58\" y=\"60\" width=\"8\" height=\"12\" stroke=\"#5e35b1\" stroke-dasharray=\"2 1\"/>\n  <text x=\"59\" y=\"70\" fill=\"#5e35b1\">1</text>\n"));
+        // the hand pointing up from the centre of the screen, and its pivot
+        assert!(svg.contains("<rect x=\"117\" y=\"58\" width=\"6\" height=\"100\" stroke=\"#00acc1\" stroke-dasharray=\"4 2\"/>\n"));
+        assert!(svg.contains("<path d=\"M116 148h9M120 144v9\" stroke=\"#00acc1\"/>\n"));
+    }
+}
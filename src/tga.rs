@@ -0,0 +1,272 @@
+//  tga.rs - Truevision TGA image files
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// An 18 byte header, an optional ID, then the pixels as BGR or BGRA, bottom row first unless the descriptor says otherwise.
+// We read uncompressed and RLE true colour (types 2 and 10) at 24 or 32 bits, and grayscale (types 3 and 11) at 8 bits.
+// Colour mapped images are not supported. We write 32 bit BGRA, top row first, uncompressed or RLE.
+//
+// RLE packets start with a byte: if the top bit is set, the next pixel is repeated (low 7 bits + 1) times,
+// otherwise (low 7 bits + 1) pixels follow as they are.
+
+
+use crate::img::{Img, ImgFormat};
+use crate::util::{get_u16, put_u16};
+
+
+const HEADER_SIZE: usize = 18;
+const MAX_PACKET: usize = 128;
+
+const TYPE_TRUECOLOR: u8 = 2;
+const TYPE_GRAYSCALE: u8 = 3;
+const TYPE_TRUECOLOR_RLE: u8 = 10;
+const TYPE_GRAYSCALE_RLE: u8 = 11;
+
+const DESC_RIGHT_TO_LEFT: u8 = 0x10;
+const DESC_TOP_TO_BOTTOM: u8 = 0x20;
+
+
+// the pixels of an RLE image, uncompressed
+fn unpack_rle(data: &[u8], pixel_size: usize, pixels: usize) -> Result<Vec<u8>, String> {
+    let mut out: Vec<u8> = Vec::with_capacity(pixels * pixel_size);
+    let mut pos = 0;
+    while out.len() < pixels * pixel_size {
+        let packet = *data.get(pos).ok_or_else(|| "TGA RLE data is incomplete".to_string())?;
+        let count = (packet & 0x7F) as usize + 1;
+        pos += 1;
+        if packet & 0x80 != 0 {
+            let p = data.get(pos..pos + pixel_size).ok_or_else(|| "TGA RLE data is incomplete".to_string())?;
+            (0..count).for_each(|_| out.extend(p));
+            pos += pixel_size;
+        } else {
+            let p = data.get(pos..pos + count * pixel_size).ok_or_else(|| "TGA RLE data is incomplete".to_string())?;
+            out.extend(p);
+            pos += count * pixel_size;
+        }
+    }
+    // a packet may not cross the end of the image, but be forgiving
+    out.truncate(pixels * pixel_size);
+    Ok(out)
+}
+
+// RLE packets for one row. packets don't cross rows, as some readers expect.
+fn pack_rle(row: &[u8], pixel_size: usize, b: &mut Vec<u8>) {
+    let pixels: Vec<&[u8]> = row.chunks_exact(pixel_size).collect();
+    let mut x = 0;
+    while x < pixels.len() {
+        let mut run = 1;
+        while x + run < pixels.len() && run < MAX_PACKET && pixels[x + run] == pixels[x] {
+            run += 1;
+        }
+        if run > 1 {
+            b.push(0x80 | (run - 1) as u8);
+            b.extend(pixels[x]);
+            x += run;
+            continue;
+        }
+        // raw pixels, up to the next run of two or more
+        let mut count = 1;
+        while x + count < pixels.len() && count < MAX_PACKET && !(x + count + 1 < pixels.len() && pixels[x + count] == pixels[x + count + 1]) {
+            count += 1;
+        }
+        b.push((count - 1) as u8);
+        pixels[x..x + count].iter().for_each(|p| b.extend(*p));
+        x += count;
+    }
+}
+
+impl Img {
+    // reads true colour and grayscale TGA files, the result is Argb8888
+    pub fn from_tga(bytes: &[u8]) -> Result<Img, String> {
+        if bytes.len() < HEADER_SIZE {
+            return Err("TGA file is too small".to_string());
+        }
+        let id_size = bytes[0] as usize;
+        let colour_map_type = bytes[1];
+        let image_type = bytes[2];
+        let w = get_u16(bytes, 12) as u32;
+        let h = get_u16(bytes, 14) as u32;
+        let bpp = bytes[16];
+        let descriptor = bytes[17];
+
+        if colour_map_type != 0 {
+            return Err("Colour mapped TGA files are not supported".to_string());
+        }
+        let grayscale = match image_type {
+            TYPE_TRUECOLOR | TYPE_TRUECOLOR_RLE => false,
+            TYPE_GRAYSCALE | TYPE_GRAYSCALE_RLE => true,
+            _ => return Err(format!("TGA image type {} is not supported", image_type)),
+        };
+        match (grayscale, bpp) {
+            (false, 24) | (false, 32) | (true, 8) => {},
+            _ => return Err(format!("TGA at {} bits per pixel is not supported", bpp)),
+        }
+        if w == 0 || h == 0 {
+            return Err("TGA image is empty".to_string());
+        }
+
+        let pixel_size = bpp as usize / 8;
+        let pixels = w as usize * h as usize;
+        let data = &bytes[(HEADER_SIZE + id_size).min(bytes.len())..];
+        let data = if image_type == TYPE_TRUECOLOR_RLE || image_type == TYPE_GRAYSCALE_RLE {
+            unpack_rle(data, pixel_size, pixels)?
+        } else {
+            data.get(..pixels * pixel_size).ok_or_else(|| "TGA file is too small".to_string())?.to_vec()
+        };
+
+        // only use alpha if the descriptor says there are alpha bits, some writers leave it as 0
+        let has_alpha = bpp == 32 && descriptor & 0x0F != 0;
+        let mut img = Img::new(w, h);
+        for (i, p) in data.chunks_exact(pixel_size).enumerate() {
+            let (x, y) = (i as u32 % w, i as u32 / w);
+            let x = if descriptor & DESC_RIGHT_TO_LEFT != 0 { w - 1 - x } else { x };
+            let y = if descriptor & DESC_TOP_TO_BOTTOM != 0 { y } else { h - 1 - y };
+            let argb = match pixel_size {
+                1 => [ 255, p[0], p[0], p[0] ],
+                3 => [ 255, p[2], p[1], p[0] ],
+                _ => [ if has_alpha { p[3] } else { 255 }, p[2], p[1], p[0] ],
+            };
+            img.put_pixel(x, y, argb);
+        }
+        Ok(img)
+    }
+
+    // 32 bit BGRA, top row first
+    pub fn to_tga(&self, rle: bool) -> Vec<u8> {
+        let mut img = self.clone();
+        img.convert_format(ImgFormat::Argb8888);
+        let mut b: Vec<u8> = vec![0; HEADER_SIZE];
+        b[2] = if rle { TYPE_TRUECOLOR_RLE } else { TYPE_TRUECOLOR };
+        put_u16(&mut b, 12, img.w as u16);
+        put_u16(&mut b, 14, img.h as u16);
+        b[16] = 32;
+        b[17] = DESC_TOP_TO_BOTTOM | 8;          // 8 alpha bits
+        // we store ARGB, the TGA file wants BGRA
+        let bgra: Vec<u8> = img.data.chunks_exact(4).flat_map(|p| [ p[3], p[2], p[1], p[0] ]).collect();
+        if rle {
+            for row in bgra.chunks_exact((img.w as usize * 4).max(1)) {
+                pack_rle(row, 4, &mut b);
+            }
+        } else {
+            b.extend(bgra);
+        }
+        b
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a 4x2 image with a run of one colour, and pixels that are all different
+    fn four_by_two() -> Img {
+        let mut img = Img::new(4, 2);
+        for x in 0..4 {
+            img.put_pixel(x, 0, [ 255, 10, 20, 30 ]);
+            img.put_pixel(x, 1, [ 64 * x as u8, 1, 2, x as u8 ]);
+        }
+        img
+    }
+
+    // a header for reading, with no ID
+    fn header(image_type: u8, w: u16, h: u16, bpp: u8, descriptor: u8) -> Vec<u8> {
+        let mut b = vec![0; HEADER_SIZE];
+        b[2] = image_type;
+        put_u16(&mut b, 12, w);
+        put_u16(&mut b, 14, h);
+        b[16] = bpp;
+        b[17] = descriptor;
+        b
+    }
+
+    #[test]
+    fn round_trip() {
+        let img = four_by_two();
+        let b = img.to_tga(false);
+        assert_eq!(b.len(), HEADER_SIZE + 4 * 2 * 4);
+        assert_eq!(b[HEADER_SIZE..HEADER_SIZE + 4], [ 30, 20, 10, 255 ]);
+        assert_eq!(Img::from_tga(&b).unwrap().data, img.data);
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let img = four_by_two();
+        let b = img.to_tga(true);
+        // the top row is one repeated packet, the bottom row one raw packet
+        assert_eq!(b[2], TYPE_TRUECOLOR_RLE);
+        assert_eq!(b[HEADER_SIZE..HEADER_SIZE + 5], [ 0x83, 30, 20, 10, 255 ]);
+        assert_eq!(b[HEADER_SIZE + 5], 0x03);
+        assert_eq!(b.len(), HEADER_SIZE + 5 + 1 + 4 * 4);
+        assert_eq!(Img::from_tga(&b).unwrap().data, img.data);
+    }
+
+    #[test]
+    fn packets() {
+        // two raw, a run of three, then one raw. a run doesn't go over 128 pixels
+        let mut b = Vec::new();
+        pack_rle(&[ 1, 2, 3, 3, 3, 4 ], 1, &mut b);
+        assert_eq!(b, [ 0x01, 1, 2,  0x82, 3,  0x00, 4 ]);
+        assert_eq!(unpack_rle(&b, 1, 6).unwrap(), [ 1, 2, 3, 3, 3, 4 ]);
+        let mut b = Vec::new();
+        pack_rle(&[ 9; 130 ], 1, &mut b);
+        assert_eq!(b, [ 0xFF, 9,  0x81, 9 ]);
+        assert!(unpack_rle(&[ 0x02, 1, 2 ], 1, 3).is_err());
+    }
+
+    #[test]
+    fn grayscale_bottom_row_first() {
+        let mut b = header(TYPE_GRAYSCALE, 2, 2, 8, 0);
+        b.extend([ 1, 2,  3, 4 ]);
+        let img = Img::from_tga(&b).unwrap();
+        assert_eq!(img.get_pixel(0, 1), [ 255, 1, 1, 1 ]);
+        assert_eq!(img.get_pixel(1, 0), [ 255, 4, 4, 4 ]);
+
+        let mut b = header(TYPE_GRAYSCALE_RLE, 2, 2, 8, 0);
+        b.extend([ 0x81, 1,  0x01, 3, 4 ]);
+        let img = Img::from_tga(&b).unwrap();
+        assert_eq!(img.get_pixel(1, 1), [ 255, 1, 1, 1 ]);
+        assert_eq!(img.get_pixel(0, 0), [ 255, 3, 3, 3 ]);
+    }
+
+    #[test]
+    fn descriptor() {
+        // 24 bit, top row first and right to left
+        let mut b = header(TYPE_TRUECOLOR, 2, 1, 24, DESC_TOP_TO_BOTTOM | DESC_RIGHT_TO_LEFT);
+        b.extend([ 3, 2, 1,  6, 5, 4 ]);
+        let img = Img::from_tga(&b).unwrap();
+        assert_eq!(img.get_pixel(0, 0), [ 255, 4, 5, 6 ]);
+        assert_eq!(img.get_pixel(1, 0), [ 255, 1, 2, 3 ]);
+
+        // 32 bit with no alpha bits in the descriptor is opaque, and an ID is skipped
+        let mut b = header(TYPE_TRUECOLOR, 1, 1, 32, DESC_TOP_TO_BOTTOM);
+        b[0] = 2;
+        b.extend([ b'h', b'i',  3, 2, 1, 0 ]);
+        assert_eq!(Img::from_tga(&b).unwrap().get_pixel(0, 0), [ 255, 1, 2, 3 ]);
+    }
+
+    #[test]
+    fn bad_files() {
+        let mut truncated = four_by_two().to_tga(false);
+        truncated.pop();
+        let mut mapped = header(TYPE_TRUECOLOR, 1, 1, 24, 0);
+        mapped[1] = 1;
+        for (b, error) in [
+            (vec![0; 10], "TGA file is too small"),
+            (truncated, "TGA file is too small"),
+            (four_by_two().to_tga(true)[..30].to_vec(), "TGA RLE data is incomplete"),
+            (mapped, "Colour mapped TGA files are not supported"),
+            (header(1, 1, 1, 8, 0), "TGA image type 1 is not supported"),
+            (header(TYPE_TRUECOLOR, 1, 1, 16, 0), "TGA at 16 bits per pixel is not supported"),
+            (header(TYPE_TRUECOLOR, 0, 1, 24, 0), "TGA image is empty"),
+        ] {
+            assert_eq!(Img::from_tga(&b).err().as_deref(), Some(error));
+        }
+    }
+}
@@ -0,0 +1,433 @@
+//  tui.rs - browse a binary watch face file in the terminal
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// Plain ANSI escape codes, with 'stty' for raw keyboard input, so it works over SSH without extra libraries.
+// Images are drawn with half block characters (two pixels per character), or as sixel graphics.
+
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+use crate::face::{FaceN, Region};
+use crate::img::{Img, ResizeFilter};
+use crate::img_data::ImgData;
+use crate::render::decode;
+
+
+const LEFT_WIDTH: usize = 30;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+enum Pane {
+    Fields,
+    Preview,
+    Hex,
+}
+
+// one line in the list on the left
+struct Item {
+    name: String,
+    fields: String,
+    imgs: Vec<ImgData>,
+    region: Option<Region>,
+}
+
+#[derive(PartialEq, Debug)]
+enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Tab,
+    Char(char),
+    Other,
+}
+
+// puts the terminal into raw mode, and back again when dropped
+struct RawTerminal {
+    saved: String,
+}
+
+fn stty(args: &[&str]) -> Result<String, String> {
+    let out = Command::new("stty").args(args).stdin(Stdio::inherit()).output().map_err(|e| format!("Unable to run stty: {}", e))?;
+    if !out.status.success() {
+        return Err("stdin is not a terminal".to_string());
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+impl RawTerminal {
+    fn new() -> Result<Self, String> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        // alternate screen, hide the cursor
+        print!("\x1b[?1049h\x1b[?25l");
+        Ok(Self { saved })
+    }
+
+    // rows, columns
+    fn size(&self) -> (usize, usize) {
+        let s = stty(&["size"]).unwrap_or_default();
+        let v: Vec<usize> = s.split_whitespace().filter_map(|n| n.parse().ok()).collect();
+        if v.len() == 2 && v[0] > 0 && v[1] > 0 { (v[0], v[1]) } else { (24, 80) }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = std::io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+// the keys in what one read from the terminal gave us. a key that sends an escape sequence arrives all at once,
+// so an ESC on its own is just the escape key, and we don't wait for more.
+fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys: Vec<Key> = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let (key, used) = match rest {
+            [ 0x1B, b'[', b'A', .. ] => (Key::Up, 3),
+            [ 0x1B, b'[', b'B', .. ] => (Key::Down, 3),
+            [ 0x1B, b'[', b'C', .. ] => (Key::Right, 3),
+            [ 0x1B, b'[', b'D', .. ] => (Key::Left, 3),
+            [ 0x1B, b'[', b'5', b'~', .. ] => (Key::PageUp, 4),
+            [ 0x1B, b'[', b'6', b'~', .. ] => (Key::PageDown, 4),
+            // some other escape sequence, up to and including its final byte
+            [ 0x1B, b'[', tail @ .. ] => (Key::Other, 2 + tail.iter().position(|b| (0x40..=0x7E).contains(b)).map_or(tail.len(), |p| p + 1)),
+            [ 0x1B, .. ] => (Key::Other, 1),
+            [ b'\t', .. ] => (Key::Tab, 1),
+            [ b'\r', .. ] => (Key::Char('\n'), 1),
+            [ c, .. ] if c.is_ascii() => (Key::Char(*c as char), 1),
+            _ => (Key::Other, 1),
+        };
+        keys.push(key);
+        rest = &rest[used..];
+    }
+    keys
+}
+
+fn read_keys() -> Option<Vec<Key>> {
+    let mut buf = [0u8; 64];
+    let n = std::io::stdin().read(&mut buf).ok()?;
+    if n == 0 {
+        return None;
+    }
+    Some(parse_keys(&buf[..n]))
+}
+
+fn find_region(layout: &[Region], name: &str) -> Option<Region> {
+    layout.iter().find(|r| r.name == name).cloned()
+}
+
+fn items(face: &mut FaceN, layout: &[Region], file_len: usize) -> Vec<Item> {
+    let mut v: Vec<Item> = Vec::new();
+    v.push(Item {
+        name: "header".to_string(),
+        fields: format!("api_ver          {}\nunknown          0x{:04X}\npreview          {}x{}\ndigit sets       {}\nelements         {}\nfile size        {}",
+            face.api_ver, face.unknown, face.preview_img_data.w, face.preview_img_data.h, face.digits.len(), face.elements.len(), file_len),
+        imgs: vec![ face.preview_img_data.clone() ],
+        region: find_region(layout, "header"),
+    });
+    for (n, d) in face.digits.iter().enumerate() {
+        let name = format!("digit set {}", n);
+        v.push(Item {
+            fields: format!("{:#?}", d),
+            imgs: d.img_data.clone(),
+            region: find_region(layout, &name),
+            name,
+        });
+    }
+    for (n, el) in face.elements.iter_mut().enumerate() {
+        let region = layout.iter().find(|r| r.name.starts_with(&format!("element {} ", n))).cloned();
+        v.push(Item {
+            name: format!("{:2} {}", n, el.type_name()),
+            fields: format!("{:#?}", el),
+            imgs: el.img_data_mut().into_iter().map(|id| id.clone()).collect(),
+            region,
+        });
+    }
+    v
+}
+
+// scale to fit in w x h pixels, keeping the aspect ratio. never enlarges more than 4 times.
+fn fit(img: &Img, w: usize, h: usize) -> Img {
+    let scale = (w as f32 / img.w as f32).min(h as f32 / img.h as f32).min(4.0);
+    let nw = ((img.w as f32 * scale) as u32).max(1);
+    let nh = ((img.h as f32 * scale) as u32).max(1);
+    img.resize(nw, nh, ResizeFilter::Bilinear)
+}
+
+// alpha over a dark grey checkerboard, so transparent areas can be seen
+fn flatten(p: [u8; 4], x: u32, y: u32) -> [u8; 3] {
+    let bg: u32 = if ((x / 4 + y / 4) & 1) == 0 { 40 } else { 64 };
+    let a = p[0] as u32;
+    let mix = |c: u8| ((c as u32 * a + bg * (255 - a)) / 255) as u8;
+    [ mix(p[1]), mix(p[2]), mix(p[3]) ]
+}
+
+// lines of half block characters, the top pixel is the foreground colour and the bottom pixel the background
+fn half_blocks(img: &Img) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for row in 0..img.h.div_ceil(2) {
+        let mut line = String::new();
+        for x in 0..img.w {
+            let top = flatten(img.get_pixel(x, row * 2), x, row * 2);
+            let bottom = if row * 2 + 1 < img.h { flatten(img.get_pixel(x, row * 2 + 1), x, row * 2 + 1) } else { [0, 0, 0] };
+            line += &format!("\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}", top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]);
+        }
+        line += "\x1b[0m";
+        lines.push(line);
+    }
+    lines
+}
+
+// sixel graphics, with a 6x6x6 colour cube palette
+fn sixel(img: &Img) -> String {
+    let mut s = String::from("\x1bPq");
+    for i in 0..216 {
+        let (r, g, b) = (i / 36, (i / 6) % 6, i % 6);
+        s += &format!("#{};2;{};{};{}", i, r * 20, g * 20, b * 20);
+    }
+    let index = |x: u32, y: u32| -> usize {
+        let p = flatten(img.get_pixel(x, y), x, y);
+        let q = |c: u8| (c as usize * 5 + 127) / 255;
+        q(p[0]) * 36 + q(p[1]) * 6 + q(p[2])
+    };
+    for band in 0..img.h.div_ceil(6) {
+        let mut used = [false; 216];
+        for y in band * 6..(band * 6 + 6).min(img.h) {
+            for x in 0..img.w {
+                used[index(x, y)] = true;
+            }
+        }
+        for c in (0..216).filter(|c| used[*c]) {
+            s += &format!("#{}", c);
+            for x in 0..img.w {
+                let mut bits = 0;
+                for dy in 0..6 {
+                    let y = band * 6 + dy;
+                    if y < img.h && index(x, y) == c {
+                        bits |= 1 << dy;
+                    }
+                }
+                s.push((63 + bits) as u8 as char);
+            }
+            s.push('$');
+        }
+        s.push('-');
+    }
+    s += "\x1b\\";
+    s
+}
+
+fn hex_lines(data: &[u8], region: &Option<Region>, start_line: usize, count: usize) -> Vec<String> {
+    let (r_start, r_end) = match region {
+        Some(r) => (r.offset, r.offset + r.size),
+        None => (0, 0),
+    };
+    let mut lines: Vec<String> = Vec::new();
+    for line in start_line..start_line + count {
+        let offset = line * 16;
+        if offset >= data.len() {
+            break;
+        }
+        let mut s = format!("{:08X} ", offset);
+        for (i, b) in data.iter().enumerate().take(offset + 16).skip(offset) {
+            if i >= r_start && i < r_end {
+                s += &format!(" \x1b[7m{:02X}\x1b[0m", b);
+            } else {
+                s += &format!(" {:02X}", b);
+            }
+        }
+        lines.push(s);
+    }
+    lines
+}
+
+pub struct Inspector<'a> {
+    data: &'a [u8],
+    items: Vec<Item>,
+    selected: usize,
+    pane: Pane,
+    img_index: usize,
+    scroll: usize,
+    use_sixel: bool,
+}
+
+impl<'a> Inspector<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        let (mut face, layout) = FaceN::from_bin_with_layout(data)?;
+        Ok(Self {
+            data,
+            items: items(&mut face, &layout, data.len()),
+            selected: 0,
+            pane: Pane::Fields,
+            img_index: 0,
+            scroll: 0,
+            use_sixel: false,
+        })
+    }
+
+    // move to the selected item's offset in the hex pane
+    fn jump(&mut self) {
+        self.scroll = self.items[self.selected].region.as_ref().map(|r| r.offset / 16).unwrap_or(0);
+    }
+
+    fn draw(&self, rows: usize, cols: usize) -> String {
+        let mut out = String::from("\x1b[H\x1b[2J");
+        let right_col = LEFT_WIDTH + 2;
+        let right_width = cols.saturating_sub(right_col);
+        let body_rows = rows.saturating_sub(2);
+
+        // the list, scrolled so the selection is visible
+        let first = self.selected.saturating_sub(body_rows.saturating_sub(1));
+        for (row, n) in (first..self.items.len()).take(body_rows).enumerate() {
+            let name: String = self.items[n].name.chars().take(LEFT_WIDTH - 1).collect();
+            out += &format!("\x1b[{};1H", row + 2);
+            if n == self.selected {
+                out += &format!("\x1b[7m{:<width$}\x1b[0m", name, width = LEFT_WIDTH - 1);
+            } else {
+                out += &name;
+            }
+        }
+
+        let item = &self.items[self.selected];
+        let title = match self.pane {
+            Pane::Fields => "Fields".to_string(),
+            Pane::Preview => format!("Preview {} of {}", (self.img_index + 1).min(item.imgs.len()), item.imgs.len()),
+            Pane::Hex => match &item.region {
+                Some(r) => format!("Hex - {} at 0x{:X}, {} bytes", r.name, r.offset, r.size),
+                None => "Hex".to_string(),
+            },
+        };
+        out += &format!("\x1b[1;1H\x1b[1m{}\x1b[0m\x1b[1;{}H\x1b[1m{}\x1b[0m", "Face", right_col, title);
+
+        let lines: Vec<String> = match self.pane {
+            Pane::Fields => item.fields.lines().skip(self.scroll).take(body_rows).map(|l| l.chars().take(right_width).collect()).collect(),
+            Pane::Hex => hex_lines(self.data, &item.region, self.scroll, body_rows),
+            Pane::Preview => match item.imgs.get(self.img_index) {
+                Some(id) if id.w > 0 && id.h > 0 => {
+                    let img = decode(id);
+                    if self.use_sixel {
+                        // assume 10 x 20 pixel characters
+                        let small = fit(&img, right_width * 10, body_rows * 20);
+                        out += &format!("\x1b[2;{}H{}", right_col, sixel(&small));
+                        vec![]
+                    } else {
+                        half_blocks(&fit(&img, right_width, body_rows * 2))
+                    }
+                },
+                Some(_) => vec![ "(empty image)".to_string() ],
+                None => vec![ "(no images)".to_string() ],
+            },
+        };
+        for (row, line) in lines.iter().enumerate() {
+            out += &format!("\x1b[{};{}H{}", row + 2, right_col, line);
+        }
+
+        out += &format!("\x1b[{};1H\x1b[2mUp/Down select  Tab pane  Left/Right image  PgUp/PgDn scroll  h hex  x sixel  q quit\x1b[0m", rows);
+        out
+    }
+
+    // returns false when it is time to quit
+    fn key(&mut self, key: Key, page: usize) -> bool {
+        match key {
+            Key::Up | Key::Char('k') => {
+                if self.selected > 0 { self.selected -= 1; }
+                self.img_index = 0;
+                if self.pane == Pane::Hex { self.jump(); } else { self.scroll = 0; }
+            },
+            Key::Down | Key::Char('j') => {
+                if self.selected + 1 < self.items.len() { self.selected += 1; }
+                self.img_index = 0;
+                if self.pane == Pane::Hex { self.jump(); } else { self.scroll = 0; }
+            },
+            Key::Left => self.img_index = self.img_index.saturating_sub(1),
+            Key::Right if self.img_index + 1 < self.items[self.selected].imgs.len() => self.img_index += 1,
+            Key::PageUp => self.scroll = self.scroll.saturating_sub(page),
+            Key::PageDown => self.scroll += page,
+            Key::Tab => {
+                self.pane = match self.pane {
+                    Pane::Fields => Pane::Preview,
+                    Pane::Preview => Pane::Hex,
+                    Pane::Hex => Pane::Fields,
+                };
+                if self.pane == Pane::Hex { self.jump(); } else { self.scroll = 0; }
+            },
+            Key::Char('h') | Key::Char('\n') => {
+                self.pane = Pane::Hex;
+                self.jump();
+            },
+            Key::Char('x') => self.use_sixel = !self.use_sixel,
+            Key::Char('q') | Key::Char('\x03') => return false,
+            _ => {},
+        }
+        true
+    }
+
+    pub fn run(&mut self) -> Result<(), String> {
+        let term = RawTerminal::new()?;
+        loop {
+            let (rows, cols) = term.size();
+            print!("{}", self.draw(rows, cols));
+            let _ = std::io::stdout().flush();
+            match read_keys() {
+                Some(keys) => if !keys.into_iter().all(|k| self.key(k, rows.saturating_sub(2))) { break; },
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert_eq!(parse_keys(b"\x1b[A\x1b[B\x1b[C\x1b[D"), vec![ Key::Up, Key::Down, Key::Right, Key::Left ]);
+        assert_eq!(parse_keys(b"\x1b[5~\x1b[6~\t\rq"), vec![ Key::PageUp, Key::PageDown, Key::Tab, Key::Char('\n'), Key::Char('q') ]);
+        // a bare ESC, and sequences we don't use, don't swallow the keys after them
+        assert_eq!(parse_keys(b"\x1b"), vec![ Key::Other ]);
+        assert_eq!(parse_keys(b"\x1bj"), vec![ Key::Other, Key::Char('j') ]);
+        assert_eq!(parse_keys(b"\x1b[1;5Ck"), vec![ Key::Other, Key::Char('k') ]);
+        assert_eq!(parse_keys(b"\x1b["), vec![ Key::Other ]);
+        assert_eq!(parse_keys(&[ 0xC3, 0xA9 ]), vec![ Key::Other, Key::Other ]);
+    }
+
+    // red, white / transparent, blue
+    fn two_by_two() -> Img {
+        let mut img = Img::new(2, 2);
+        img.put_pixel(0, 0, [ 255, 255, 0, 0 ]);
+        img.put_pixel(1, 0, [ 255, 255, 255, 255 ]);
+        img.put_pixel(0, 1, [ 0, 0, 0, 0 ]);
+        img.put_pixel(1, 1, [ 255, 0, 0, 255 ]);
+        img
+    }
+
+    #[test]
+    fn half_block_lines() {
+        // the transparent pixel shows the checkerboard
+        assert_eq!(half_blocks(&two_by_two()), vec![ "\x1b[38;2;255;0;0m\x1b[48;2;40;40;40m\u{2580}\x1b[38;2;255;255;255m\x1b[48;2;0;0;255m\u{2580}\x1b[0m" ]);
+    }
+
+    #[test]
+    fn sixel_image() {
+        let s = sixel(&two_by_two());
+        assert!(s.starts_with("\x1bPq#0;2;0;0;0#1;2;0;0;20#2;2;0;0;40"));
+        // one band, each colour used is a column of two pixels: blue, grey, red, white
+        assert!(s.ends_with("#215;2;100;100;100#5?A$#43A?$#180@?$#215?@$-\x1b\\"), "{:?}", s);
+    }
+}
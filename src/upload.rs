@@ -0,0 +1,467 @@
+//  upload.rs - send a packed watch face to the watch
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// A file transfer to install a watch face, sent as MoYoung packets.
+//
+// Every message is a MoYoung packet:
+//  0xFE 0xEA, 0x10 | length >> 8, length & 0xFF, command, payload...
+// where length is the size of the whole packet, header included.
+//
+//  phone -> watch                                      watch -> phone
+//  START   size u32 BE, crc32 u32 BE                   START   status
+//  DATA    index u16 BE, bytes..., checksum u8         DATA    index u16 BE, status
+//  END     crc32 u32 BE                                END     status
+//
+// The checksum of a DATA packet is the XOR of its bytes. A chunk with a bad status, or no reply, is sent again.
+// A reply that arrives after we gave up on it is for an earlier packet, and is dropped.
+// The watch only installs the face after END, when the crc32 of everything it received matches.
+//
+// This has not been checked against a real watch yet. The command bytes (0x74, 0x75, 0x76), the XOR checksum
+// and the big-endian crc32 are placeholders, to be replaced once a real transfer has been captured.
+//
+// A Transport carries the packets. MockDevice is an in-memory watch, so the protocol can be tried without one.
+// A BLE adapter only has to implement Transport.
+
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+
+const MAGIC: [u8; 2] = [ 0xFE, 0xEA ];
+const HEADER_SIZE: usize = 5;
+const MAX_PACKET: usize = 0xFFF;
+const MIN_MTU: usize = HEADER_SIZE + 8;        // the START packet
+
+// placeholders, see above
+pub const CMD_START: u8 = 0x74;
+pub const CMD_DATA: u8 = 0x75;
+pub const CMD_END: u8 = 0x76;
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_BAD_CHECKSUM: u8 = 1;
+pub const STATUS_OUT_OF_ORDER: u8 = 2;
+pub const STATUS_NO_SPACE: u8 = 3;
+pub const STATUS_BAD_CRC: u8 = 4;
+pub const STATUS_NOT_STARTED: u8 = 5;
+
+fn status_name(status: u8) -> &'static str {
+    match status {
+        STATUS_OK => "ok",
+        STATUS_BAD_CHECKSUM => "bad checksum",
+        STATUS_OUT_OF_ORDER => "out of order",
+        STATUS_NO_SPACE => "not enough space",
+        STATUS_BAD_CRC => "crc mismatch",
+        STATUS_NOT_STARTED => "no transfer started",
+        _ => "unknown status",
+    }
+}
+
+// moves packets between us and the watch
+pub trait Transport {
+    fn send(&mut self, packet: &[u8]) -> Result<(), String>;
+    // the next packet from the watch. an error if nothing arrives in time.
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, String>;
+    // the largest packet that can be sent in one go
+    fn mtu(&self) -> usize;
+}
+
+pub fn frame(cmd: u8, payload: &[u8]) -> Vec<u8> {
+    let len = HEADER_SIZE + payload.len();
+    let mut p: Vec<u8> = Vec::with_capacity(len);
+    p.extend(MAGIC);
+    p.push(0x10 | (len >> 8) as u8);
+    p.push(len as u8);
+    p.push(cmd);
+    p.extend(payload);
+    p
+}
+
+// returns the command and the payload
+pub fn unframe(packet: &[u8]) -> Result<(u8, &[u8]), String> {
+    if packet.len() < HEADER_SIZE || packet[0..2] != MAGIC || packet[2] & 0xF0 != 0x10 {
+        return Err("Not a MoYoung packet".to_string());
+    }
+    let len = ((packet[2] as usize & 0x0F) << 8) | packet[3] as usize;
+    if len != packet.len() {
+        return Err(format!("Packet length is {}, but the header says {}", packet.len(), len));
+    }
+    Ok((packet[4], &packet[HEADER_SIZE..]))
+}
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |c, b| c ^ b)
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UploadReport
+{
+    pub size: usize,
+    pub chunks: usize,
+    pub resent: usize,
+}
+
+pub struct Uploader<'a, T: Transport>
+{
+    transport: &'a mut T,
+    retries: usize,                     // times each packet is sent again before giving up
+    timeout: Duration,
+}
+
+impl<'a, T: Transport> Uploader<'a, T>
+{
+    pub fn new(transport: &'a mut T) -> Result<Self, String> {
+        if transport.mtu() < MIN_MTU {
+            return Err(format!("MTU {} is too small, it must be at least {}", transport.mtu(), MIN_MTU));
+        }
+        Ok(Self { transport, retries: 3, timeout: Duration::from_secs(5) })
+    }
+
+    // data bytes that fit in one DATA packet
+    fn chunk_size(&self) -> usize {
+        self.transport.mtu().min(MAX_PACKET) - HEADER_SIZE - 3
+    }
+
+    // send a packet until the watch replies with the same command and an ok status. returns how many times it was sent again.
+    // expect is the start of the reply payload, before the status.
+    fn exchange(&mut self, cmd: u8, payload: &[u8], expect: &[u8]) -> Result<usize, String> {
+        let packet = frame(cmd, payload);
+        let mut last_error = String::new();
+        for attempt in 0..=self.retries {
+            self.transport.send(&packet)?;
+            let mut stale = 0;
+            let reply = loop {
+                let reply = match self.transport.receive(self.timeout) {
+                    Ok(r) => r,
+                    Err(e) => break Err(e),
+                };
+                let (rcmd, rpayload) = unframe(&reply)?;
+                if rcmd == cmd && rpayload.starts_with(expect) {
+                    break Ok(reply);
+                }
+                // a late reply to an earlier packet. there can only be one for each time we sent it.
+                stale += 1;
+                if stale > self.retries + 1 {
+                    return Err(format!("Unexpected reply from the watch: {}", crate::util::to_hex(&reply)));
+                }
+            };
+            let reply = match reply {
+                Ok(r) => r,
+                Err(e) => { last_error = e; continue; },
+            };
+            let (_, rpayload) = unframe(&reply)?;
+            if rpayload.len() != expect.len() + 1 {
+                return Err(format!("Unexpected reply from the watch: {}", crate::util::to_hex(&reply)));
+            }
+            match rpayload[expect.len()] {
+                STATUS_OK => return Ok(attempt),
+                // these can be fixed by sending again
+                STATUS_BAD_CHECKSUM => last_error = status_name(STATUS_BAD_CHECKSUM).to_string(),
+                s => return Err(format!("The watch refused the upload: {}", status_name(s))),
+            }
+        }
+        Err(format!("No good reply after {} tries, last problem: {}", self.retries + 1, last_error))
+    }
+
+    // send the file. progress is called with the bytes sent so far and the total.
+    pub fn upload(&mut self, data: &[u8], mut progress: impl FnMut(usize, usize)) -> Result<UploadReport, String> {
+        let chunk_size = self.chunk_size();
+        let chunks = data.len().div_ceil(chunk_size);
+        if chunks > u16::MAX as usize + 1 {
+            return Err(format!("The file is too large to send, {} chunks of {} bytes", chunks, chunk_size));
+        }
+        let crc = crc32(data);
+        let mut report = UploadReport { size: data.len(), chunks, resent: 0 };
+
+        let mut start: Vec<u8> = Vec::new();
+        start.extend((data.len() as u32).to_be_bytes());
+        start.extend(crc.to_be_bytes());
+        report.resent += self.exchange(CMD_START, &start, &[])?;
+
+        for (n, chunk) in data.chunks(chunk_size).enumerate() {
+            let index = (n as u16).to_be_bytes();
+            let mut payload: Vec<u8> = Vec::with_capacity(chunk.len() + 3);
+            payload.extend(index);
+            payload.extend(chunk);
+            payload.push(checksum(chunk));
+            report.resent += self.exchange(CMD_DATA, &payload, &index)
+                .map_err(|e| format!("Chunk {} of {}: {}", n + 1, chunks, e))?;
+            progress(n * chunk_size + chunk.len(), data.len());
+        }
+
+        report.resent += self.exchange(CMD_END, &crc.to_be_bytes(), &[])?;
+        Ok(report)
+    }
+}
+
+// MOCK DEVICE STARTS HERE
+
+// an in-memory watch that follows the protocol, for trying it out without a watch
+pub struct MockDevice
+{
+    pub mtu: usize,
+    pub capacity: usize,                // largest face the watch will take
+    pub corrupt_every: usize,           // pretend every Nth chunk arrived damaged, the first time it is sent. 0 for never.
+    pub installed: Option<Vec<u8>>,     // the face, once a transfer completes
+    pub packets_received: usize,
+    expected: Option<(usize, u32)>,     // size and crc32 from START
+    received: Vec<u8>,
+    next_index: usize,
+    corrupted: Vec<usize>,
+    outbox: VecDeque<Vec<u8>>,
+}
+
+impl MockDevice
+{
+    pub fn new(mtu: usize) -> Self {
+        Self {
+            mtu,
+            capacity: 2 * 1024 * 1024,
+            corrupt_every: 0,
+            installed: None,
+            packets_received: 0,
+            expected: None,
+            received: Vec::new(),
+            next_index: 0,
+            corrupted: Vec::new(),
+            outbox: VecDeque::new(),
+        }
+    }
+
+    fn reply(&mut self, cmd: u8, payload: &[u8]) {
+        self.outbox.push_back(frame(cmd, payload));
+    }
+
+    fn start(&mut self, payload: &[u8]) -> u8 {
+        if payload.len() != 8 {
+            return STATUS_NOT_STARTED;
+        }
+        let size = u32::from_be_bytes([ payload[0], payload[1], payload[2], payload[3] ]) as usize;
+        let crc = u32::from_be_bytes([ payload[4], payload[5], payload[6], payload[7] ]);
+        if size > self.capacity {
+            return STATUS_NO_SPACE;
+        }
+        self.expected = Some((size, crc));
+        self.received.clear();
+        self.next_index = 0;
+        STATUS_OK
+    }
+
+    fn data(&mut self, index: usize, bytes: &[u8], sum: u8) -> u8 {
+        let (size, _) = match self.expected {
+            Some(e) => e,
+            None => return STATUS_NOT_STARTED,
+        };
+        let damaged = self.corrupt_every > 0 && (index + 1).is_multiple_of(self.corrupt_every) && !self.corrupted.contains(&index);
+        if damaged {
+            self.corrupted.push(index);
+        }
+        if damaged || checksum(bytes) != sum {
+            return STATUS_BAD_CHECKSUM;
+        }
+        if index + 1 == self.next_index {
+            return STATUS_OK;               // our reply was lost, and they sent it again
+        }
+        if index != self.next_index || self.received.len() + bytes.len() > size {
+            return STATUS_OUT_OF_ORDER;
+        }
+        self.received.extend(bytes);
+        self.next_index += 1;
+        STATUS_OK
+    }
+
+    fn end(&mut self, payload: &[u8]) -> u8 {
+        let (size, crc) = match self.expected.take() {
+            Some(e) => e,
+            None => return STATUS_NOT_STARTED,
+        };
+        let end_crc = if payload.len() == 4 { u32::from_be_bytes([ payload[0], payload[1], payload[2], payload[3] ]) } else { !crc };
+        if self.received.len() != size || crc32(&self.received) != crc || end_crc != crc {
+            return STATUS_BAD_CRC;
+        }
+        self.installed = Some(std::mem::take(&mut self.received));
+        STATUS_OK
+    }
+}
+
+impl Transport for MockDevice
+{
+    fn send(&mut self, packet: &[u8]) -> Result<(), String> {
+        if packet.len() > self.mtu {
+            return Err(format!("Packet of {} bytes is larger than the MTU of {}", packet.len(), self.mtu));
+        }
+        self.packets_received += 1;
+        let (cmd, payload) = unframe(packet)?;
+        match cmd {
+            CMD_START => {
+                let status = self.start(payload);
+                self.reply(CMD_START, &[ status ]);
+            },
+            CMD_DATA if payload.len() >= 3 => {
+                let index = u16::from_be_bytes([ payload[0], payload[1] ]);
+                let bytes = &payload[2..payload.len() - 1];
+                let status = self.data(index as usize, bytes, payload[payload.len() - 1]);
+                let mut r: Vec<u8> = index.to_be_bytes().to_vec();
+                r.push(status);
+                self.reply(CMD_DATA, &r);
+            },
+            CMD_END => {
+                let status = self.end(payload);
+                self.reply(CMD_END, &[ status ]);
+            },
+            _ => {},                        // a real watch ignores commands it doesn't know
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, _timeout: Duration) -> Result<Vec<u8>, String> {
+        self.outbox.pop_front().ok_or_else(|| "No reply from the watch".to_string())
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a test file, with every byte value in it
+    fn face_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    // a mock watch behind a bad connection
+    struct Flaky {
+        mock: MockDevice,
+        lose_replies: usize,        // the next replies that don't arrive
+        late_replies: usize,        // the next replies that arrive after we gave up waiting
+        damage_data: bool,          // every DATA packet arrives with a bad checksum
+    }
+
+    impl Transport for Flaky {
+        fn send(&mut self, packet: &[u8]) -> Result<(), String> {
+            let mut packet = packet.to_vec();
+            if self.damage_data && packet[4] == CMD_DATA {
+                *packet.last_mut().unwrap() ^= 0xFF;
+            }
+            self.mock.send(&packet)
+        }
+
+        fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, String> {
+            if self.late_replies > 0 {
+                self.late_replies -= 1;
+                return Err("Timed out".to_string());
+            }
+            let reply = self.mock.receive(timeout)?;
+            if self.lose_replies > 0 {
+                self.lose_replies -= 1;
+                return Err("Timed out".to_string());
+            }
+            Ok(reply)
+        }
+
+        fn mtu(&self) -> usize {
+            self.mock.mtu
+        }
+    }
+
+    #[test]
+    fn clean_upload() {
+        let data = face_data(1000);
+        let mut mock = MockDevice::new(64);
+        let report = Uploader::new(&mut mock).unwrap().upload(&data, |_, _| {}).unwrap();
+        assert_eq!((report.size, report.chunks, report.resent), (1000, 18, 0));
+        assert_eq!(mock.packets_received, 20);
+        assert_eq!(mock.installed, Some(data));
+    }
+
+    #[test]
+    fn bad_checksum_is_sent_again() {
+        let data = face_data(1000);
+        let mut mock = MockDevice::new(64);
+        mock.corrupt_every = 5;
+        let report = Uploader::new(&mut mock).unwrap().upload(&data, |_, _| {}).unwrap();
+        assert_eq!(report.resent, 3);                       // chunks 5, 10 and 15
+        assert_eq!(mock.installed, Some(data));
+    }
+
+    #[test]
+    fn lost_reply_is_sent_again() {
+        let data = face_data(1000);
+        let mut t = Flaky { mock: MockDevice::new(64), lose_replies: 2, damage_data: false, late_replies: 0 };
+        let report = Uploader::new(&mut t).unwrap().upload(&data, |_, _| {}).unwrap();
+        assert_eq!(report.resent, 2);
+        assert_eq!(t.mock.installed, Some(data));
+    }
+
+    #[test]
+    fn late_reply_is_dropped() {
+        // START is sent three times, and two of its replies arrive while waiting for the reply to chunk 1
+        let data = face_data(1000);
+        let mut t = Flaky { mock: MockDevice::new(64), lose_replies: 0, damage_data: false, late_replies: 2 };
+        let report = Uploader::new(&mut t).unwrap().upload(&data, |_, _| {}).unwrap();
+        assert_eq!(report.resent, 2);
+        assert_eq!(t.mock.installed, Some(data));
+    }
+
+    #[test]
+    fn small_mtu_is_an_error() {
+        let mut mock = MockDevice::new(12);
+        assert_eq!(Uploader::new(&mut mock).err().unwrap(), "MTU 12 is too small, it must be at least 13");
+        let mut mock = MockDevice::new(13);
+        let report = Uploader::new(&mut mock).unwrap().upload(&face_data(20), |_, _| {}).unwrap();
+        assert_eq!(report.chunks, 4);
+        assert_eq!(mock.installed, Some(face_data(20)));
+    }
+
+    #[test]
+    fn timeout_gives_up_after_retries() {
+        let mut t = Flaky { mock: MockDevice::new(64), lose_replies: usize::MAX, damage_data: false, late_replies: 0 };
+        let err = Uploader::new(&mut t).unwrap().upload(&face_data(100), |_, _| {}).unwrap_err();
+        assert_eq!(err, "No good reply after 4 tries, last problem: Timed out");
+        assert_eq!(t.mock.packets_received, 4);
+    }
+
+    #[test]
+    fn bad_status_gives_up_after_retries() {
+        let mut t = Flaky { mock: MockDevice::new(64), lose_replies: 0, damage_data: true, late_replies: 0 };
+        let err = Uploader::new(&mut t).unwrap().upload(&face_data(100), |_, _| {}).unwrap_err();
+        assert_eq!(err, "Chunk 1 of 2: No good reply after 4 tries, last problem: bad checksum");
+        assert_eq!(t.mock.installed, None);
+    }
+
+    #[test]
+    fn refused_upload() {
+        let mut mock = MockDevice::new(64);
+        mock.capacity = 50;
+        let err = Uploader::new(&mut mock).unwrap().upload(&face_data(100), |_, _| {}).unwrap_err();
+        assert_eq!(err, "The watch refused the upload: not enough space");
+        assert_eq!(mock.packets_received, 1);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(checksum(&[ 0x12, 0x34, 0x56 ]), 0x70);
+    }
+}
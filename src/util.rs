@@ -38,12 +38,15 @@ pub fn get_u32(blob: &[u8], idx: usize) -> u32
     (d << 24) | (c << 16) | (b << 8) | a
 }
 
+// the +0 keeps the bytes lined up
+#[allow(clippy::identity_op)]
 pub fn put_u16(blob: &mut[u8], idx: usize, val: u16) 
 {
     blob[idx+0] = (val & 0xFF) as u8;
     blob[idx+1] = ((val & 0xFF00) >> 8) as u8;
 }
 
+#[allow(clippy::identity_op)]
 pub fn put_u32(blob: &mut[u8], idx: usize, val: u32) 
 {
     blob[idx+0] = (val & 0xFF) as u8;
@@ -83,4 +86,73 @@ pub fn align_it(o: &mut u32) {
         3 => 1,
         _ => panic!("mod function failure"),
     };
-}
\ No newline at end of file
+}
+// HEX STRINGS, FOR SMALL AMOUNTS OF BINARY DATA IN JSON
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("Hex string '{}' has an odd length", s));
+    }
+    // by bytes, as the string may not be ASCII
+    let digit = |c: u8| (c as char).to_digit(16).ok_or_else(|| format!("Hex string '{}' is not valid", s));
+    s.as_bytes().chunks_exact(2).map(|p| Ok((digit(p[0])? * 16 + digit(p[1])?) as u8)).collect()
+}
+
+// BASE64, FOR IMAGES EMBEDDED IN TEXT FILES
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn to_base64(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+// whitespace is ignored, so wrapped base64 is fine
+pub fn from_base64(s: &str) -> Result<Vec<u8>, String> {
+    let mut b: Vec<u8> = Vec::with_capacity(s.len() / 4 * 3);
+    let mut n: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        if c == b'=' {
+            break;
+        }
+        let v = BASE64_CHARS.iter().position(|x| *x == c).ok_or_else(|| format!("'{}' is not a base64 character", c as char))?;
+        n = (n << 6 | v as u32) & 0xFFFF;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            b.push((n >> bits) as u8);
+        }
+    }
+    Ok(b)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_strings() {
+        assert_eq!(from_hex("00ff7A").unwrap(), [ 0x00, 0xFF, 0x7A ]);
+        assert_eq!(to_hex(&[ 0x00, 0xFF, 0x7A ]), "00ff7a");
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("0g").is_err());
+        // é is two bytes, so this has an even length
+        assert_eq!(from_hex("aéb").err().unwrap(), "Hex string 'aéb' is not valid");
+    }
+}
@@ -0,0 +1,235 @@
+//  watch.rs - repack a watch face whenever its dump folder changes
+//
+// 	ExtraThunder WatchFace Tool
+// 	for Mo Young / Da Fit binary watch face files.
+//
+// 	Copyright 2022-4 David Atkinson
+// 	Author: David Atkinson <dav!id47k@d47.co> (remove the '!')
+// 	License: GNU General Public License version 2 or any later version (GPL-2.0-or-later)
+
+
+// The folder is polled, so no platform specific file notification is needed. Any change to a file in it starts
+// a pack, except hidden files (like the pack cache and editor swap files) and the output file.
+// Each pack is the same as a plain pack with the same options, so unchanged images come from the pack cache.
+
+
+use std::collections::HashMap;
+use std::fs;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_json::Value;
+
+use crate::{Output, PackOptions};
+
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const SETTLE_TIME: Duration = Duration::from_millis(200);      // let editors finish writing before reading
+
+
+// enough to tell if a file has changed
+#[derive(Clone, PartialEq, Debug)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Option<FileStamp> {
+        let md = fs::metadata(path).ok()?;
+        Some(FileStamp { modified: md.modified().ok(), len: md.len() })
+    }
+}
+
+pub struct Watcher<'a> {
+    folder_name: &'a str,
+    out_file_name: &'a str,
+    options: &'a PackOptions<'a>,
+    json: bool,                                     // print each status as a line of JSON
+    stamps: HashMap<PathBuf, FileStamp>,            // the folder as it was at the last pack
+}
+
+impl<'a> Watcher<'a> {
+    pub fn new(folder_name: &'a str, out_file_name: &'a str, options: &'a PackOptions<'a>, json: bool) -> Self {
+        Self {
+            folder_name,
+            out_file_name,
+            options,
+            json,
+            stamps: HashMap::new(),
+        }
+    }
+
+    // every file in the folder and its subfolders, as they are now
+    fn snapshot(&self) -> HashMap<PathBuf, FileStamp> {
+        let out = fs::canonicalize(self.out_file_name).ok();
+        let mut stamps: HashMap<PathBuf, FileStamp> = HashMap::new();
+        let mut folders: Vec<PathBuf> = vec![ PathBuf::from(self.folder_name) ];
+        while let Some(folder) = folders.pop() {
+            let entries = match fs::read_dir(&folder) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                if path.is_dir() {
+                    folders.push(path);
+                } else if out.is_none() || fs::canonicalize(&path).ok() != out {
+                    if let Some(s) = FileStamp::of(&path) {
+                        stamps.insert(path, s);
+                    }
+                }
+            }
+        }
+        stamps
+    }
+
+    // one pack, with the warnings kept in out
+    fn pack(&mut self, out: &mut Output) -> Result<Value, String> {
+        self.stamps = self.snapshot();
+        let result = crate::pack_once(out, self.folder_name, self.out_file_name, self.options)?;
+        // saving new sizes changes watchface.json, which doesn't need another pack
+        if result["sizes_updated"] == true {
+            self.stamps = self.snapshot();
+        }
+        Ok(result)
+    }
+
+    fn pack_and_report(&mut self) {
+        let start = Instant::now();
+        // quiet, as the progress messages would be lost among the packs
+        let mut out = Output { json: true, debug: 0, warnings: Vec::new() };
+        // a half edited face shouldn't stop the watching, even if it makes something panic
+        let result = crate::catch("Unable to pack", AssertUnwindSafe(|| self.pack(&mut out))).and_then(|r| r);
+        if self.json {
+            let line = match result {
+                Ok(r) => serde_json::json!({ "ok": true, "result": r, "warnings": out.warnings, "ms": start.elapsed().as_millis() as u64 }),
+                Err(e) => serde_json::json!({ "ok": false, "error": e, "warnings": out.warnings }),
+            };
+            println!("{}", line);
+            return;
+        }
+        for w in out.warnings.iter() {
+            eprintln!("WARNING: {}", w);
+        }
+        match result {
+            Ok(r) => {
+                let cached = match r.get("cache") {
+                    Some(c) => format!(", {} of {} images encoded", c["encoded"], c["encoded"].as_u64().unwrap_or(0) + c["hits"].as_u64().unwrap_or(0)),
+                    None => String::new(),
+                };
+                println!("Packed '{}': {} bytes{}, {} warnings ({} ms)",
+                    self.out_file_name, r["size"], cached, out.warnings.len(), start.elapsed().as_millis());
+            },
+            Err(e) => eprintln!("ERROR: {}", e),
+        }
+    }
+
+    // pack now, and again every time something changes. never returns.
+    pub fn run(&mut self) {
+        if !self.json { println!("Watching '{}', press Ctrl+C to stop.", self.folder_name); }
+        self.pack_and_report();
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            let mut stamps = self.snapshot();
+            if stamps != self.stamps {
+                // wait for the files to stop changing
+                loop {
+                    std::thread::sleep(SETTLE_TIME);
+                    let now = self.snapshot();
+                    if now == stamps {
+                        break;
+                    }
+                    stamps = now;
+                }
+                self.pack_and_report();
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SizeCheck;
+    use crate::device::DEVICES;
+    use crate::face::FaceN;
+    use crate::face_builder::FaceBuilder;
+    use crate::img::Img;
+    use crate::img_data::DumpFormat;
+
+    fn options(sizes: SizeCheck) -> PackOptions<'static> {
+        PackOptions { watch: true, use_cache: true, sizes, make_preview: false, check_preview: false, recolor_ops: &[], only: &[] }
+    }
+
+    fn quiet() -> Output {
+        Output { json: true, debug: 0, warnings: Vec::new() }
+    }
+
+    // a folder of its own for each test
+    fn temp_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("extrathundertool-watch-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    // a dumped face, with the preview 5 wide in watchface.json but 4 wide in its file
+    fn dump(folder: &Path) {
+        let mut face = FaceBuilder::new(&DEVICES[0]).preview(Img::new(4, 4)).build().unwrap();
+        face.generate_file_names(&DumpFormat::BMP, false);
+        face.write_imgs(folder.to_str().unwrap(), &DumpFormat::BMP).unwrap();
+        face.preview_img_data.w = 5;
+        fs::write(folder.join("watchface.json"), serde_json::to_string(&face).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn bad_face_is_an_error() {
+        let folder = temp_folder("bad");
+        dump(&folder);
+        let mut face: FaceN = serde_json::from_slice(&fs::read(folder.join("watchface.json")).unwrap()).unwrap();
+        face.api_ver = 3;
+        fs::write(folder.join("watchface.json"), serde_json::to_string(&face).unwrap()).unwrap();
+        let folder_name = folder.to_str().unwrap();
+        let out_file = folder.join("out.bin");
+        let options = options(SizeCheck::Warn);
+        let mut watcher = Watcher::new(folder_name, out_file.to_str().unwrap(), &options, false);
+        let err = watcher.pack(&mut quiet()).err().unwrap();
+        assert!(err.contains("Unsupported api_ver 3"), "{}", err);
+        watcher.pack_and_report();
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn packs_like_pack() {
+        let folder = temp_folder("sizes");
+        dump(&folder);
+        let folder_name = folder.to_str().unwrap();
+        let out_file = folder.join("out.bin");
+
+        let strict = options(SizeCheck::Strict);
+        let err = Watcher::new(folder_name, out_file.to_str().unwrap(), &strict, false).pack(&mut quiet()).err().unwrap();
+        assert!(err.contains("not the size watchface.json says"), "{}", err);
+
+        let update = options(SizeCheck::Update);
+        let mut watcher = Watcher::new(folder_name, out_file.to_str().unwrap(), &update, false);
+        let mut out = quiet();
+        let result = watcher.pack(&mut out).unwrap();
+        assert_eq!(result["sizes_updated"], true);
+        assert_eq!(out.warnings, [ "Image 'preview.bmp' is 4x4, but watchface.json says 5x4" ]);
+        let saved: FaceN = serde_json::from_slice(&fs::read(folder.join("watchface.json")).unwrap()).unwrap();
+        assert_eq!(saved.preview_img_data.w, 4);
+        // neither the new sizes nor the output file start another pack
+        assert_eq!(watcher.snapshot(), watcher.stamps);
+
+        // packing again uses the cache
+        watcher.pack(&mut quiet()).unwrap();
+        let result = watcher.pack(&mut quiet()).unwrap();
+        assert_eq!((&result["cache"]["hits"], &result["cache"]["encoded"]), (&1.into(), &0.into()));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}